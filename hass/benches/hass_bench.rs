@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use hass::pirengine::home::{self, VecGraph};
+use hass::pirengine::home::VecGraph;
 
 mod graph {
     use rand::Rng;
@@ -7,6 +7,7 @@ mod graph {
     use super::*;
 
     struct Room {
+        #[allow(dead_code)]
         pub id: String,
     }
 
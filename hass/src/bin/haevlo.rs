@@ -1,18 +1,32 @@
-use clap::Parser;
-use hass;
+// `AppError` carries a whole `hass::error::Error`
+#![allow(clippy::result_large_err)]
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::BufRead;
+use std::str::FromStr;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use clap::{ArgEnum, Parser};
 use hass::error::{self, Error};
-use hass::sync::shutdown;
+use hass::hast::scenario;
+use hass::logging::{self, LogHandle};
+use hass::sync::clock::{Clock, SystemClock};
+use hass::sync::{runtime, shutdown};
 use hass::wsapi::WsApi;
 use hass::json::{WsMessage, EventType, EventObj};
-use tokio;
-use tokio::io::{self, AsyncWriteExt};
-use tokio::fs::{OpenOptions, File};
+use hass::serde_json::Value;
+use async_compression::tokio::write::GzipEncoder;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::fs::OpenOptions;
 use tokio::sync::mpsc::Receiver;
 use tokio::signal;
-use tracing_subscriber;
 
 type AppError = (ExitCode, Option<(Error, &'static str)>);
 type AppResult = Result<(), AppError>;
+/// Output file, compressed according to [CmdArgs::compress].
+type Output = Box<dyn AsyncWrite + Unpin + Send>;
 
 
 /// Command-line arguments for the binary
@@ -34,24 +48,236 @@ struct CmdArgs {
     /// Enable event logging start/stop via HA events.
     /// When enabled, generate a custom event `haevlo_start`
     /// from Home Assistant to start logging, and `haevlo_stop`
-    /// to quit.
+    /// to quit. A `piresence_loglevel` event with a `level` field in
+    /// its data, e.g. `{"level": "debug"}`, replaces the `RUST_LOG`
+    /// filter at runtime.
     #[clap(long)]
     use_events: bool,
 
     #[clap(long, default_value = ".")]
     output_folder: String,
 
-    test_name: String,
+    /// Template of the names of the output files, where `{name}` is replaced by
+    /// the test name, `{idx}` by the recording index, and `{timestamp}` and `{date}`
+    /// by the UTC time the file is opened at. The extension is added according to
+    /// --format
+    #[clap(long, default_value = "{name}-{idx}")]
+    name_template: NameTemplate,
+
+    /// Format of the output files. Only `yaml` can be played by hast
+    #[clap(long, arg_enum, default_value = "yaml")]
+    format: Format,
+
+    /// Compress the output files, adding the matching extension to their names,
+    /// e.g. `.yaml.gz`. Compressed scenarios are still played by hast
+    #[clap(long, arg_enum, value_name = "FORMAT")]
+    compress: Option<Compression>,
+
+    /// Device class of the entities whose state changes are recorded. May be
+    /// repeated; pass an empty one, i.e. `--device-class=`, to record them all
+    #[clap(long = "device-class", default_value = "motion")]
+    device_classes: Vec<String>,
+
+    /// Entity whose state changes are recorded, on top of the --device-class
+    /// filter. May be repeated; when missing, all entities are recorded
+    #[clap(long = "entity-id")]
+    entity_ids: Vec<String>,
+
+    /// Run on a single-threaded tokio runtime
+    #[clap(long)]
+    single_thread: bool,
+
+    /// Instead of recording, replay the `state_changed` events of a YAML recording
+    /// into HA by firing them on its event bus. Automations listening to them react
+    /// as they originally did, but the state of entities is left untouched. Other
+    /// documents of the recording are skipped. Recordings compressed with --compress,
+    /// e.g. `.yaml.gz`, are decompressed on the fly
+    #[clap(long, value_name = "FILE")]
+    replay: Option<String>,
+
+    /// With --replay, fire the events with their original timing, rather than
+    /// in a single burst
+    #[clap(long, requires = "replay")]
+    realtime: bool,
+
+    /// Name of the recording, used for the output files
+    #[clap(required_unless_present = "replay")]
+    test_name: Option<String>,
+}
+
+
+/// Template of the names of the output files, see [CmdArgs::name_template].
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct NameTemplate(String);
+
+impl NameTemplate {
+    /// Expands the placeholders of the template, checking that the result is
+    /// a plain file name.
+    fn expand(&self, name: &str, idx: i32, now: DateTime<Utc>) -> Result<String, String> {
+        let mut file_name = String::new();
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            file_name.push_str(&rest[..start]);
+            let end = rest[start..].find('}')
+                .ok_or_else(|| format!("unterminated placeholder in {}", self.0))?;
+            match &rest[start + 1..start + end] {
+                "name" => file_name.push_str(name),
+                "idx" => file_name.push_str(&idx.to_string()),
+                "timestamp" => file_name.push_str(&now.format("%Y%m%dT%H%M%SZ").to_string()),
+                "date" => file_name.push_str(&now.format("%Y-%m-%d").to_string()),
+                other => return Err(format!("unknown placeholder {{{}}} in {}", other, self.0)),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        file_name.push_str(rest);
+
+        if file_name.is_empty() || file_name == "." || file_name == ".." || file_name.contains(['/', '\\', '\0']) {
+            return Err(format!("not a valid file name: {:?}", file_name));
+        }
+        Ok(file_name)
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NameTemplate, String> {
+        let template = NameTemplate(s.to_owned());
+        template.expand("name", 0, DateTime::UNIX_EPOCH)?;
+        Ok(template)
+    }
+}
+
+
+/// Format of the output files, see [CmdArgs::format].
+#[derive(ArgEnum, Copy, Clone, PartialEq, Eq, Debug)]
+enum Format {
+    /// YAML documents, as read by hast
+    Yaml,
+    /// Pretty-printed JSON objects, one after another
+    Json,
+    /// Compact JSON objects, one per line
+    Ndjson,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Yaml => "yaml",
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+        }
+    }
+
+    fn serialize(&self, msg: &WsMessage) -> Result<String, String> {
+        let serialized = match self {
+            Format::Yaml => serde_yaml::to_string(msg).map_err(|e| e.to_string())?,
+            Format::Json => hass::serde_json::to_string_pretty(msg).map_err(|e| e.to_string())? + "\n",
+            Format::Ndjson => hass::serde_json::to_string(msg).map_err(|e| e.to_string())? + "\n",
+        };
+        Ok(serialized)
+    }
+}
+
+
+/// Compression of the output files, see [CmdArgs::compress].
+#[derive(ArgEnum, Copy, Clone, PartialEq, Eq, Debug)]
+enum Compression {
+    Gzip,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+        }
+    }
+}
+
+
+/// Which `state_changed` events get recorded.
+#[derive(Clone, Default, Debug)]
+struct Filter {
+    /// Device classes of the entities recorded, any if empty
+    device_classes: Vec<String>,
+    /// Entities recorded, any if empty
+    entity_ids: Vec<String>,
+}
+
+impl Filter {
+    fn new(args: &CmdArgs) -> Filter {
+        Filter {
+            device_classes: args.device_classes.iter()
+                .filter(|device_class| !device_class.is_empty())
+                .cloned()
+                .collect(),
+            entity_ids: args.entity_ids.clone(),
+        }
+    }
+}
+
+/// Statistics of a recording session, logged when haevlo quits.
+#[derive(Debug)]
+struct Summary {
+    started: Instant,
+    /// `state_changed` events received, recording or not
+    seen: usize,
+    /// Events written to the output files
+    recorded: usize,
+    /// Events seen per device class
+    device_classes: BTreeMap<String, usize>,
+    /// Output files opened
+    files: Vec<String>,
+}
+
+impl Summary {
+    fn new() -> Summary {
+        Summary {
+            started: Instant::now(),
+            seen: 0,
+            recorded: 0,
+            device_classes: BTreeMap::new(),
+            files: Vec::new(),
+        }
+    }
+
+    fn add_seen(&mut self, msg: &WsMessage) {
+        self.seen += 1;
+        if let Some(device_class) = device_class(msg) {
+            *self.device_classes.entry(device_class.to_owned()).or_default() += 1;
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "duration: {:?}", self.started.elapsed())?;
+        writeln!(f, "events seen: {}", self.seen)?;
+        writeln!(f, "events recorded: {}", self.recorded)?;
+        writeln!(f, "events per device class:")?;
+        for (device_class, count) in &self.device_classes {
+            writeln!(f, "  {}: {}", device_class, count)?;
+        }
+        write!(f, "files written: {:?}", self.files)
+    }
 }
 
 
+/// Exit codes of the process, see also the ones of `hast`.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum ExitCode {
+    /// Quit on CTRL-C or `haevlo_stop`, or replayed the whole recording
     Success = 0,
+    /// Could not connect or authenticate to HA
     ConnectionError,
+    /// Could not subscribe to the control events of --use-events
     ControlSubscriptionError,
+    /// Could not subscribe to `state_changed` events
     StateSubscriptionError,
+    /// Could not open an output file, or read the recording to replay
     OpenFileError,
+    /// Could not fire the events of the recording to replay
+    ReplayError,
 }
 
 
@@ -62,15 +288,15 @@ impl ExitCode {
 }
 
 
-#[tokio::main]
-async fn main() {
-    // Initialize logging framework
-    tracing_subscriber::fmt::init();
+fn main() {
+    // Initialize logging framework, keeping a handle to change its filter at runtime
+    let log_handle = logging::init();
 
     let args = CmdArgs::parse();
     tracing::debug!("commandline args: {:?}", args);
 
-    std::process::exit(match run_app(args).await {
+    let runtime = runtime::build(args.single_thread).expect("could not build the tokio runtime");
+    std::process::exit(match runtime.block_on(run_app(args, log_handle)) {
         Ok(_) => {
             let code = ExitCode::Success;
             tracing::info!("exit: {:?} ({})", code, code as i32);
@@ -92,15 +318,27 @@ async fn main() {
 }
 
 
-async fn run_app(args: CmdArgs) -> AppResult {
+async fn run_app(args: CmdArgs, log_handle: LogHandle) -> AppResult {
     let manager = shutdown::Manager::new();
 
     let api = WsApi::new_unsecure(&args.host, args.port, &args.token, manager.subscribe()).await
         .map_err(|e| err(ExitCode::ConnectionError, e, "could not connect to HA WebSocket"))?;
 
+    if let Some(file) = &args.replay {
+        let path = file.clone();
+        let recording = tokio::task::spawn_blocking(move || scenario::open(path)).await
+            .unwrap_or_else(|e| Err(io::Error::other(e)))
+            .map_err(|e| err(ExitCode::OpenFileError, e.into(), "could not open the recording to replay"))?;
+        let fired = replay(&api, recording, args.realtime, manager.subscribe()).await
+            .map_err(|e| err(ExitCode::ReplayError, e, "could not replay the recording"))?;
+        tracing::info!("replayed {} events from {}", fired, file);
+        manager.shutdown().await;
+        return Ok(());
+    }
+
     let control_events = if args.use_events {
         register_control_events(&api).await
-            .map_err(|e| err(ExitCode::ControlSubscriptionError, e, "could not subscribe to events: haevlo_start, haevlo_stop"))
+            .map_err(|e| err(ExitCode::ControlSubscriptionError, e, "could not subscribe to events: haevlo_start, haevlo_stop, piresence_loglevel"))
             .map(Option::Some)?
     } else {
         None
@@ -109,30 +347,32 @@ async fn run_app(args: CmdArgs) -> AppResult {
     let state_events = api.subscribe_event(Some(EventType::StateChanged)).await
         .map_err(|e| err(ExitCode::StateSubscriptionError, e, "could not subscribe to events: state_changed"))?;
 
-    run_main_loop(args, state_events, control_events).await?; // exits on CTRL-C signal
+    run_main_loop(args, log_handle, state_events.into_inner(), control_events).await?; // exits on CTRL-C signal
 
     manager.shutdown().await;
 
     Ok(())
 }
 
-async fn run_main_loop(args: CmdArgs, mut state_events: Receiver<WsMessage>, mut control_events: Option<Receiver<WsMessage>>) -> AppResult {
+async fn run_main_loop(args: CmdArgs, log_handle: LogHandle, mut state_events: Receiver<WsMessage>, mut control_events: Option<Receiver<WsMessage>>) -> AppResult {
     let mut recording = !args.use_events;
     let mut recording_index = 0;
+    let mut summary = Summary::new();
+    let filter = Filter::new(&args);
     let mut file_opt = if recording {
-        Some(open_file(&args, recording_index).await?)
+        Some(open_file(&args, recording_index, &SystemClock, &mut summary).await?)
     } else {
         None
     };
     loop {
         tokio::select! {
-            Some(ev) = recv_ctrl_events(&mut control_events), if args.use_events => match ev {
+            Some((ev, data)) = recv_ctrl_events(&mut control_events), if args.use_events => match ev {
                 EventType::HaevloStart => {
                     recording = true;
                     recording_index += 1;
-                    if let Some(mut prev_file) = file_opt.replace(open_file(&args, recording_index).await?) {
-                        if let Err(e) = prev_file.flush().await {
-                            tracing::error!("haevlo_start event: could not correctly flush previous log file: {}", e);
+                    if let Some(mut prev_file) = file_opt.replace(open_file(&args, recording_index, &SystemClock, &mut summary).await?) {
+                        if let Err(e) = prev_file.shutdown().await {
+                            tracing::error!("haevlo_start event: could not correctly close previous log file: {}", e);
                         }
                     }
                     tracing::info!("haevlo_start event: started logging: #{}", recording_index);
@@ -141,15 +381,24 @@ async fn run_main_loop(args: CmdArgs, mut state_events: Receiver<WsMessage>, mut
                     recording = false;
                     tracing::info!("haevlo_stop event: stopped logging: #{}", recording_index);
                 },
+                EventType::PiresenceLoglevel => {
+                    match logging::set_log_level(&log_handle, &data) {
+                        Ok(level) => tracing::info!("piresence_loglevel event: log level set to {}", level),
+                        Err(e) => tracing::error!("piresence_loglevel event: {}", e),
+                    }
+                },
                 _ => (),
             },
 
             Some(st) = state_events.recv() => {
+                summary.add_seen(&st);
                 if !recording {
                     continue;
                 }
-                if let Err(e) = append_event(st, &mut file_opt).await {
-                    tracing::error!("IO error appending event to output file: {}", e);
+                match append_event(st, &filter, args.format, &mut file_opt).await {
+                    Ok(true) => summary.recorded += 1,
+                    Ok(false) => (),
+                    Err(e) => tracing::error!("IO error appending event to output file: {}", e),
                 }
             },
 
@@ -162,41 +411,123 @@ async fn run_main_loop(args: CmdArgs, mut state_events: Receiver<WsMessage>, mut
         }
     }
 
+    if let Some(mut file) = file_opt {
+        if let Err(e) = file.shutdown().await {
+            tracing::error!("could not correctly close log file: {}", e);
+        }
+    }
+    tracing::info!("summary:\n{}", summary);
     Ok(())
 }
 
-fn filter_event(msg: WsMessage) -> Option<WsMessage> {
-    use hass::serde_json::value::Value;
-    if let WsMessage::Event { event: EventObj::Event { data, ..}, .. } = &msg {
-        if let Some(Value::String(device_class)) = data.pointer("/new_state/attributes/device_class") {
-            if device_class == "motion" {
-                return Some(msg);
-            }
-        }
+/// Returns the device class of the entity whose state changed with `msg`, if any.
+fn device_class(msg: &WsMessage) -> Option<&str> {
+    match msg {
+        WsMessage::Event { event: EventObj::Event { data, ..}, .. } => {
+            data.pointer("/new_state/attributes/device_class").and_then(Value::as_str)
+        },
+        _ => None,
     }
-    None
 }
 
-async fn append_event(msg: WsMessage , file: &mut Option<File>) -> io::Result<()> {
-    if let Some(msg) = filter_event(msg) {
+/// Keeps `msg` if it is a `state_changed` event of an entity passing both the
+/// device class and the entity id lists of `filter`.
+fn filter_event(msg: WsMessage, filter: &Filter) -> Option<WsMessage> {
+    let state_changed = matches!(&msg, WsMessage::Event { event: EventObj::Event { event_type: EventType::StateChanged, .. }, .. });
+    let keep = state_changed
+        && (filter.device_classes.is_empty()
+            || device_class(&msg).is_some_and(|class| filter.device_classes.iter().any(|c| c == class)))
+        && (filter.entity_ids.is_empty()
+            || msg.entity_id().is_some_and(|id| filter.entity_ids.iter().any(|e| e == id)));
+    keep.then_some(msg)
+}
+
+/// Appends `msg` to `file` in the given `format` if it passes the filter,
+/// returning whether it did.
+async fn append_event(msg: WsMessage, filter: &Filter, format: Format, file: &mut Option<Output>) -> io::Result<bool> {
+    append_event_with(msg, filter, file, |msg| format.serialize(msg)).await
+}
+
+/// Same as [append_event()], with a custom serializer `serialize`.
+async fn append_event_with<E: std::fmt::Display>(msg: WsMessage, filter: &Filter, file: &mut Option<Output>, serialize: impl FnOnce(&WsMessage) -> Result<String, E>) -> io::Result<bool> {
+    if let Some(msg) = filter_event(msg, filter) {
         tracing::debug!("raw state_changed event:\n{}", msg);
-        if let Ok(yaml) = serde_yaml::to_string(&msg) {
-            tracing::info!("received new state_change event:\n{}", yaml);
-            if let Some(file) = file {
-                let _ = file.write(yaml.as_bytes()).await?;
+        match serialize(&msg) {
+            Ok(serialized) => {
+                tracing::info!("received new state_change event:\n{}", serialized);
+                if let Some(file) = file {
+                    file.write_all(serialized.as_bytes()).await?;
+                }
+                return Ok(true);
+            },
+            Err(e) => {
+                let event_type = match &msg {
+                    WsMessage::Event { event: EventObj::Event { event_type, .. }, .. } => Some(*event_type),
+                    _ => None,
+                };
+                tracing::warn!("dropped event id={:?} event_type={:?}: could not serialize: {}", msg.id(), event_type, e);
+            },
+        }
+    }
+    Ok(false)
+}
+
+
+/// Fires the `state_changed` events of the YAML recording read from `recording`,
+/// e.g. opened with [scenario::open()], on the event bus of HA, returning how many
+/// were. With `realtime`, events are fired with the same delays found between their
+/// `time_fired`.
+///
+/// Messages other than `state_changed` events are skipped with a warning, as are
+/// documents that cannot be parsed. Waiting for the next event is interrupted by
+/// either `cancel` or CTRL-C, leaving the following events out.
+async fn replay(api: &WsApi, recording: impl BufRead + Send + 'static, realtime: bool, mut cancel: shutdown::Shutdown) -> error::Result<usize> {
+    let mut fired = 0;
+    let mut last_fired: Option<DateTime<Utc>> = None;
+    let mut messages = scenario::spawn_messages(recording);
+    let mut idx = 0;
+    while let Some(message) = messages.recv().await {
+        idx += 1;
+        let msg = match message? {
+            Ok(msg) => msg,
+            Err(issue) => {
+                tracing::warn!("replay: skipped message #{}: {}", idx, issue);
+                continue;
+            },
+        };
+        let WsMessage::Event { event: EventObj::Event { event_type: EventType::StateChanged, data, time_fired, .. }, .. } = msg else {
+            tracing::warn!("replay: skipped message #{}: not a state_changed event", idx);
+            continue;
+        };
+        if realtime {
+            if let Some(delay) = last_fired.and_then(|last| (time_fired - last).to_std().ok()) {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => (),
+                    _ = cancel.recv() => {
+                        tracing::info!("replay: interrupted by shutdown after {} events", fired);
+                        break;
+                    },
+                    _ = signal::ctrl_c() => {
+                        tracing::info!("replay: interrupted by CTRL-C after {} events", fired);
+                        break;
+                    },
+                }
             }
+            last_fired = Some(time_fired);
         }
+        api.fire_event(EventType::StateChanged, Some(data)).await?;
+        fired += 1;
     }
-    Ok(())
+    Ok(fired)
 }
 
 
-async fn recv_ctrl_events(rx_opt: &mut Option<Receiver<WsMessage>>) -> Option<EventType> {
+async fn recv_ctrl_events(rx_opt: &mut Option<Receiver<WsMessage>>) -> Option<(EventType, Value)> {
     match rx_opt.as_mut() {
         None => None,
         Some(rx) => match rx.recv().await {
-            Some(WsMessage::Event { event: EventObj::Event { event_type, .. } ,.. }) => {
-                Some(event_type)
+            Some(WsMessage::Event { event: EventObj::Event { event_type, data, .. } ,.. }) => {
+                Some((event_type, data))
             },
             _ => None
         },
@@ -207,23 +538,344 @@ fn err(code: ExitCode, err: Error, msg: &'static str) -> AppError {
     ( code, Some((err, msg)) )
 }
 
-async fn open_file(args: &CmdArgs, idx: i32) -> Result<File, AppError> {
-    let file_name = format!("{}/{}-{}.yaml", args.output_folder, args.test_name, idx);
+async fn open_file(args: &CmdArgs, idx: i32, clock: &dyn Clock, summary: &mut Summary) -> Result<Output, AppError> {
+    let file_name = args.name_template.expand(args.test_name.as_deref().unwrap_or_default(), idx, clock.now())
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            (ExitCode::OpenFileError, None)
+        })?;
+    let mut file_name = format!("{}/{}.{}", args.output_folder, file_name, args.format.extension());
+    if let Some(compression) = args.compress {
+        file_name = format!("{}.{}", file_name, compression.extension());
+    }
     tracing::info!("opened {} for writing", file_name);
-    OpenOptions::new()
+    let file = OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
-        .open(file_name)
+        .open(&file_name)
         .await
-        .or_else(|e| {
+        .map_err(|e| {
             tracing::error!("{}", e);
-            Err((ExitCode::OpenFileError, None))
-        })
+            (ExitCode::OpenFileError, None)
+        })?;
+    summary.files.push(file_name);
+    Ok(match args.compress {
+        None => Box::new(file),
+        Some(Compression::Gzip) => Box::new(GzipEncoder::new(file)),
+    })
 }
 
 
 async fn register_control_events(api: &WsApi) -> error::Result<Receiver<WsMessage>> {
-    let events = [EventType::HaevloStart, EventType::HaevloStop];
+    let events = [EventType::HaevloStart, EventType::HaevloStop, EventType::PiresenceLoglevel];
     api.subscribe_events(&events).await
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hass::json::ContextObject;
+    use hass::serde_json::json;
+    use tracing_test::traced_test;
+
+    fn filter(device_classes: &[&str], entity_ids: &[&str]) -> Filter {
+        Filter {
+            device_classes: device_classes.iter().map(|c| c.to_string()).collect(),
+            entity_ids: entity_ids.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    fn motion() -> Filter {
+        filter(&["motion"], &[])
+    }
+
+    fn state_changed(device_class: &str) -> WsMessage {
+        state_changed_of("binary_sensor.hallway", device_class)
+    }
+
+    fn state_changed_of(entity_id: &str, device_class: &str) -> WsMessage {
+        WsMessage::Event {
+            id: 7,
+            event: EventObj::Event {
+                data: json!({"new_state": {"entity_id": entity_id, "attributes": {"device_class": device_class}}}),
+                event_type: EventType::StateChanged,
+                time_fired: hass::serde_json::from_value(json!("2022-05-10T23:34:50.163029Z")).unwrap(),
+                origin: "LOCAL".to_owned(),
+                context: ContextObject::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn device_class_filter() {
+        let classes = filter(&["motion", "occupancy"], &[]);
+        assert!(filter_event(state_changed("occupancy"), &classes).is_some());
+        assert!(filter_event(state_changed("temperature"), &classes).is_none());
+        assert!(filter_event(state_changed("temperature"), &Filter::default()).is_some());
+    }
+
+    #[test]
+    fn entity_id_filter() {
+        let hallway = filter(&["motion"], &["binary_sensor.hallway"]);
+        assert!(filter_event(state_changed_of("binary_sensor.hallway", "motion"), &hallway).is_some());
+        assert!(filter_event(state_changed_of("binary_sensor.garage", "motion"), &hallway).is_none());
+        // Both filters must pass
+        assert!(filter_event(state_changed_of("binary_sensor.hallway", "door"), &hallway).is_none());
+
+        let args = CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t",
+            "--entity-id", "binary_sensor.hallway", "test"]).unwrap();
+        assert_eq!(Filter::new(&args).entity_ids, vec!["binary_sensor.hallway"]);
+    }
+
+    #[test]
+    fn device_class_args() {
+        let args = CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t", "test"]).unwrap();
+        assert_eq!(args.device_classes, vec!["motion"]);
+        let args = CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t",
+            "--device-class", "door", "--device-class", "occupancy", "test"]).unwrap();
+        assert_eq!(args.device_classes, vec!["door", "occupancy"]);
+        assert_eq!(args.test_name.as_deref(), Some("test"));
+    }
+
+    #[test]
+    fn replay_args() {
+        let args = CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t", "--replay", "rec.yaml", "--realtime"]).unwrap();
+        assert_eq!(args.replay.as_deref(), Some("rec.yaml"));
+        assert!(args.realtime);
+        assert_eq!(args.test_name, None);
+        assert!(CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t"]).is_err());
+        assert!(CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t", "--realtime", "test"]).is_err());
+    }
+
+    #[test]
+    fn name_template_expansion() {
+        use hass::sync::clock::MockClock;
+
+        let clock = MockClock::new(DateTime::from_timestamp(1_652_225_690, 0).unwrap());
+        let expand = |template: &str| template.parse::<NameTemplate>().unwrap()
+            .expand("kitchen", 3, clock.now())
+            .unwrap();
+        assert_eq!(expand("{name}-{idx}"), "kitchen-3");
+        assert_eq!(expand("{name}-{timestamp}"), "kitchen-20220510T233450Z");
+        assert_eq!(expand("{date}_{name}_{idx}"), "2022-05-10_kitchen_3");
+        assert_eq!(expand("recording"), "recording");
+    }
+
+    #[test]
+    fn name_template_invalid() {
+        for template in ["", "..", "{name}/{idx}.yaml", "{nome}.yaml", "{name.yaml"] {
+            assert!(template.parse::<NameTemplate>().is_err(), "accepted template: {:?}", template);
+        }
+        let template: NameTemplate = "{name}.yaml".parse().unwrap();
+        assert!(template.expand("../kitchen", 0, DateTime::UNIX_EPOCH).is_err());
+    }
+
+    #[test]
+    fn output_formats() {
+        let args = CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t", "test"]).unwrap();
+        assert_eq!(args.format, Format::Yaml);
+        let args = CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t", "--format", "ndjson", "test"]).unwrap();
+        assert_eq!(args.format, Format::Ndjson);
+        assert!(CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t", "--format", "toml", "test"]).is_err());
+
+        let msg = state_changed("motion");
+        let yaml = Format::Yaml.serialize(&msg).unwrap();
+        assert_eq!(hass::hast::scenario::read(&yaml).len(), 1);
+        let json = Format::Json.serialize(&msg).unwrap();
+        assert!(json.lines().count() > 1);
+        assert_eq!(hass::json::deserialize(&json).unwrap(), msg);
+        let ndjson = Format::Ndjson.serialize(&msg).unwrap();
+        assert_eq!(ndjson.lines().count(), 1);
+        assert!(ndjson.ends_with('\n'));
+        assert_eq!(hass::json::deserialize(&ndjson).unwrap(), msg);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn append_event_serialization_failure() {
+        let msg = state_changed("motion");
+        append_event_with(msg, &motion(), &mut None, |_| Err("crafted failure")).await.unwrap();
+        assert!(logs_contain("dropped event id=Some(7) event_type=Some(StateChanged): could not serialize: crafted failure"));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "hast-server")]
+    async fn recording_summary() {
+        use hass::hast::server::{Hast, HastConfig};
+
+        const PORT: u16 = 18128;
+        let manager = shutdown::Manager::new();
+        let yaml_dir = format!("{}/tests/resources/", env!("CARGO_MANIFEST_DIR"));
+        let cfg = HastConfig::new_with_scenario(PORT, "letmein".to_owned(), yaml_dir, Some("000-base.yaml".to_owned()));
+        let hast = Hast::new(cfg, manager.subscribe());
+        let mut startup_notifier = hast.startup_notifier();
+        tokio::spawn(hast.run());
+        let _ = startup_notifier.changed().await;
+
+        let api = WsApi::new_unsecure("127.0.0.1", PORT, "letmein", manager.subscribe()).await.unwrap();
+        let mut state_events = api.subscribe_event(Some(EventType::StateChanged)).await.unwrap();
+        let mut summary = Summary::new();
+        for _ in 0..8 {
+            let msg = state_events.recv().await.unwrap();
+            summary.add_seen(&msg);
+            if append_event(msg, &motion(), Format::Yaml, &mut None).await.unwrap() {
+                summary.recorded += 1;
+            }
+        }
+
+        assert_eq!(summary.seen, 8);
+        assert_eq!(summary.recorded, 8);
+        assert_eq!(summary.device_classes, BTreeMap::from([("motion".to_owned(), 8)]));
+        assert!(summary.to_string().contains("events recorded: 8"));
+        manager.shutdown().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[cfg(feature = "hast-server")]
+    async fn replay_recording() {
+        use hass::hast::server::{Hast, HastConfig};
+
+        const PORT: u16 = 18129;
+        let manager = shutdown::Manager::new();
+        let cfg = HastConfig::new(PORT, "letmein".to_owned(), ".".to_owned());
+        let hast = Hast::new(cfg, manager.subscribe());
+        let stats = hast.stats();
+        let mut startup_notifier = hast.startup_notifier();
+        tokio::spawn(hast.run());
+        let _ = startup_notifier.changed().await;
+
+        let recording = [
+            Format::Yaml.serialize(&state_changed_of("binary_sensor.hallway", "motion")).unwrap(),
+            Format::Yaml.serialize(&WsMessage::Pong { id: 3 }).unwrap(),
+            Format::Yaml.serialize(&state_changed_of("binary_sensor.kitchen", "motion")).unwrap(),
+        ].concat();
+        let api = WsApi::new_unsecure("127.0.0.1", PORT, "letmein", manager.subscribe()).await.unwrap();
+        assert_eq!(replay(&api, std::io::Cursor::new(recording), true, manager.subscribe()).await.unwrap(), 2);
+
+        let fired: Vec<Value> = stats.received().into_iter()
+            .filter_map(|msg| match msg {
+                WsMessage::FireEvent { event_type: EventType::StateChanged, event_data, .. } => event_data,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(fired.len(), 2);
+        assert_eq!(fired[0]["new_state"]["entity_id"], "binary_sensor.hallway");
+        assert_eq!(fired[1]["new_state"]["entity_id"], "binary_sensor.kitchen");
+
+        // A long gap between events does not hold back shutdown
+        let mut later = state_changed_of("binary_sensor.kitchen", "motion");
+        if let WsMessage::Event { event: EventObj::Event { time_fired, .. }, .. } = &mut later {
+            *time_fired += chrono::Duration::hours(1);
+        }
+        let recording = [
+            Format::Yaml.serialize(&state_changed_of("binary_sensor.hallway", "motion")).unwrap(),
+            Format::Yaml.serialize(&later).unwrap(),
+        ].concat();
+        let cancel = manager.subscribe();
+        let (replayed, ()) = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            tokio::join!(
+                replay(&api, std::io::Cursor::new(recording), true, cancel),
+                async {
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    manager.shutdown().await;
+                },
+            )
+        }).await.unwrap();
+        assert_eq!(replayed.unwrap(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[cfg(feature = "hast-server")]
+    async fn replay_compressed_recording() {
+        use std::io::Write;
+        use hass::hast::server::{Hast, HastConfig};
+
+        const PORT: u16 = 18131;
+        let manager = shutdown::Manager::new();
+        let cfg = HastConfig::new(PORT, "letmein".to_owned(), ".".to_owned());
+        let hast = Hast::new(cfg, manager.subscribe());
+        let stats = hast.stats();
+        let mut startup_notifier = hast.startup_notifier();
+        tokio::spawn(hast.run());
+        let _ = startup_notifier.changed().await;
+
+        // As written by --compress gzip, with several documents
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.yaml.gz");
+        let mut encoder = flate2::write::GzEncoder::new(std::fs::File::create(&path).unwrap(), flate2::Compression::default());
+        for entity_id in ["binary_sensor.hallway", "binary_sensor.kitchen"] {
+            encoder.write_all(Format::Yaml.serialize(&state_changed_of(entity_id, "motion")).unwrap().as_bytes()).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let api = WsApi::new_unsecure("127.0.0.1", PORT, "letmein", manager.subscribe()).await.unwrap();
+        let recording = scenario::open(&path).unwrap();
+        assert_eq!(replay(&api, recording, false, manager.subscribe()).await.unwrap(), 2);
+        let fired = stats.received().into_iter()
+            .filter(|msg| matches!(msg, WsMessage::FireEvent { event_type: EventType::StateChanged, .. }))
+            .count();
+        assert_eq!(fired, 2);
+
+        drop(api);
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "hast-server")]
+    async fn gzip_round_trip() {
+        let output_folder = std::env::temp_dir().join("haevlo-gzip");
+        std::fs::create_dir_all(&output_folder).unwrap();
+        let args = CmdArgs::try_parse_from(["haevlo", "--host", "h", "--token", "t", "--compress", "gzip",
+            "--output-folder", output_folder.to_str().unwrap(), "test"]).unwrap();
+        assert_eq!(args.compress, Some(Compression::Gzip));
+
+        let mut summary = Summary::new();
+        let mut file = Some(open_file(&args, 0, &SystemClock, &mut summary).await.unwrap());
+        for entity_id in ["binary_sensor.hallway", "binary_sensor.kitchen"] {
+            assert!(append_event(state_changed_of(entity_id, "motion"), &motion(), Format::Yaml, &mut file).await.unwrap());
+        }
+        file.unwrap().shutdown().await.unwrap();
+
+        let path = &summary.files[0];
+        assert!(path.ends_with("/test-0.yaml.gz"));
+        assert_eq!(std::fs::read(path).unwrap()[..2], [0x1f, 0x8b]);
+        let messages = hass::hast::scenario::read_file(path).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].as_ref().unwrap().entity_id(), Some("binary_sensor.kitchen"));
+        let _ = std::fs::remove_dir_all(output_folder);
+    }
+
+    #[test]
+    #[cfg(feature = "hast-server")]
+    fn single_thread_runtime() {
+        use hass::hast::server::{Hast, HastConfig};
+
+        const PORT: u16 = 18127;
+        let runtime = runtime::build(true).unwrap();
+        runtime.block_on(async {
+            let manager = shutdown::Manager::new();
+            let yaml_dir = format!("{}/tests/resources/", env!("CARGO_MANIFEST_DIR"));
+            let cfg = HastConfig::new_with_scenario(PORT, "letmein".to_owned(), yaml_dir, Some("000-base.yaml".to_owned()));
+            let hast = Hast::new(cfg, manager.subscribe());
+            let mut startup_notifier = hast.startup_notifier();
+            tokio::spawn(hast.run());
+            let _ = startup_notifier.changed().await;
+
+            let api = WsApi::new_unsecure("127.0.0.1", PORT, "letmein", manager.subscribe()).await.unwrap();
+            let mut state_events = api.subscribe_event(Some(EventType::StateChanged)).await.unwrap();
+            let path = std::env::temp_dir().join("haevlo-single-thread.yaml");
+            let mut file: Option<Output> = Some(Box::new(tokio::fs::File::create(&path).await.unwrap()));
+            for _ in 0..8 {
+                append_event(state_events.recv().await.unwrap(), &motion(), Format::Yaml, &mut file).await.unwrap();
+            }
+            file.unwrap().shutdown().await.unwrap();
+
+            let recorded = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(hass::hast::scenario::read(&recorded).len(), 8);
+            let _ = std::fs::remove_file(path);
+            manager.shutdown().await;
+        });
+    }
+}
@@ -1,10 +1,11 @@
 use clap::{self, StructOpt};
-use hass::sync::shutdown;
-use hass::hast::server::{HastConfig, Hast};
+use hass::sync::{runtime, shutdown};
+use hass::hast::{scenario, server::{ChaosConfig, Expectation, HastConfig, Hast}};
 use std::io;
+use std::path::Path;
+use std::time::Duration;
 use tokio::{self, signal};
 use tokio_tungstenite::tungstenite::Result;
-use tracing_subscriber;
 
 /// Home Assistant Surrogate Tool
 ///
@@ -13,23 +14,35 @@ use tracing_subscriber;
 ///
 /// Clients connecting to the mock service should expect the same behaviour of a real
 /// HA instance, with the difference that upon subscription, all events are sent in
-/// a single burst of messages to speed things up. The client should be aware of this
-/// and adjust any time-based calculation on the timestamps included in messages, rather
-/// than on real system clocks.
+/// a single burst of messages to speed things up, unless --realtime is given. The
+/// client should be aware of this and adjust any time-based calculation on the
+/// timestamps included in messages, rather than on real system clocks.
 /// 
 /// Another difference with real HA, is the preliminary setup phase which include new
 /// kinds of messages to customize the behaviour of the mock before actually starting
-/// the HA simulation. This phase is only available when the optional YAML_SCENARIO
-/// positional argument is not provided.
+/// the HA simulation. This phase is only available when the optional YAML_SCENARIOS
+/// positional arguments are not provided.
 /// Please refer to the [hass::hast] module for more details.
+///
+/// When some commands are expected with --expect, hast quits as soon as the first
+/// connection gets closed, reporting whether the client sent each of them, and
+/// exiting with a non-zero code if not.
+///
+/// Exit codes: 0 on success, 1 when expected commands were not received, 2 when
+/// a scenario is missing or broken, either before starting or while playing it,
+/// and 3 when the mock service could not be started.
 #[derive(clap::Parser, Debug)]
-#[clap(author, version)]
+#[clap(author, version, args_conflicts_with_subcommands = true)]
 struct CmdArgs {
 
     /// Port used to expose the mock HA WebSocket service
     #[clap(long, default_value_t = 8123)]
     pub port: u16,
 
+    /// IP address the mock HA WebSocket service binds to, e.g. 0.0.0.0 for all interfaces
+    #[clap(long, default_value = "127.0.0.1")]
+    pub bind: String,
+
     /// Authentication token required by the mock HA WebSocket service
     #[clap(long, default_value = "letmein")]
     pub token: String,
@@ -38,53 +51,363 @@ struct CmdArgs {
     #[clap(long, default_value = ".")]
     pub yaml_dir: String,
 
-    /// Filename of the YAML event log to run
-    pub yaml_scenario: Option<String>,
+    /// Send the homeassistant_start and homeassistant_started events to subscribers
+    /// before the YAML event log
+    #[clap(long)]
+    pub emit_lifecycle: bool,
+
+    /// Randomly drop or reorder the events of the YAML event log, see the --chaos-* options
+    #[clap(long)]
+    pub chaos: bool,
+
+    /// Probability of each event to be dropped with --chaos
+    #[clap(long, default_value_t = 0.1)]
+    pub chaos_drop: f64,
+
+    /// Probability of each event to be swapped with the previous one with --chaos
+    #[clap(long, default_value_t = 0.1)]
+    pub chaos_reorder: f64,
+
+    /// Seed for --chaos, the same seed always altering events the same way
+    #[clap(long, default_value_t = 0)]
+    pub chaos_seed: u64,
+
+    /// Send the events of the YAML event log with their original timing, rather
+    /// than in a single burst
+    #[clap(long)]
+    pub realtime: bool,
+
+    /// Play the YAML event log again and again, until the connection gets closed
+    #[clap(long = "loop")]
+    pub loop_scenario: bool,
+
+    /// Delay in milliseconds between two plays of the YAML event log with --loop
+    #[clap(long, default_value_t = 1000)]
+    pub loop_delay_ms: u64,
+
+    /// Interval in milliseconds between the time_changed events sent to subscribers
+    /// once the YAML event log is over, none if missing
+    #[clap(long)]
+    pub heartbeat_ms: Option<u64>,
+
+    /// Maximum number of events read from the YAML event log, the following ones being ignored
+    #[clap(long)]
+    pub max_events: Option<usize>,
+
+    /// WebSocket subprotocol accepted in the handshake, when offered by the client.
+    /// May be repeated, in order of preference
+    #[clap(long)]
+    pub subprotocol: Vec<String>,
+
+    /// Run on a single-threaded tokio runtime
+    #[clap(long)]
+    pub single_thread: bool,
+
+    /// Command the client must send within its session, either a service such as
+    /// `light.turn_on` or a message type such as `get_states`. May be repeated
+    #[clap(long)]
+    pub expect: Vec<Expectation>,
+
+    /// Filenames of the YAML event logs to run, played one after the other as
+    /// a single scenario
+    pub yaml_scenarios: Vec<String>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Check YAML event log files for problems, without starting the mock service
+    Lint {
+        /// Filenames of the YAML event logs to check
+        #[clap(required = true)]
+        files: Vec<String>,
+    },
+}
+
 impl CmdArgs {
     fn to_hast_config(&self) -> HastConfig {
         let mut hc = HastConfig::new(self.port,
                 self.token.clone(),
                 self.yaml_dir.clone());
-        if let Some(scenario) = self.yaml_scenario.as_ref() {
+        hc.bind_addr = self.bind.clone();
+        if let Some((scenario, extra)) = self.yaml_scenarios.split_first() {
             hc.yaml_scenario = Some(scenario.clone());
+            hc.extra_scenarios = extra.to_vec();
             hc.skip_hast_messages = true;
         }
+        hc.emit_lifecycle = self.emit_lifecycle;
+        hc.expectations = self.expect.clone();
+        hc.max_scenario_events = self.max_events;
+        hc.realtime = self.realtime;
+        hc.loop_scenario = self.loop_scenario;
+        hc.loop_delay = Duration::from_millis(self.loop_delay_ms);
+        hc.heartbeat = self.heartbeat_ms.map(Duration::from_millis);
+        hc.subprotocols = self.subprotocol.clone();
+        if self.chaos {
+            hc.chaos = Some(ChaosConfig {
+                drop_prob: self.chaos_drop,
+                reorder_prob: self.chaos_reorder,
+                seed: self.chaos_seed,
+            });
+        }
         hc
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), io::Error> {
-    tracing_subscriber::fmt::init();
+/// Exit codes of the process, see also the ones of `haevlo`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum ExitCode {
+    /// Quit on CTRL-C, or all the commands expected with --expect were received
+    Success = 0,
+    /// Some commands expected with --expect were not received
+    ExpectationsUnmet,
+    /// A scenario is missing or has errors, found either before starting, by
+    /// `hast lint`, or while playing it
+    ScenarioError,
+    /// The mock service could not be started, e.g. because its port is taken
+    ServiceError,
+}
+
+fn main() {
+    // Logs go to stderr along with the issues found, leaving reports to stdout
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(io::stderr)
+        .init();
 
     let args = CmdArgs::parse();
     tracing::info!("args: {:?}", args);
 
+    let code = if let Some(Command::Lint { files }) = &args.command {
+        lint(files)
+    } else {
+        match runtime::build(args.single_thread) {
+            Ok(runtime) => runtime.block_on(run(args)),
+            Err(e) => {
+                tracing::error!("could not build the tokio runtime: {}", e);
+                ExitCode::ServiceError
+            },
+        }
+    };
+    if code != ExitCode::Success {
+        tracing::error!("exit with error: {:?} ({})", code, code as i32);
+    }
+    std::process::exit(code as i32);
+}
+
+/// Runs the mock service until CTRL-C, or until the first connection gets closed
+/// when expecting commands, and returns the exit code: non-zero when scenarios
+/// were broken or some expected commands were not received.
+async fn run(args: CmdArgs) -> ExitCode {
     let hast_cfg = args.to_hast_config();
+    if let Err(e) = check_scenarios(&hast_cfg) {
+        tracing::error!("{}", e);
+        return ExitCode::ScenarioError;
+    }
     let manager = shutdown::Manager::new();
 
-    let hast = Hast::new(hast_cfg, manager.subscribe());
-
-    let mut startup_notifier = hast.startup_notifier();
-
-    tracing::debug!("spawning main task");
-    tokio::spawn(async move {
-        if let Err(e) = hast.run().await {
-            tracing::error!("hast terminated with error: {}", e);
-        }
-    });
-
-    let _ = startup_notifier.changed().await;
+    let mut hast = Hast::new(hast_cfg, manager.subscribe());
+    let stats = hast.stats();
+    if let Err(e) = hast.start() {
+        tracing::error!("hast could not start: {}", e);
+        return ExitCode::ServiceError;
+    }
     tracing::info!("hast service ready");
 
-    if let Err(e) = signal::ctrl_c().await {
-        tracing::error!("failed to wait for ctrl-c signal: {}", e);
+    tokio::select! {
+        res = signal::ctrl_c() => if let Err(e) = res {
+            tracing::error!("failed to wait for ctrl-c signal: {}", e);
+        },
+        _ = stats.connection_closed(), if !args.expect.is_empty() => {
+            tracing::info!("connection closed, checking expectations");
+        },
     }
+    drop(hast);
     manager.shutdown().await;
 
+    let unmet = stats.unmet_expectations();
+    for (addr, expectation) in &unmet {
+        eprintln!("{}: expectation not met: {}", addr, expectation);
+    }
+    let scenario_errors = stats.scenario_errors();
+    if scenario_errors > 0 {
+        eprintln!("{} scenario error(s) while playing", scenario_errors);
+    }
+
     tracing::info!("all task terminated, quitting");
+    if scenario_errors > 0 {
+        ExitCode::ScenarioError
+    } else if !unmet.is_empty() {
+        ExitCode::ExpectationsUnmet
+    } else {
+        ExitCode::Success
+    }
+}
+
+/// Checks that all the scenario files configured in `hc` exist, and that
+/// none of them has errors, which are printed as by `hast lint`.
+fn check_scenarios(hc: &HastConfig) -> Result<(), io::Error> {
+    for scenario in hc.yaml_scenario.iter().chain(hc.extra_scenarios.iter()) {
+        let path = Path::new(&hc.yaml_dir).join(scenario);
+        if !path.is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("YAML event log not found: {}", path.display())));
+        }
+        let errors: Vec<_> = scenario::lint(&scenario::read_source(&path)?).into_iter()
+            .filter(|issue| issue.severity == scenario::Severity::Error)
+            .collect();
+        for issue in &errors {
+            eprintln!("{}: {}", path.display(), issue);
+        }
+        if !errors.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("YAML event log has {} error(s): {}", errors.len(), path.display())));
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Lints all the given scenario `files`, printing the issues found to stderr
+/// and a summary to stdout, and returns the exit code: [ExitCode::ScenarioError] when at least one error is found.
+fn lint(files: &[String]) -> ExitCode {
+    let (mut errors, mut warnings) = (0, 0);
+    for file in files {
+        let source = match scenario::read_source(file) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("{}: error: could not read file: {}", file, e);
+                errors += 1;
+                continue;
+            }
+        };
+        for issue in scenario::lint(&source) {
+            match issue.severity {
+                scenario::Severity::Error => errors += 1,
+                scenario::Severity::Warning => warnings += 1,
+            }
+            eprintln!("{}: {}", file, issue);
+        }
+    }
+    println!("{} file(s) checked: {} error(s), {} warning(s)", files.len(), errors, warnings);
+    if errors > 0 { ExitCode::ScenarioError } else { ExitCode::Success }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_thread_runtime() {
+        let args = CmdArgs::try_parse_from(["hast", "--single-thread", "--port", "18126"]).unwrap();
+        let runtime = runtime::build(args.single_thread).unwrap();
+        runtime.block_on(async {
+            let manager = shutdown::Manager::new();
+            let hast = Hast::new(args.to_hast_config(), manager.subscribe());
+            let mut startup_notifier = hast.startup_notifier();
+            tokio::spawn(hast.run());
+            let _ = startup_notifier.changed().await;
+
+            let api = hass::WsApi::new_unsecure("127.0.0.1", 18126, &args.token, manager.subscribe()).await;
+            assert!(api.is_ok());
+            manager.shutdown().await;
+        });
+    }
+
+    #[test]
+    fn lint_subcommand() {
+        let args = CmdArgs::try_parse_from(["hast", "lint", "a.yaml", "b.yaml"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Lint { files }) if files == ["a.yaml", "b.yaml"]));
+
+        let args = CmdArgs::try_parse_from(["hast", "a.yaml"]).unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.yaml_scenarios, ["a.yaml"]);
+    }
+
+    #[test]
+    fn multiple_scenarios() {
+        let yaml_dir = format!("{}/tests/resources", env!("CARGO_MANIFEST_DIR"));
+        let args = CmdArgs::try_parse_from(["hast", "--yaml-dir", &yaml_dir, "000-base.yaml", "001-contexts.yaml"]).unwrap();
+        let hc = args.to_hast_config();
+        assert_eq!(hc.yaml_scenario.as_deref(), Some("000-base.yaml"));
+        assert_eq!(hc.extra_scenarios, ["001-contexts.yaml"]);
+        assert!(check_scenarios(&hc).is_ok());
+
+        let args = CmdArgs::try_parse_from(["hast", "--yaml-dir", &yaml_dir, "000-base.yaml", "missing.yaml"]).unwrap();
+        assert_eq!(check_scenarios(&args.to_hast_config()).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn expect_args() {
+        let args = CmdArgs::try_parse_from(["hast", "--expect", "light.turn_on", "--expect", "get_states"]).unwrap();
+        assert_eq!(args.to_hast_config().expectations, vec![
+            Expectation::CallService { domain: "light".to_string(), service: "turn_on".to_string() },
+            Expectation::Type("get_states".to_string()),
+        ]);
+        assert!(CmdArgs::try_parse_from(["hast", "--expect", "light."]).is_err());
+    }
+
+    #[test]
+    fn max_events_arg() {
+        let args = CmdArgs::try_parse_from(["hast", "--max-events", "100"]).unwrap();
+        assert_eq!(args.to_hast_config().max_scenario_events, Some(100));
+        let args = CmdArgs::try_parse_from(["hast"]).unwrap();
+        assert_eq!(args.to_hast_config().max_scenario_events, None);
+    }
+
+    #[test]
+    fn realtime_arg() {
+        assert!(CmdArgs::try_parse_from(["hast", "--realtime"]).unwrap().to_hast_config().realtime);
+        assert!(!CmdArgs::try_parse_from(["hast"]).unwrap().to_hast_config().realtime);
+    }
+
+    #[test]
+    fn loop_args() {
+        let hc = CmdArgs::try_parse_from(["hast", "--loop", "--loop-delay-ms", "250"]).unwrap().to_hast_config();
+        assert!(hc.loop_scenario);
+        assert_eq!(hc.loop_delay, Duration::from_millis(250));
+        assert!(!CmdArgs::try_parse_from(["hast"]).unwrap().to_hast_config().loop_scenario);
+    }
+
+    #[test]
+    fn heartbeat_arg() {
+        let hc = CmdArgs::try_parse_from(["hast", "--heartbeat-ms", "500"]).unwrap().to_hast_config();
+        assert_eq!(hc.heartbeat, Some(Duration::from_millis(500)));
+        assert!(CmdArgs::try_parse_from(["hast"]).unwrap().to_hast_config().heartbeat.is_none());
+    }
+
+    #[test]
+    fn bind_arg() {
+        let args = CmdArgs::try_parse_from(["hast", "--bind", "0.0.0.0"]).unwrap();
+        assert_eq!(args.to_hast_config().bind_addr, "0.0.0.0");
+        let args = CmdArgs::try_parse_from(["hast"]).unwrap();
+        assert_eq!(args.to_hast_config().bind_addr, "127.0.0.1");
+    }
+
+    #[test]
+    fn subprotocol_args() {
+        let args = CmdArgs::try_parse_from(["hast", "--subprotocol", "v2.ha", "--subprotocol", "v1.ha"]).unwrap();
+        assert_eq!(args.to_hast_config().subprotocols, vec!["v2.ha", "v1.ha"]);
+        assert!(CmdArgs::try_parse_from(["hast"]).unwrap().to_hast_config().subprotocols.is_empty());
+    }
+
+    #[test]
+    fn chaos_args() {
+        let args = CmdArgs::try_parse_from(["hast", "--chaos-drop", "0.5"]).unwrap();
+        assert!(args.to_hast_config().chaos.is_none());
+
+        let args = CmdArgs::try_parse_from(["hast", "--chaos", "--chaos-drop", "0.5", "--chaos-seed", "3"]).unwrap();
+        assert_eq!(args.to_hast_config().chaos, Some(ChaosConfig { drop_prob: 0.5, reorder_prob: 0.1, seed: 3 }));
+    }
+
+    #[test]
+    fn lint_exit_code() {
+        let good = format!("{}/tests/resources/000-base.yaml", env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(lint(std::slice::from_ref(&good)), ExitCode::Success);
+
+        let bad = std::env::temp_dir().join("hast-lint-bad.yaml");
+        std::fs::write(&bad, "---\ntype: event\nid: [1, 2\n").unwrap();
+        assert_eq!(lint(&[good, bad.to_string_lossy().into_owned()]), ExitCode::ScenarioError);
+        let _ = std::fs::remove_file(bad);
+    }
+}
@@ -1,9 +1,9 @@
 use anyhow;
 use serde_json;
 use thiserror::Error;
-use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::{self, error::ProtocolError};
 use url;
-use crate::json::{WsMessage, ErrorObject};
+use crate::json::{Id, WsMessage, ErrorObject};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -33,6 +33,9 @@ pub enum Error {
     #[error("HA sent error code {0}: {1}")]
     ProtocolError(String, String),
 
+    #[error("HA rejected id {0} as already used")]
+    IdReuse(Id),
+
     #[error("Next message not found")]
     NoNextMessage,
 
@@ -42,6 +45,18 @@ pub enum Error {
     #[error("Could not subscribe")]
     SubscribeError,
 
+    #[error("Operation timed out")]
+    Timeout,
+
+    #[error("invalid handshake header: {0}")]
+    InvalidHeader(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Request cancelled")]
+    Cancelled,
+
     #[error("Internal error: {cause:?}")]
     InternalError {
         cause: anyhow::Error,
@@ -63,3 +78,96 @@ impl From<ErrorObject> for Error {
         Error::ProtocolError(err.code, err.message)
     }
 }
+
+impl Error {
+    /// Whether the operation failing with this error may succeed if retried
+    /// as-is, as opposed to errors that would happen again.
+    ///
+    /// Failures of the connection to HA are retryable: [Error::Timeout] and
+    /// [Error::WebSocket] transport errors, i.e. I/O errors and connections
+    /// being closed or reset. Any other [Error::WebSocket] is not, nor are
+    /// e.g. [Error::Authentication], [Error::UnexpectedMessage] and parsing errors.
+    ///
+    /// Errors reported by HA are classified according to their code:
+    ///
+    /// | Code                       | Retryable | Reason                                        |
+    /// |----------------------------|-----------|-----------------------------------------------|
+    /// | `timeout`                  | yes       | HA did not complete the operation in time     |
+    /// | `home_assistant_error`     | yes       | e.g. an integration or device was unavailable |
+    /// | `unknown_error`            | yes       | unexpected failure within HA                  |
+    /// | `id_reuse`                 | yes       | retrying allocates a new id                   |
+    /// | `invalid_format`           | no        | malformed message                             |
+    /// | `not_allowed`              | no        |                                               |
+    /// | `unauthorized`             | no        |                                               |
+    /// | `not_found`                | no        |                                               |
+    /// | `not_supported`            | no        |                                               |
+    /// | `unknown_command`          | no        |                                               |
+    /// | `template_error`           | no        |                                               |
+    /// | `service_validation_error` | no        |                                               |
+    ///
+    /// Unknown codes are not retryable, whereas [Error::IdReuse] is.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::ProtocolError(code, _) => matches!(code.as_str(),
+                "timeout" | "home_assistant_error" | "unknown_error" | "id_reuse"),
+            Error::IdReuse(_) => true,
+            Error::Timeout => true,
+            Error::WebSocket(e) => matches!(e,
+                tungstenite::Error::Io(_)
+                | tungstenite::Error::ConnectionClosed
+                | tungstenite::Error::AlreadyClosed
+                | tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protocol_error(code: &str) -> Error {
+        Error::from(ErrorObject { code: code.to_owned(), message: String::from("some message") })
+    }
+
+    #[test]
+    fn retryable_codes() {
+        for code in ["timeout", "home_assistant_error", "unknown_error", "id_reuse"] {
+            assert!(protocol_error(code).is_retryable(), "{}", code);
+        }
+        assert!(Error::IdReuse(3).is_retryable());
+    }
+
+    #[test]
+    fn non_retryable_codes() {
+        for code in ["invalid_format", "not_allowed", "unauthorized", "not_found", "not_supported",
+                "unknown_command", "template_error", "service_validation_error", "not_a_code"] {
+            assert!(!protocol_error(code).is_retryable(), "{}", code);
+        }
+        assert!(!Error::from(None).is_retryable());
+        assert!(!Error::SubscribeError.is_retryable());
+    }
+
+    #[test]
+    fn retryable_connection_errors() {
+        assert!(Error::Timeout.is_retryable());
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(Error::from(tungstenite::Error::Io(io)).is_retryable());
+        assert!(Error::from(tungstenite::Error::ConnectionClosed).is_retryable());
+        assert!(Error::from(tungstenite::Error::AlreadyClosed).is_retryable());
+        let reset = ProtocolError::ResetWithoutClosingHandshake;
+        assert!(Error::from(tungstenite::Error::Protocol(reset)).is_retryable());
+    }
+
+    #[test]
+    fn non_retryable_errors() {
+        assert!(!Error::Authentication(String::from("invalid password")).is_retryable());
+        assert!(!Error::UnexpectedMessage(WsMessage::Pong { id: 1 }).is_retryable());
+        assert!(!Error::JsonParsing("missing field").is_retryable());
+        assert!(!Error::from(serde_json::from_str::<WsMessage>("{").unwrap_err()).is_retryable());
+        assert!(!Error::from(url::Url::parse("not a url").unwrap_err()).is_retryable());
+        assert!(!Error::from(tungstenite::Error::Utf8).is_retryable());
+        let handshake = ProtocolError::HandshakeIncomplete;
+        assert!(!Error::from(tungstenite::Error::Protocol(handshake)).is_retryable());
+    }
+}
@@ -10,6 +10,9 @@
 //! interchangeably.
 
 
+#[cfg(any(feature = "scenario", test))]
+pub mod scenario;
+
 #[cfg(any(feature = "hast-client", test))]
 pub mod client {
     use serde::{Serialize, Deserialize};
@@ -38,26 +41,39 @@ pub mod client {
 #[cfg(any(feature = "hast-server", test))]
 pub mod server {
     use super::client::HastMessage;
-    use std::fs::File;
-    use std::net::SocketAddr;
-    use std::{io, sync::Arc};
-    use serde::Deserialize;
+    use super::scenario;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::fmt;
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::{Component, Path};
+    use std::str::FromStr;
+    use std::time::Duration;
+    use std::{io, sync::{Arc, Mutex}};
     use serde_json;
-    use serde_yaml;
-    use tokio::sync::watch;
+    use tokio::sync::{watch, Notify};
     use crate::sync::shutdown::Shutdown;
-    use crate::json::{self, WsMessage};
-    use tokio::{self, net::{TcpListener, TcpStream}, sync::mpsc::{self, UnboundedSender}};
+    use crate::json::{self, ContextObject, EventObj, EventType, Id, WsMessage};
+    use chrono::Utc;
+    use tokio::{self, net::{TcpListener, TcpSocket, TcpStream}, sync::{mpsc::{self, UnboundedSender}, oneshot}};
+    use tokio::{runtime::Handle, task::JoinHandle};
     use tokio_tungstenite::tungstenite::{Result, Message};
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response, ErrorResponse};
+    use tokio_tungstenite::tungstenite::http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode};
+    use tokio_tungstenite::tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
     use futures_util::{StreamExt, SinkExt};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
     use tracing;
 
     /// Configuration data required to set up an instance of [Hast].
-    #[derive(Debug)]
+    #[derive(Clone, Debug)]
     pub struct HastConfig {
         /// The port on which the HA WebSocket mock service will bind listening for connections.
         pub port: u16,
 
+        /// The IP address on which the HA WebSocket mock service will bind, e.g. `0.0.0.0`
+        /// to be reachable from other hosts. Defaults to `127.0.0.1`.
+        pub bind_addr: String,
+
         /// The authentication token required by the HA WebSocket mock service.
         pub token: String,
 
@@ -65,12 +81,78 @@ pub mod server {
         pub yaml_dir: String,
 
         /// Optionally, the default YAML event log file used for all incoming connections.
+        ///
+        /// Clients may pick a different one by connecting to
+        /// `/api/websocket/scenarios/<file>`, `<file>` being relative to [HastConfig::yaml_dir].
         pub yaml_scenario: Option<String>,
 
-        // When true, disable the initial configuration phase via [HastMessage] messages for
+        /// YAML event log files in [HastConfig::yaml_dir] played right after
+        /// [HastConfig::yaml_scenario], in order, as parts of the same scenario.
+        /// They are left out when clients pick a different scenario.
+        pub extra_scenarios: Vec<String>,
+
+        /// When true, disable the initial configuration phase via [HastMessage] messages for
         /// new connections.
+        ///
+        /// When false, the configuration phase is still optional for clients: it ends either
+        /// with [HastMessage::Start], or implicitly with the first [WsMessage] received.
         pub skip_hast_messages: bool,
 
+        /// When true, subscribers also receive the `homeassistant_start` and
+        /// `homeassistant_started` lifecycle events before the scenario, as long
+        /// as they match the event type of the subscription.
+        pub emit_lifecycle: bool,
+
+        /// Canned replies, mapping the type of a message (e.g. `validate_config`) to the
+        /// YAML file in [HastConfig::yaml_dir] holding the messages sent back when it is
+        /// received, after setting their id to the one of the request.
+        ///
+        /// They take precedence over the built-in handling of messages.
+        pub responses: BTreeMap<String, String>,
+
+        /// Commands each client must send within its session, checked when its
+        /// connection gets closed, see [HastStats::unmet_expectations()].
+        pub expectations: Vec<Expectation>,
+
+        /// When set, events of the scenario are randomly dropped or reordered
+        /// before being sent to subscribers.
+        pub chaos: Option<ChaosConfig>,
+
+        /// Maximum number of events read from a scenario, the following ones being
+        /// left out with a warning. `None` reads them all.
+        pub max_scenario_events: Option<usize>,
+
+        /// When true, events of the scenario are sent with the same delays found
+        /// between their `time_fired`, rather than all at once. Events with no such
+        /// timestamp are sent right away.
+        pub realtime: bool,
+
+        /// When true, the scenario is played again and again to each subscriber, until
+        /// its connection is closed or shutdown is requested.
+        pub loop_scenario: bool,
+
+        /// Delay between two plays of the scenario with [HastConfig::loop_scenario].
+        pub loop_delay: Duration,
+
+        /// When set, once the scenario is over, subscribers to `time_changed` events,
+        /// or to all events, receive a synthetic `time_changed` event at this interval
+        /// until their connection is closed or shutdown is requested.
+        pub heartbeat: Option<Duration>,
+
+        /// When true, subscribers to `time_changed` events, or to all events, receive
+        /// one such event right before the result acknowledging their subscription,
+        /// as HA may do under races.
+        pub event_before_ack: bool,
+
+        /// WebSocket subprotocols accepted in the handshake, in order of preference.
+        /// The first one also offered by the client is echoed back in the response,
+        /// none if there is no match, e.g. when empty, the default.
+        pub subprotocols: Vec<String>,
+
+        /// Grace period given to open connections on shutdown to flush the messages
+        /// still queued for their clients, before closing them.
+        pub drain_timeout: Duration,
+
         /// The HA version declared by the HA WebSocket mock.
         ha_version: String,
     }
@@ -88,30 +170,324 @@ pub mod server {
             let skip_hast_messages = yaml_scenario.is_some();
             HastConfig {
                 port,
+                bind_addr: "127.0.0.1".to_string(),
                 token,
                 yaml_dir,
                 yaml_scenario,
+                extra_scenarios: Vec::new(),
                 ha_version: format!("{}-{}", env!("CARGO_PKG_VERSION"), env!("CARGO_PKG_NAME")),
                 skip_hast_messages,
+                emit_lifecycle: false,
+                responses: BTreeMap::new(),
+                expectations: Vec::new(),
+                chaos: None,
+                max_scenario_events: None,
+                realtime: false,
+                loop_scenario: false,
+                loop_delay: Duration::from_secs(1),
+                drain_timeout: Duration::from_secs(1),
+                heartbeat: None,
+                event_before_ack: false,
+                subprotocols: Vec::new(),
+            }
+        }
+    }
+
+    /// Randomly alters the scenario sent to subscribers, to check how clients
+    /// cope with missing or out-of-order events.
+    ///
+    /// Given the same `seed`, the same scenario is always altered the same way.
+    #[derive(Clone, PartialEq, Debug)]
+    pub struct ChaosConfig {
+        /// Probability of each event to be dropped
+        pub drop_prob: f64,
+
+        /// Probability of each event to be swapped with the one before it
+        pub reorder_prob: f64,
+
+        /// Seed of the random number generator
+        pub seed: u64,
+    }
+
+    impl ChaosConfig {
+        /// Returns `events` once randomly altered.
+        ///
+        /// Probabilities are clamped to `[0, 1]`.
+        pub fn apply<T>(&self, events: Vec<T>) -> Vec<T> {
+            let mut stream = self.stream();
+            let mut altered: Vec<T> = events.into_iter()
+                .filter_map(|event| stream.push(event))
+                .collect();
+            altered.extend(stream.finish());
+            altered
+        }
+
+        /// Returns a [ChaosStream] altering events one at a time, exactly as
+        /// [ChaosConfig::apply()] would.
+        pub fn stream<T>(&self) -> ChaosStream<T> {
+            ChaosStream {
+                rng: StdRng::seed_from_u64(self.seed),
+                drop_prob: self.drop_prob.clamp(0.0, 1.0),
+                reorder_prob: self.reorder_prob.clamp(0.0, 1.0),
+                held: None,
+            }
+        }
+    }
+
+    /// Alters events one at a time as they are played, see [ChaosConfig::stream()].
+    #[derive(Debug)]
+    pub struct ChaosStream<T> {
+        rng: StdRng,
+        drop_prob: f64,
+        reorder_prob: f64,
+        /// Last event pushed, which may still be swapped with the next one
+        held: Option<T>,
+    }
+
+    impl<T> ChaosStream<T> {
+        /// Pushes the next `event`, returning the one to be sent next, if any.
+        pub fn push(&mut self, event: T) -> Option<T> {
+            if self.rng.gen_bool(self.drop_prob) {
+                return None;
+            }
+            let Some(held) = self.held.take() else {
+                self.held = Some(event);
+                return None;
+            };
+            if self.rng.gen_bool(self.reorder_prob) {
+                self.held = Some(held);
+                Some(event)
+            } else {
+                self.held = Some(event);
+                Some(held)
+            }
+        }
+
+        /// Returns the last event left to be sent, if any, once all were pushed.
+        pub fn finish(self) -> Option<T> {
+            self.held
+        }
+    }
+
+    /// A command that clients are expected to send to [Hast].
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub enum Expectation {
+        /// Any message of the given type, e.g. `get_states`
+        Type(String),
+
+        /// A `call_service` message for the given domain and service
+        CallService { domain: String, service: String },
+    }
+
+    impl Expectation {
+        /// Returns whether `msg` fulfills the expectation.
+        pub fn is_met_by(&self, msg: &WsMessage) -> bool {
+            match (self, msg) {
+                (Expectation::CallService { domain, service }, WsMessage::CallService { domain: d, service: s, .. }) => {
+                    domain == d && service == s
+                },
+                (Expectation::CallService { .. }, _) => false,
+                (Expectation::Type(msg_type), msg) => message_type(msg).as_deref() == Some(msg_type.as_str()),
+            }
+        }
+    }
+
+    impl FromStr for Expectation {
+        type Err = String;
+
+        /// Parses either a service, as in `light.turn_on`, or a message type,
+        /// as in `get_states`.
+        fn from_str(s: &str) -> std::result::Result<Expectation, String> {
+            if s.is_empty() {
+                return Err("empty expectation".to_string());
+            }
+            Ok(match s.split_once('.') {
+                Some((domain, service)) if !domain.is_empty() && !service.is_empty() => Expectation::CallService {
+                    domain: domain.to_string(),
+                    service: service.to_string(),
+                },
+                Some(_) => return Err(format!("invalid service: {}", s)),
+                None => Expectation::Type(s.to_string()),
+            })
+        }
+    }
+
+    impl fmt::Display for Expectation {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Expectation::Type(msg_type) => write!(f, "{}", msg_type),
+                Expectation::CallService { domain, service } => write!(f, "{}.{}", domain, service),
+            }
+        }
+    }
+
+    /// Returns the `type` field of `msg` once serialized, e.g. `call_service`.
+    fn message_type(msg: &WsMessage) -> Option<String> {
+        serde_json::to_value(msg).ok()?
+            .get("type")?
+            .as_str()
+            .map(str::to_owned)
+    }
+
+    /// Statistics collected by a [Hast] instance across all of its connections.
+    ///
+    /// They are shared via [Hast::stats()], and remain available after the server
+    /// has been shut down, so that tests may perform assertions on what hast actually
+    /// sent to its clients.
+    #[derive(Debug, Default)]
+    pub struct HastStats {
+        /// Number of events streamed, per connection and subscription id
+        events: Mutex<BTreeMap<(SocketAddr, Id), usize>>,
+
+        /// Messages received after the configuration phase, in order of arrival
+        received: Mutex<Vec<(SocketAddr, WsMessage)>>,
+
+        /// [HastConfig::expectations] not met by each closed connection
+        unmet: Mutex<BTreeMap<SocketAddr, Vec<Expectation>>>,
+
+        /// Notified whenever a connection gets closed
+        closed: Notify,
+
+        /// Number of scenario files, or documents within them, that could not be played
+        scenario_errors: Mutex<usize>,
+
+        /// Subscriptions neither cancelled nor closed along with their connection
+        subscriptions: Mutex<BTreeSet<(SocketAddr, Id)>>,
+    }
+
+    impl HastStats {
+        /// Returns the number of events streamed to the subscription `id` of the
+        /// connection from `addr`.
+        pub fn events(&self, addr: &SocketAddr, id: Id) -> usize {
+            self.events.lock().unwrap()
+                .get(&(*addr, id))
+                .copied()
+                .unwrap_or(0)
+        }
+
+        /// Returns the number of events streamed to subscriptions with the given
+        /// `id`, summed across all connections.
+        pub fn events_for_subscription(&self, id: Id) -> usize {
+            self.events.lock().unwrap()
+                .iter()
+                .filter(|((_, sub_id), _)| *sub_id == id)
+                .map(|(_, count)| count)
+                .sum()
+        }
+
+        /// Returns the number of events streamed to each subscription of the
+        /// connection from `addr`.
+        pub fn connection_events(&self, addr: &SocketAddr) -> BTreeMap<Id, usize> {
+            self.events.lock().unwrap()
+                .iter()
+                .filter(|((conn, _), _)| conn == addr)
+                .map(|((_, id), count)| (*id, *count))
+                .collect()
+        }
+
+        /// Returns all the messages received from clients after their configuration
+        /// phase, across all connections, in order of arrival.
+        pub fn received(&self) -> Vec<WsMessage> {
+            self.received.lock().unwrap()
+                .iter()
+                .map(|(_, msg)| msg.clone())
+                .collect()
+        }
+
+        /// Returns the [HastConfig::expectations] that were not met by the
+        /// connections closed so far, along with the address of each client.
+        pub fn unmet_expectations(&self) -> Vec<(SocketAddr, Expectation)> {
+            self.unmet.lock().unwrap()
+                .iter()
+                .flat_map(|(addr, unmet)| unmet.iter().map(|e| (*addr, e.clone())))
+                .collect()
+        }
+
+        /// Returns the number of scenario files that could not be read, plus
+        /// the number of their documents that could not be parsed, so far.
+        pub fn scenario_errors(&self) -> usize {
+            *self.scenario_errors.lock().unwrap()
+        }
+
+        fn add_scenario_error(&self) {
+            *self.scenario_errors.lock().unwrap() += 1;
+        }
+
+        /// Returns the number of subscriptions currently active, across all
+        /// connections.
+        pub fn subscriptions(&self) -> usize {
+            self.subscriptions.lock().unwrap().len()
+        }
+
+        fn add_subscription(&self, addr: &SocketAddr, id: Id) {
+            self.subscriptions.lock().unwrap().insert((*addr, id));
+        }
+
+        fn remove_subscription(&self, addr: &SocketAddr, id: Id) {
+            self.subscriptions.lock().unwrap().remove(&(*addr, id));
+        }
+
+        /// Waits for a connection to get closed.
+        ///
+        /// A connection closed while nobody is waiting makes the next call
+        /// return right away.
+        pub async fn connection_closed(&self) {
+            self.closed.notified().await;
+        }
+
+        /// Checks the `expectations` against the messages received from `addr`,
+        /// and notifies that its connection got closed.
+        fn close_connection(&self, addr: &SocketAddr, expectations: &[Expectation]) -> Vec<Expectation> {
+            let unmet: Vec<Expectation> = {
+                let received = self.received.lock().unwrap();
+                expectations.iter()
+                    .filter(|e| !received.iter().any(|(from, msg)| from == addr && e.is_met_by(msg)))
+                    .cloned()
+                    .collect()
+            };
+            if !unmet.is_empty() {
+                self.unmet.lock().unwrap().insert(*addr, unmet.clone());
             }
+            self.subscriptions.lock().unwrap().retain(|(conn, _)| conn != addr);
+            self.closed.notify_one();
+            unmet
+        }
+
+        fn add_received(&self, addr: &SocketAddr, msg: &WsMessage) {
+            self.received.lock().unwrap().push((*addr, msg.clone()));
+        }
+
+        fn add_event(&self, addr: &SocketAddr, id: Id) {
+            *self.events.lock().unwrap()
+                .entry((*addr, id))
+                .or_insert(0) += 1;
         }
     }
 
     #[derive(Debug)]
     struct HastConnConfig {
         pub token: String,
-        pub yaml_scenario: Option<String>,
+        /// Files making up the scenario, played one after the other
+        pub yaml_scenarios: Vec<String>,
         pub name: Option<String>,
-        common_cfg: Arc<Box<HastConfig>>,
+        common_cfg: Arc<HastConfig>,
+        stats: Arc<HastStats>,
+        /// Active subscriptions, cancelled by dropping their sender
+        subscriptions: Mutex<BTreeMap<Id, oneshot::Sender<()>>>,
     }
 
     impl HastConnConfig {
-        fn new(hc: Arc<Box<HastConfig>>) -> HastConnConfig {
+        fn new(hc: Arc<HastConfig>, stats: Arc<HastStats>) -> HastConnConfig {
             HastConnConfig {
                 token: hc.token.clone(),
                 common_cfg: hc.clone(),
-                yaml_scenario: hc.yaml_scenario.clone(),
+                yaml_scenarios: hc.yaml_scenario.iter()
+                    .chain(hc.extra_scenarios.iter())
+                    .cloned()
+                    .collect(),
                 name: None,
+                stats,
+                subscriptions: Mutex::new(BTreeMap::new()),
             }
         }
 
@@ -124,7 +500,8 @@ pub mod server {
         }
 
         fn test_name(&self) -> String {
-            if let Some(scenario) = self.yaml_scenario.as_ref() {
+            if !self.yaml_scenarios.is_empty() {
+                let scenario = self.yaml_scenarios.join("+");
                 return if let Some(name) = self.name.as_ref() {
                     format!("{}[{}]", scenario, name)
                 } else {
@@ -137,6 +514,57 @@ pub mod server {
         fn skip_hast_messages(&self) -> bool {
             self.common_cfg.skip_hast_messages
         }
+
+        fn emit_lifecycle(&self) -> bool {
+            self.common_cfg.emit_lifecycle
+        }
+
+        fn max_scenario_events(&self) -> usize {
+            self.common_cfg.max_scenario_events.unwrap_or(usize::MAX)
+        }
+
+        fn realtime(&self) -> bool {
+            self.common_cfg.realtime
+        }
+
+        fn loop_scenario(&self) -> bool {
+            self.common_cfg.loop_scenario
+        }
+
+        fn loop_delay(&self) -> Duration {
+            self.common_cfg.loop_delay
+        }
+
+        fn drain_timeout(&self) -> Duration {
+            self.common_cfg.drain_timeout
+        }
+
+        fn heartbeat(&self) -> Option<Duration> {
+            self.common_cfg.heartbeat
+        }
+
+        fn event_before_ack(&self) -> bool {
+            self.common_cfg.event_before_ack
+        }
+
+        fn subprotocols(&self) -> &[String] {
+            &self.common_cfg.subprotocols
+        }
+
+        fn chaos(&self) -> Option<&ChaosConfig> {
+            self.common_cfg.chaos.as_ref()
+        }
+
+        fn expectations(&self) -> &[Expectation] {
+            &self.common_cfg.expectations
+        }
+
+        /// Returns the path of the file with the canned replies to `msg`, if any.
+        fn response_file(&self, msg: &WsMessage) -> Option<String> {
+            let msg_type = message_type(msg)?;
+            self.common_cfg.responses.get(&msg_type)
+                .map(|file| format!("{}/{}", self.yaml_dir(), file))
+        }
     }
 
     /// # Home Assistant Surrogate Tool
@@ -144,23 +572,60 @@ pub mod server {
     /// Listens on a WebSocket for incoming connections, accepts them, and
     /// spawns tokio tasks handling each with basic Home Assistant WebSocket
     /// functionality such as authentication and event subscription.
+    ///
+    /// The server is either run to completion with [Hast::run()], or started in
+    /// the background with [Hast::start()], in which case it may be restarted with
+    /// a different configuration via [Hast::restart()], e.g. to play a sequence
+    /// of scenarios within the same test.
     pub struct Hast {
-        cfg: Arc<Box<HastConfig>>,
+        cfg: Arc<HastConfig>,
         shutdown: Shutdown,
         startup: Option<watch::Sender<()>>,
+        stats: Arc<HastStats>,
+        running: Option<Running>,
+    }
+
+    /// Accept loop of a [Hast] started in the background.
+    struct Running {
+        stop: oneshot::Sender<()>,
+        task: JoinHandle<()>,
+        runtime: Handle,
+        addr: SocketAddr,
     }
 
     impl Hast {
         /// Creates a new [Hast] by providing a configuration and a [Shutdown] object.
         /// The latter is required to coordinate graceful shutdown.
-        pub fn new(cfg: HastConfig, shutdown: Shutdown) -> Hast {
+        ///
+        /// The configuration may be an `Arc<HastConfig>` shared with other instances.
+        pub fn new(cfg: impl Into<Arc<HastConfig>>, shutdown: Shutdown) -> Hast {
             Hast {
-                cfg: Arc::new(Box::new(cfg)),
+                cfg: cfg.into(),
                 startup: Some(watch::channel(()).0),
                 shutdown,
+                stats: Arc::new(HastStats::default()),
+                running: None,
             }
         }
 
+        /// Returns the configuration of this instance.
+        pub fn config(&self) -> &Arc<HastConfig> {
+            &self.cfg
+        }
+
+        /// Returns the configuration of this instance for changing it, which is
+        /// cloned first if shared. Changes only apply to the connections accepted
+        /// after the next [Hast::start()] or [Hast::restart()].
+        pub fn config_mut(&mut self) -> &mut HastConfig {
+            Arc::make_mut(&mut self.cfg)
+        }
+
+        /// Returns the statistics collected by this instance, which keep being
+        /// updated while [Hast::run()] is going on.
+        pub fn stats(&self) -> Arc<HastStats> {
+            self.stats.clone()
+        }
+
         /// Returns a watch channel that may be used to wait for the completion of [Hass]'s startup.
         /// 
         /// The corresponding sender will be dropped as soon as all services are up and running listening
@@ -168,43 +633,124 @@ pub mod server {
         /// Please note that, since startup and its waiting may happen out of order, the wait may either
         /// return witn an `Ok(())` as well as with an `Err(_)`. Either way, the result should be discarded.
         pub fn startup_notifier(&self) -> watch::Receiver<()> {
-            self.startup
-                .as_ref()
-                .map(|s| s.subscribe())
-                .unwrap() // we're sure it's safe, as run() who takes `self.startup` consumes `self`
+            match self.startup.as_ref() {
+                Some(startup) => startup.subscribe(),
+                // Already started: the sender is gone, and so will be the one of the receiver
+                None => watch::channel(()).1,
+            }
         }
 
         /// Consumes the [Hast] instance and starts the server
         pub async fn run(mut self) -> Result<(), io::Error> {
-            let addr = format!("127.0.0.1:{}", self.cfg.port);
-
-            let listener = TcpListener::bind(&addr).await?;
-            tracing::info!("hast: listening on {}", addr);
+            let listener = self.bind()?;
 
             if let Some(startup) = self.startup.take() {
                 drop(startup); // send startup signal
             }
 
-            loop {
-                tokio::select! {
-                    Ok((stream, _)) = listener.accept() => {
-                        let conn_cfg = HastConnConfig::new(self.cfg.clone());
-                        let shutdown_cl = self.shutdown.clone();
-                        tokio::spawn(accept_connection(stream, conn_cfg, shutdown_cl));
-                    },
+            serve(listener, self.cfg.clone(), self.stats.clone(), self.shutdown.clone(), std::future::pending()).await;
+            Ok(())
+        }
 
-                    _ = self.shutdown.recv() => {
-                        tracing::info!("hast: received shutdown request");
-                        break;
-                    },
+        /// Starts the server in the background on the current tokio runtime,
+        /// returning as soon as it listens for connections.
+        ///
+        /// The server keeps running until either shutdown is requested, [Hast::stop()]
+        /// is called, or the instance is dropped.
+        pub fn start(&mut self) -> Result<(), io::Error> {
+            self.start_on(&Handle::current())
+        }
 
-                    else => break,
-                }
+        /// Same as [Hast::start()], on the tokio runtime of `runtime`.
+        pub fn start_on(&mut self, runtime: &Handle) -> Result<(), io::Error> {
+            if self.running.is_some() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "hast: already started"));
+            }
+            let listener = {
+                let _guard = runtime.enter();
+                self.bind()?
+            };
+            let addr = listener.local_addr()?;
+            let (stop, stop_rx) = oneshot::channel();
+            let task = runtime.spawn(serve(listener, self.cfg.clone(), self.stats.clone(), self.shutdown.clone(), async move {
+                let _ = stop_rx.await;
+            }));
+            self.running = Some(Running { stop, task, runtime: runtime.clone(), addr });
+            if let Some(startup) = self.startup.take() {
+                drop(startup); // send startup signal
             }
-
-            tracing::info!("hast: shutdown");
             Ok(())
         }
+
+        /// Returns the address listened on while started with [Hast::start()],
+        /// e.g. to find out the port picked when configured with port 0.
+        pub fn local_addr(&self) -> Option<SocketAddr> {
+            self.running.as_ref().map(|running| running.addr)
+        }
+
+        /// Stops listening for new connections, if started with [Hast::start()].
+        ///
+        /// Connections already accepted are left open, and keep running with
+        /// the configuration they were accepted with.
+        pub async fn stop(&mut self) {
+            if let Some(running) = self.running.take() {
+                let _ = running.stop.send(());
+                let _ = running.task.await;
+            }
+        }
+
+        /// Stops the server and starts it again, on the same runtime, with the
+        /// current configuration, e.g. after changing [HastConfig::yaml_scenario]
+        /// via [Hast::config_mut()]. When not started yet, it is just started.
+        pub async fn restart(&mut self) -> Result<(), io::Error> {
+            let runtime = self.running.as_ref().map_or_else(Handle::current, |running| running.runtime.clone());
+            self.stop().await;
+            self.start_on(&runtime)
+        }
+
+        /// Binds a listener to the address and port configured, which must be
+        /// called from within a tokio runtime.
+        fn bind(&self) -> Result<TcpListener, io::Error> {
+            let ip: IpAddr = self.cfg.bind_addr.parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid bind address {:?}: {}", self.cfg.bind_addr, e)))?;
+            let addr = SocketAddr::new(ip, self.cfg.port);
+
+            let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            socket.set_reuseaddr(true)?;
+            socket.bind(addr)?;
+            let listener = socket.listen(1024)?;
+            tracing::info!("hast: listening on {}", listener.local_addr().unwrap_or(addr));
+            Ok(listener)
+        }
+    }
+
+    /// Accepts connections on `listener`, each one handled with `cfg`, until
+    /// either shutdown is requested or `stop` completes.
+    async fn serve(listener: TcpListener, cfg: Arc<HastConfig>, stats: Arc<HastStats>, mut shutdown: Shutdown, stop: impl std::future::Future<Output = ()>) {
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                Ok((stream, _)) = listener.accept() => {
+                    let conn_cfg = HastConnConfig::new(cfg.clone(), stats.clone());
+                    let shutdown_cl = shutdown.clone();
+                    tokio::spawn(accept_connection(stream, conn_cfg, shutdown_cl));
+                },
+
+                _ = shutdown.recv() => {
+                    tracing::info!("hast: received shutdown request");
+                    break;
+                },
+
+                _ = &mut stop => {
+                    tracing::info!("hast: stopped");
+                    break;
+                },
+
+                else => break,
+            }
+        }
+
+        tracing::info!("hast: shutdown");
     }
 
 
@@ -215,38 +761,92 @@ pub mod server {
         let addr = stream.peer_addr().expect("connected streams should have a peer address");
         tracing::info!("{}: connected, configuration: {:?}", addr, cfg);
 
-        let ws_stream = tokio_tungstenite::accept_async(stream)
-            .await
-            .expect("Error during the websocket handshake occurred");
+        let mut path_scenario = None;
+        let subprotocols = cfg.subprotocols();
+        let handshake = |req: &Request, mut resp: Response| {
+            match scenario_from_path(req.uri().path()) {
+                Ok(scenario) => {
+                    path_scenario = scenario;
+                    if let Some(protocol) = select_subprotocol(req, subprotocols) {
+                        resp.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+                    }
+                    Ok(resp)
+                },
+                Err(e) => {
+                    let mut err = ErrorResponse::new(Some(e));
+                    *err.status_mut() = StatusCode::BAD_REQUEST;
+                    Err(err)
+                },
+            }
+        };
+        let ws_stream = match tokio_tungstenite::accept_hdr_async(stream, handshake).await {
+            Ok(ws_stream) => ws_stream,
+            Err(e) => {
+                tracing::warn!("{}: WebSocket handshake failed: {}", addr, e);
+                return Ok(());
+            },
+        };
         tracing::info!("{}: new WebSocket connection", addr);
+        if let Some(scenario) = path_scenario {
+            tracing::info!("{}: scenario selected by request path: {}", addr, scenario);
+            cfg.yaml_scenarios = vec![scenario];
+        }
 
         let (mut sk_write, mut sk_read) = ws_stream.split();
 
+        // Just like HA, greet the client right away: regular clients wait for
+        // `auth_required` before sending anything, config phase or not.
+        let auth_required = WsMessage::AuthRequired { ha_version: cfg.ha_version().to_string() };
+        sk_write.send(Message::Text(json::serialize(&auth_required).unwrap())).await?;
+
         // Hast configuration loop
+        //
+        // Clients unaware of hast never send `HastMessage::Start`: their first
+        // `WsMessage` implicitly starts the session, and is then handled as usual.
+        let mut first_wsmsg = None;
         while ! cfg.skip_hast_messages() {
             tokio::select! {
                 Some(msg) = sk_read.next() => {
-                    match serde_json::from_str(msg?.to_text()?).unwrap() {
-                        HastMessage::Name(n) => {
+                    let msg = msg?;
+                    if !msg.is_text() {
+                        continue;
+                    }
+                    match serde_json::from_str(msg.to_text()?) {
+                        Ok(HastMessage::Name(n)) => {
                             cfg.name = Some(n);
                         }
-                        HastMessage::Token(t) => {
+                        Ok(HastMessage::Token(t)) => {
                             cfg.token = t;
                         },
-                        HastMessage::Scenario(p) => {
-                            cfg.yaml_scenario = Some(p);
+                        Ok(HastMessage::Scenario(p)) => {
+                            cfg.yaml_scenarios = vec![p];
+                        },
+                        Ok(HastMessage::Start) => break,
+                        Err(e) => match json::deserialize(msg.to_text()?) {
+                            Ok(wsmsg) => {
+                                tracing::info!("{}: implicit start on first message: {:?}", addr, wsmsg);
+                                first_wsmsg = Some(wsmsg);
+                                break;
+                            },
+                            Err(_) => {
+                                tracing::warn!("{}: ignoring unrecognized configuration message: {}", addr, e);
+                                let reply = invalid_hast_message(&e);
+                                sk_write.send(Message::Text(json::serialize(&reply).unwrap())).await?;
+                            },
                         },
-                        HastMessage::Start => break,
                     }
                 },
                 else => break,
             }
         }
         let (tx, mut rx) = mpsc::unbounded_channel();
-        tx.send(WsMessage::AuthRequired { ha_version: cfg.ha_version().to_string() }).unwrap();
-        
+
         let test_name = cfg.test_name();
         let cfg = Arc::new(cfg);
+        if let Some(wsmsg) = first_wsmsg {
+            spawn_handle_message(wsmsg, &tx, &cfg, addr, &shutdown);
+        }
+        let mut draining = false;
         loop {
             tokio::select! {
                 msg = rx.recv() => {
@@ -268,17 +868,13 @@ pub mod server {
                     }
                     if let Ok(wsmsg) = json::deserialize(msg.to_text()?) {
                         tracing::info!("{}: {}: RECEIVED:\n{:?}", addr, test_name, wsmsg);
-                        let tx_cl = tx.clone();
-                        let cfg_cl = cfg.clone();
-                        let shutdown_cl = shutdown.clone();
-                        tokio::spawn(async move {
-                            handle_message(wsmsg, tx_cl, cfg_cl, &addr, shutdown_cl).await.unwrap();
-                        });
+                        spawn_handle_message(wsmsg, &tx, &cfg, addr, &shutdown);
                     }
                 },
 
                 _ = shutdown.recv() => {
                     tracing::info!("{}: {}: received shutdown request", addr, test_name);
+                    draining = true;
                     break;
                 }
 
@@ -288,12 +884,310 @@ pub mod server {
 
         drop(tx);
 
+        // The client may well have closed the connection on its own meanwhile,
+        // which must not prevent its expectations from being checked
+        if draining {
+            if let Err(e) = drain_connection(&mut rx, &mut sk_write, cfg.drain_timeout(), &addr, &test_name).await {
+                tracing::warn!("{}: {}: could not drain the connection: {}", addr, test_name, e);
+            }
+        }
+
+        tracing::info!("{}: {}: events sent per subscription: {:?}", addr, test_name, cfg.stats.connection_events(&addr));
+        let unmet = cfg.stats.close_connection(&addr, cfg.expectations());
+        for expectation in cfg.expectations() {
+            if unmet.contains(expectation) {
+                tracing::error!("{}: {}: expectation not met: {}", addr, test_name, expectation);
+            } else {
+                tracing::info!("{}: {}: expectation met: {}", addr, test_name, expectation);
+            }
+        }
         tracing::info!("{}: {}: shutdown", addr, test_name);
         Ok(())
     }
 
-    async fn handle_message(wsmsg: WsMessage, tx: UnboundedSender<WsMessage>, cfg: Arc<HastConnConfig>, addr: &SocketAddr, _shutdown: Shutdown) -> Result<()> {
-        use crate::json::{WsMessage::*, ResultBody, ErrorObject};
+    /// Returns the scenario selected by the request `path` of the WebSocket handshake,
+    /// if any, i.e. `<file>` in `/api/websocket/scenarios/<file>`.
+    ///
+    /// Fails when `<file>` would point outside of [HastConfig::yaml_dir].
+    fn scenario_from_path(path: &str) -> std::result::Result<Option<String>, String> {
+        const PREFIX: &str = "/api/websocket/scenarios/";
+        let Some(scenario) = path.strip_prefix(PREFIX) else {
+            return Ok(None);
+        };
+        let valid = !scenario.is_empty()
+            && !scenario.contains('\\')
+            && Path::new(scenario).components().all(|c| matches!(c, Component::Normal(_)));
+        if valid {
+            Ok(Some(scenario.to_owned()))
+        } else {
+            Err(format!("invalid scenario path: {}", scenario))
+        }
+    }
+
+    /// Returns the first of the `accepted` subprotocols offered by the client
+    /// with its handshake request `req`, if any.
+    fn select_subprotocol(req: &Request, accepted: &[String]) -> Option<HeaderValue> {
+        let offered: Vec<&str> = req.headers().get_all(SEC_WEBSOCKET_PROTOCOL).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        accepted.iter()
+            .find(|protocol| offered.contains(&protocol.as_str()))
+            .and_then(|protocol| HeaderValue::from_str(protocol).ok())
+    }
+
+    /// Sends to the client the messages still queued in `rx`, until either all
+    /// handlers are done or `timeout` expires, then closes the connection with
+    /// a close frame.
+    async fn drain_connection<S>(rx: &mut mpsc::UnboundedReceiver<WsMessage>, sk_write: &mut S, timeout: Duration, addr: &SocketAddr, test_name: &str) -> Result<()>
+    where
+        S: SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+    {
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut flushed = 0;
+        loop {
+            tokio::select! {
+                msg = rx.recv() => {
+                    let Some(msg) = msg else { break };
+                    let msg = json::serialize(&msg).unwrap();
+                    sk_write.send(Message::Text(msg)).await?;
+                    flushed += 1;
+                },
+                _ = &mut deadline => {
+                    tracing::warn!("{}: {}: drain timed out, dropping pending messages", addr, test_name);
+                    break;
+                },
+            }
+        }
+        tracing::info!("{}: {}: drained {} messages, closing connection", addr, test_name, flushed);
+        let frame = CloseFrame { code: CloseCode::Away, reason: "hast shutting down".into() };
+        sk_write.send(Message::Close(Some(frame))).await
+    }
+
+    fn spawn_handle_message(wsmsg: WsMessage, tx: &UnboundedSender<WsMessage>, cfg: &Arc<HastConnConfig>, addr: SocketAddr, shutdown: &Shutdown) {
+        let tx_cl = tx.clone();
+        let cfg_cl = cfg.clone();
+        let shutdown_cl = shutdown.clone();
+        tokio::spawn(async move {
+            handle_message(wsmsg, tx_cl, cfg_cl, &addr, shutdown_cl).await.unwrap();
+        });
+    }
+
+    /// Builds the error sent back to clients for messages of the configuration
+    /// phase which are neither a [HastMessage] nor a [WsMessage].
+    fn invalid_hast_message(e: &serde_json::Error) -> WsMessage {
+        WsMessage::Result {
+            id: 0,
+            success: false,
+            data: json::ResultBody::Error {
+                error: json::ErrorObject {
+                    code: "invalid_hast_message".to_string(),
+                    message: e.to_string(),
+                },
+            },
+        }
+    }
+
+    /// Builds a context with a new, unique enough id.
+    fn new_context() -> ContextObject {
+        ContextObject {
+            id: format!("{:032x}", Utc::now().timestamp_nanos_opt().unwrap_or_default()),
+            ..Default::default()
+        }
+    }
+
+    /// Builds a lifecycle event of the given `event_type` for the subscription `id`.
+    fn lifecycle_event(id: Id, event_type: EventType) -> WsMessage {
+        WsMessage::Event {
+            id,
+            event: EventObj::Event {
+                data: serde_json::Value::Object(Default::default()),
+                event_type,
+                time_fired: Utc::now(),
+                origin: "LOCAL".to_string(),
+                context: new_context(),
+            },
+        }
+    }
+
+    /// Creates a `time_changed` event for the subscription `id`, fired now.
+    fn time_changed_event(id: Id) -> WsMessage {
+        let mut event = lifecycle_event(id, EventType::TimeChanged);
+        if let WsMessage::Event { event: EventObj::Event { data, time_fired, .. }, .. } = &mut event {
+            *data = serde_json::json!({ "now": time_fired });
+        }
+        event
+    }
+
+    /// Sends a `time_changed` event to the subscription `id` every `interval`, until
+    /// either shutdown is requested or the connection gets closed.
+    async fn send_heartbeats(id: Id, interval: Duration, tx: &UnboundedSender<WsMessage>, cfg: &HastConnConfig, addr: &SocketAddr, shutdown: &mut Shutdown) {
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown.recv() => break,
+                _ = tokio::time::sleep(interval) => (),
+            }
+            if tx.send(time_changed_event(id)).is_err() {
+                break;
+            }
+            cfg.stats.add_event(addr, id);
+        }
+    }
+
+    /// Returns the time an event was fired at, if `msg` is one.
+    fn time_fired(msg: &WsMessage) -> Option<chrono::DateTime<Utc>> {
+        match msg {
+            WsMessage::Event { event: EventObj::Event { time_fired, .. }, .. } => Some(*time_fired),
+            _ => None,
+        }
+    }
+
+    /// What [read_scenario()] got out of a scenario file.
+    enum ScenarioRead {
+        /// The file could not be opened
+        Unopened(io::Error),
+        /// The next message of the file, or the error that stopped its reading
+        Message(io::Result<Result<WsMessage, scenario::Issue>>),
+    }
+
+    /// Reads the messages of the scenario in `files` on a blocking thread, so that
+    /// connections are not stalled meanwhile, e.g. on a single-threaded runtime.
+    ///
+    /// Messages are read a few at a time as they get received, tagged with the
+    /// index of their file, and reading stops as soon as the receiver is dropped.
+    fn read_scenario(files: Vec<String>) -> mpsc::Receiver<(usize, ScenarioRead)> {
+        let (tx, rx) = mpsc::channel(16);
+        tokio::task::spawn_blocking(move || {
+            for (idx, file) in files.iter().enumerate() {
+                let messages = match scenario::open(file) {
+                    Ok(reader) => scenario::messages(reader),
+                    Err(e) => {
+                        if tx.blocking_send((idx, ScenarioRead::Unopened(e))).is_err() {
+                            return;
+                        }
+                        continue;
+                    },
+                };
+                for message in messages {
+                    let failed = message.is_err();
+                    if tx.blocking_send((idx, ScenarioRead::Message(message))).is_err() {
+                        return;
+                    }
+                    if failed {
+                        break;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    /// Sends the events of the scenario in `files` to the subscription `id`,
+    /// reading them one at a time, see [read_scenario()].
+    ///
+    /// Returns `false` if interrupted, either by shutdown or because the connection
+    /// got closed, and `true` otherwise, even if the files could not be read.
+    async fn play_scenario(files: &[String], id: Id, tx: &UnboundedSender<WsMessage>, cfg: &HastConnConfig, addr: &SocketAddr, shutdown: &mut Shutdown) -> bool {
+        let test_name = &cfg.test_name();
+        let mut chaos = cfg.chaos().map(ChaosConfig::stream);
+        let (mut read, mut sent) = (0, 0);
+        let mut last_fired: Option<chrono::DateTime<Utc>> = None;
+        let mut messages = read_scenario(files.to_vec());
+        while let Some((idx, message)) = messages.recv().await {
+            let file = &files[idx];
+            let message = match message {
+                ScenarioRead::Message(message) => message,
+                ScenarioRead::Unopened(e) => {
+                    tracing::error!("{}: {}: handle message: could not open YAML event log file {}: {}", addr, test_name, file, e);
+                    cfg.stats.add_scenario_error();
+                    continue;
+                },
+            };
+            if read == cfg.max_scenario_events() {
+                tracing::warn!("{}: {}: handle message: YAML event log file {} truncated to {} events overall", addr, test_name, file, read);
+                break;
+            }
+            let ev = match message {
+                Ok(Ok(ev)) => ev,
+                Ok(Err(issue)) => {
+                    tracing::error!("{}: {}: handle message: could not deserialize YAML event log file {}: {}", addr, test_name, file, issue);
+                    cfg.stats.add_scenario_error();
+                    read += 1;
+                    continue;
+                },
+                Err(e) => {
+                    tracing::error!("{}: {}: handle message: could not read YAML event log file {}: {}", addr, test_name, file, e);
+                    cfg.stats.add_scenario_error();
+                    continue;
+                },
+            };
+            read += 1;
+            let ev = match chaos.as_mut() {
+                Some(chaos) => match chaos.push(ev) {
+                    Some(ev) => ev,
+                    None => continue,
+                },
+                None => ev,
+            };
+            if !play_event(ev, &mut last_fired, id, tx, cfg, addr, shutdown).await {
+                return false;
+            }
+            sent += 1;
+        }
+        if let Some(chaos) = chaos {
+            if let Some(ev) = chaos.finish() {
+                if !play_event(ev, &mut last_fired, id, tx, cfg, addr, shutdown).await {
+                    return false;
+                }
+                sent += 1;
+            }
+            tracing::info!("{}: {}: handle message: chaos dropped {} of {} events", addr, test_name, read - sent, read);
+        }
+        true
+    }
+
+    /// Sends `ev` to the subscription `id`, as part of a scenario whose last
+    /// event was fired at `last_fired`.
+    ///
+    /// Returns `false` if interrupted, either by shutdown or because the connection
+    /// got closed.
+    async fn play_event(ev: WsMessage, last_fired: &mut Option<chrono::DateTime<Utc>>, id: Id, tx: &UnboundedSender<WsMessage>, cfg: &HastConnConfig, addr: &SocketAddr, shutdown: &mut Shutdown) -> bool {
+        let test_name = &cfg.test_name();
+        let mut delay = None;
+        if cfg.realtime() {
+            if let Some(fired) = time_fired(&ev) {
+                delay = last_fired.and_then(|last| (fired - last).to_std().ok());
+                *last_fired = Some(fired);
+            }
+        }
+        // Give way between events, stopping as soon as shutdown is requested
+        tokio::select! {
+            biased;
+            _ = shutdown.recv() => {
+                tracing::info!("{}: {}: handle message: scenario interrupted by shutdown", addr, test_name);
+                return false;
+            },
+            _ = async {
+                match delay {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => tokio::task::yield_now().await,
+                }
+            } => (),
+        }
+        if tx.send(ev.set_id(id)).is_err() {
+            tracing::info!("{}: {}: handle message: scenario interrupted by closed connection", addr, test_name);
+            return false;
+        }
+        cfg.stats.add_event(addr, id);
+        true
+    }
+
+    async fn handle_message(wsmsg: WsMessage, tx: UnboundedSender<WsMessage>, cfg: Arc<HastConnConfig>, addr: &SocketAddr, shutdown: Shutdown) -> Result<()> {
+        let mut shutdown = shutdown;
+        use crate::json::{WsMessage::*, ResultBody, ResultObject, ErrorObject};
 
         let test_name = &cfg.test_name();
         let send = |msg| {
@@ -302,6 +1196,26 @@ pub mod server {
             }
         };
 
+        cfg.stats.add_received(addr, &wsmsg);
+
+        if let Some(file) = cfg.response_file(&wsmsg) {
+            let id = wsmsg.id().unwrap_or(0);
+            let path = file.clone();
+            let replies = tokio::task::spawn_blocking(move || scenario::read_file(path)).await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            match replies {
+                Ok(replies) => for reply in replies {
+                    match reply {
+                        Ok(reply) => send(reply.set_id(id)),
+                        Err(issue) => tracing::error!("{}: {}: handle message: could not deserialize reply from {}: {}", addr, test_name, file, issue),
+                    }
+                },
+                Err(e) => tracing::error!("{}: {}: handle message: could not open replies file {}: {}", addr, test_name, file, e),
+            }
+            tracing::info!("{}: {}: handle message: done", addr, test_name);
+            return Ok(());
+        }
+
         match wsmsg {
 
             Auth { access_token } => {
@@ -313,24 +1227,50 @@ pub mod server {
                 send(msg);
             },
 
-            SubscribeEvents { id, .. } => {
+            SubscribeEvents { id, event_type } => {
+                let (cancel_tx, cancel) = oneshot::channel::<()>();
+                cfg.subscriptions.lock().unwrap().insert(id, cancel_tx);
+                cfg.stats.add_subscription(addr, id);
+                if cfg.event_before_ack() && event_type.is_none_or(|t| t == EventType::TimeChanged) {
+                    send(time_changed_event(id));
+                    cfg.stats.add_event(addr, id);
+                }
                 send(WsMessage::new_result_success(id));
-                let yaml_scenario = cfg.yaml_scenario.as_ref();
-                //let yaml_scenario = cfg.yaml_scenario.as_ref().unwrap();
-                let file = format!("{}/{}", cfg.yaml_dir(), yaml_scenario.unwrap());
-                let event_log_file = File::open(file);
-                if let Err(e) = event_log_file {
-                    tracing::error!("{}: {}: handle message: could not open YAML event log file: {}", addr, test_name, e);
-                } else {
-                    let event_log_reader = io::BufReader::new(event_log_file.unwrap());
-                    for document in serde_yaml::Deserializer::from_reader(event_log_reader) {
-                        let _ = WsMessage::deserialize(document)
-                            .and_then(|ev| { Ok(send(ev.set_id(id))) })
-                            .or_else(|err| {
-                                tracing::error!("{}: {}: handle message: could not deserialize YAML document from event log file: {}", addr, test_name, err);
-                                Err(err)
-                            });
+                if cfg.emit_lifecycle() {
+                    for lifecycle in [EventType::HomeassistantStart, EventType::HomeassistantStarted] {
+                        if event_type.is_none_or(|t| t == lifecycle) {
+                            send(lifecycle_event(id, lifecycle));
+                            cfg.stats.add_event(addr, id);
+                        }
+                    }
+                }
+                let stream = async {
+                    if cfg.yaml_scenarios.is_empty() {
+                        tracing::info!("{}: {}: handle message: no YAML event log file to play", addr, test_name);
+                    } else {
+                        let files: Vec<String> = cfg.yaml_scenarios.iter()
+                            .map(|scenario| format!("{}/{}", cfg.yaml_dir(), scenario))
+                            .collect();
+                        while play_scenario(&files, id, &tx, &cfg, addr, &mut shutdown).await && cfg.loop_scenario() {
+                            tracing::info!("{}: {}: handle message: looping over YAML event log files {:?}", addr, test_name, files);
+                            tokio::select! {
+                                biased;
+                                _ = shutdown.recv() => break,
+                                _ = tokio::time::sleep(cfg.loop_delay()) => (),
+                            }
+                        }
                     }
+                    if let Some(interval) = cfg.heartbeat() {
+                        if event_type.is_none_or(|t| t == EventType::TimeChanged) {
+                            send_heartbeats(id, interval, &tx, &cfg, addr, &mut shutdown).await;
+                        }
+                    }
+                };
+                // Events stop as soon as the subscription is cancelled
+                tokio::select! {
+                    biased;
+                    _ = cancel => tracing::info!("{}: {}: handle message: subscription id={} cancelled", addr, test_name, id),
+                    _ = stream => (),
                 }
             },
 
@@ -338,9 +1278,34 @@ pub mod server {
                 send(Pong { id });
             },
 
+            CallService { id, .. } | FireEvent { id, .. } => {
+                send(Result {
+                    id,
+                    success: true,
+                    data: ResultBody::Result {
+                        result: Some(ResultObject::Object {
+                            context: new_context(),
+                        }),
+                    },
+                });
+            },
+
             UnsubscribeEvents { id, subscription } => {
-                tracing::warn!("handle message: unsupported UnsubscribeEvents id={} subscription={}", id, subscription);
-                send(WsMessage::new_result_success(id));
+                if cfg.subscriptions.lock().unwrap().remove(&subscription).is_some() {
+                    cfg.stats.remove_subscription(addr, subscription);
+                    send(WsMessage::new_result_success(id));
+                } else {
+                    send(Result {
+                        id,
+                        success: false,
+                        data: ResultBody::Error {
+                            error: ErrorObject {
+                                code: "not_found".to_string(),
+                                message: "Subscription not found.".to_string(),
+                            }
+                        },
+                    });
+                }
             },
 
             m => {
@@ -0,0 +1,473 @@
+//! Test scenarios, i.e. YAML event log files recorded by `haevlo` and played
+//! by [super::server::Hast].
+//!
+//! A scenario is a stream of YAML documents, each one a [WsMessage] that is
+//! sent to clients subscribing to events. The functions in this module read
+//! scenarios keeping track of the line where each document starts, so that
+//! problems can be reported precisely by both [super::server::Hast] and the
+//! `hast lint` subcommand.
+//!
+//! Scenarios are read lazily, one document at a time, so that huge ones can
+//! be played without loading them into memory first.
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use serde_yaml::{self, Value};
+use tokio::sync::mpsc;
+use crate::json::WsMessage;
+
+/// A single YAML document of a scenario.
+#[derive(Debug)]
+pub struct Document {
+    /// Position of the document in the scenario, counting from `1`.
+    pub index: usize,
+
+    /// Line of the scenario where the document starts, counting from `1`.
+    pub line: usize,
+
+    /// Text of the document.
+    pub text: String,
+}
+
+impl Document {
+    /// Range of lines of the scenario spanned by the document.
+    pub fn lines(&self) -> RangeInclusive<usize> {
+        self.line..=(self.line + self.text.lines().count().max(1) - 1)
+    }
+
+    /// Parses the document as a generic YAML value.
+    ///
+    /// Returns `Ok(None)` for empty documents, e.g. those only holding comments.
+    pub fn parse_value(&self) -> Result<Option<Value>, Issue> {
+        match serde_yaml::from_str(&self.text) {
+            Ok(Value::Null) => Ok(None),
+            Ok(value) => Ok(Some(value)),
+            Err(e) => {
+                let line = e.location().map_or(self.line, |loc| self.line + loc.line() - 1);
+                Err(Issue::error(self, line, format!("malformed document: {}", e)))
+            },
+        }
+    }
+}
+
+/// Severity of an [Issue].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Severity {
+    /// The scenario may be played, but likely not as its author meant.
+    Warning,
+    /// The document cannot be played at all.
+    Error,
+}
+
+/// A problem found in a scenario.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Issue {
+    /// Position of the document with the problem, see [Document::index].
+    pub document: usize,
+    /// Range of lines of the document with the problem, see [Document::lines()].
+    pub lines: RangeInclusive<usize>,
+    /// Line where the problem was found.
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Issue {
+    fn error(doc: &Document, line: usize, message: String) -> Issue {
+        Issue { document: doc.index, lines: doc.lines(), line, severity: Severity::Error, message }
+    }
+
+    fn warning(doc: &Document, message: String) -> Issue {
+        Issue { document: doc.index, lines: doc.lines(), line: doc.line, severity: Severity::Warning, message }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "document #{} (lines {}-{}), line {}: {}: {}",
+            self.document, self.lines.start(), self.lines.end(), self.line, severity, self.message)
+    }
+}
+
+/// Document markers, which are always found at the beginning of a line.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Marker {
+    /// `---`, starting a new document
+    Start,
+    /// `...`, ending the current document
+    End,
+}
+
+impl Marker {
+    fn of(line: &str) -> Option<Marker> {
+        let marker = match line.get(..3) {
+            Some("---") => Marker::Start,
+            Some("...") => Marker::End,
+            _ => return None,
+        };
+        // `---foo` is a plain scalar rather than a marker
+        match line[3..].chars().next() {
+            None | Some(' ' | '\t' | '\r' | '\n') => Some(marker),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `line` holds nothing that would make a document by itself.
+fn is_blank(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#') || Marker::of(line) == Some(Marker::End)
+}
+
+/// Lines of the document being read by [Documents].
+#[derive(Debug)]
+struct Chunk {
+    line: usize,
+    text: String,
+    /// Whether it starts with a `---` marker
+    explicit: bool,
+}
+
+/// Iterator over the YAML documents of a scenario, read lazily from a
+/// [BufRead], see [documents()].
+///
+/// Documents start either at `---` markers, or after a `...` marker ending the
+/// previous one. Comments not following a `---` marker, e.g. those heading the
+/// scenario, are not considered a document.
+#[derive(Debug)]
+pub struct Documents<R> {
+    reader: R,
+    /// Number of lines read so far
+    lines: usize,
+    /// Number of documents yielded so far
+    index: usize,
+    current: Option<Chunk>,
+    done: bool,
+}
+
+impl<R: BufRead> Documents<R> {
+    pub fn new(reader: R) -> Documents<R> {
+        Documents { reader, lines: 0, index: 0, current: None, done: false }
+    }
+
+    /// Turns the lines read so far into a document, if they make one.
+    fn finish(&mut self) -> Option<Document> {
+        let chunk = self.current.take()?;
+        if !chunk.explicit && chunk.text.lines().all(is_blank) {
+            return None;
+        }
+        self.index += 1;
+        Some(Document { index: self.index, line: chunk.line, text: chunk.text })
+    }
+}
+
+impl<R: BufRead> Iterator for Documents<R> {
+    type Item = io::Result<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => {
+                    self.done = true;
+                    return self.finish().map(Ok);
+                },
+                Ok(_) => self.lines += 1,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+            let marker = Marker::of(&line);
+            if marker == Some(Marker::Start) {
+                let finished = self.finish();
+                self.current = Some(Chunk { line: self.lines, text: line, explicit: true });
+                if finished.is_some() {
+                    return finished.map(Ok);
+                }
+                continue;
+            }
+            let lines = self.lines;
+            self.current.get_or_insert_with(|| Chunk { line: lines, text: String::new(), explicit: false })
+                .text.push_str(&line);
+            if marker == Some(Marker::End) {
+                if let Some(doc) = self.finish() {
+                    return Some(Ok(doc));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Splits the `source` of a scenario into its YAML documents, see [Documents].
+pub fn documents(source: &str) -> Vec<Document> {
+    Documents::new(source.as_bytes())
+        .collect::<io::Result<_>>()
+        .expect("reading a string cannot fail")
+}
+
+/// Lazily reads the messages of the scenario from `reader`, skipping empty
+/// documents.
+///
+/// Documents that are not valid messages are reported by an [Issue], while
+/// failures reading from `reader` end the iteration with an error.
+pub fn messages<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<Result<WsMessage, Issue>>> {
+    Documents::new(reader).filter_map(|doc| {
+        let doc = match doc {
+            Ok(doc) => doc,
+            Err(e) => return Some(Err(e)),
+        };
+        match doc.parse_value() {
+            Ok(None) => None,
+            Ok(Some(value)) => Some(Ok(to_message(&doc, value))),
+            Err(issue) => Some(Ok(Err(issue))),
+        }
+    })
+}
+
+/// Same as [messages()], but reading from `reader` on a blocking thread, so that
+/// async tasks are not stalled meanwhile.
+///
+/// Messages are read a few at a time as they get received. Reading stops after
+/// an error, or as soon as the receiver is dropped.
+pub fn spawn_messages<R: BufRead + Send + 'static>(reader: R) -> mpsc::Receiver<io::Result<Result<WsMessage, Issue>>> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        for message in messages(reader) {
+            let failed = message.is_err();
+            if tx.blocking_send(message).is_err() || failed {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Reads all the messages of the scenario in `source`, skipping empty documents.
+pub fn read(source: &str) -> Vec<Result<WsMessage, Issue>> {
+    read_at_most(source, usize::MAX).0
+}
+
+/// Same as [read()], but stops after `max` messages, without parsing the
+/// following ones. Also returns whether any messages were left out.
+pub fn read_at_most(source: &str, max: usize) -> (Vec<Result<WsMessage, Issue>>, bool) {
+    let mut messages = messages(source.as_bytes())
+        .map(|msg| msg.expect("reading a string cannot fail"));
+    let read: Vec<_> = messages.by_ref().take(max).collect();
+    let truncated = messages.next().is_some();
+    (read, truncated)
+}
+
+/// Opens the scenario stored at `path` for reading, decompressing it on the
+/// fly when its extension is `.gz`, as for those written by `haevlo --compress gzip`.
+pub fn open(path: impl AsRef<Path>) -> io::Result<Box<dyn BufRead + Send>> {
+    let path = path.as_ref();
+    let file = fs::File::open(path)?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Reads the whole text of the scenario stored at `path`, see [open()].
+pub fn read_source(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut source = String::new();
+    open(path)?.read_to_string(&mut source)?;
+    Ok(source)
+}
+
+/// Same as [read()], for the scenario stored at `path`, see [open()].
+pub fn read_file(path: impl AsRef<Path>) -> io::Result<Vec<Result<WsMessage, Issue>>> {
+    messages(open(path)?).collect()
+}
+
+/// Checks the scenario in `source`, returning all the issues found.
+///
+/// On top of documents that are not valid messages, the checks report events
+/// lacking either `event_type` or `time_fired`, and events fired before the
+/// ones preceding them.
+pub fn lint(source: &str) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut last_fired: Option<DateTime<Utc>> = None;
+    for doc in documents(source) {
+        let value = match doc.parse_value() {
+            Ok(Some(value)) => value,
+            Ok(None) => continue,
+            Err(issue) => {
+                issues.push(issue);
+                continue;
+            },
+        };
+
+        let event = value.get("event").filter(|_| value.get("type").and_then(Value::as_str) == Some("event"));
+        if let Some(event) = event.filter(|event| event.get("variables").is_none()) {
+            if event.get("event_type").is_none() {
+                issues.push(Issue::warning(&doc, "event without event_type".to_owned()));
+            }
+            match event.get("time_fired").cloned().map(DateTime::<Utc>::deserialize) {
+                None => issues.push(Issue::warning(&doc, "event without time_fired".to_owned())),
+                Some(Err(e)) => issues.push(Issue::warning(&doc, format!("invalid time_fired: {}", e))),
+                Some(Ok(fired)) => {
+                    if let Some(last) = last_fired.filter(|last| fired < *last) {
+                        issues.push(Issue::warning(&doc,
+                            format!("non-monotonic time_fired: {} comes after {}", fired, last)));
+                    }
+                    last_fired = Some(fired);
+                },
+            }
+        }
+
+        if let Err(issue) = to_message(&doc, value) {
+            issues.push(issue);
+        }
+    }
+    issues
+}
+
+fn to_message(doc: &Document, value: Value) -> Result<WsMessage, Issue> {
+    WsMessage::deserialize(value)
+        .map_err(|e| Issue::error(doc, doc.line, format!("not a valid message: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = include_str!("../../tests/resources/000-base.yaml");
+
+    const EVENT: &str = "
+type: event
+id: 3
+event:
+  data: {}
+  event_type: state_changed
+  time_fired: \"2022-05-10T23:34:50.163029Z\"
+  origin: LOCAL
+  context:
+    id: 0180b0534a329317ab8d2d0ef37f1bcb
+";
+
+    #[test]
+    fn documents_lines() {
+        let docs = documents("# header\n---\na: 1\n---\nb: 2\nc: 3\n--- \nd: 4");
+        let lines: Vec<usize> = docs.iter().map(|doc| doc.line).collect();
+        assert_eq!(lines, vec![2, 4, 7]);
+        assert_eq!(docs[1].text, "---\nb: 2\nc: 3\n");
+        assert_eq!(docs[1].index, 2);
+        assert_eq!(docs[1].lines(), 4..=6);
+    }
+
+    #[test]
+    fn documents_markers() {
+        let docs = documents("a: 1\n...\n# between\n---\t\nb: ---x\n---c: 2\n...\nd: 3\n");
+        let lines: Vec<usize> = docs.iter().map(|doc| doc.line).collect();
+        assert_eq!(lines, vec![1, 4, 8]);
+        assert_eq!(docs[1].text, "---\t\nb: ---x\n---c: 2\n...\n");
+        let value = docs[1].parse_value().unwrap().unwrap();
+        assert_eq!(value.get("---c").and_then(Value::as_u64), Some(2));
+    }
+
+    #[test]
+    fn messages_lazy() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+
+        // Documents are parsed as soon as the next one starts, before reading further
+        let mut messages = messages(BufReader::new(BASE.as_bytes().chain(Broken)));
+        for _ in 0..7 {
+            assert!(matches!(messages.next(), Some(Ok(Ok(WsMessage::Event { .. })))));
+        }
+        assert!(matches!(messages.next(), Some(Err(_))));
+        assert!(messages.next().is_none());
+    }
+
+    #[test]
+    fn read_base() {
+        let messages = read(BASE);
+        assert_eq!(messages.len(), 8);
+        assert!(messages.iter().all(|msg| matches!(msg, Ok(WsMessage::Event { .. }))));
+    }
+
+    #[test]
+    fn read_at_most_base() {
+        let (messages, truncated) = read_at_most(BASE, 3);
+        assert_eq!(messages, read(BASE)[..3]);
+        assert!(truncated);
+
+        let (messages, truncated) = read_at_most(BASE, 8);
+        assert_eq!(messages.len(), 8);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn lint_base() {
+        assert_eq!(lint(BASE), vec![]);
+    }
+
+    #[test]
+    fn lint_bad() {
+        let source = [
+            // line 1
+            "---",
+            EVENT.trim(),
+            // line 11
+            "---",
+            &EVENT.trim().replace("2022-05-10T23:34:50", "2022-05-10T20:00:00"),
+            // line 21
+            "---",
+            &EVENT.trim().replace("  event_type: state_changed\n", ""),
+            // line 30
+            "---",
+            "type: event\nid: [1, 2",
+        ].join("\n");
+
+        let issues = lint(&source);
+        assert_eq!(issues.len(), 4, "{:?}", issues);
+        assert_eq!(issues[0].line, 11);
+        assert_eq!(issues[0].severity, Severity::Warning);
+        assert!(issues[0].message.contains("non-monotonic"));
+        assert_eq!(issues[1].line, 21);
+        assert_eq!(issues[1].document, 3);
+        assert_eq!(issues[1].message, "event without event_type");
+        assert_eq!(issues[2].line, 21);
+        assert_eq!(issues[2].severity, Severity::Error);
+        assert!(issues[3].line > 30, "{}", issues[3]);
+        assert!(issues[3].message.starts_with("malformed document"));
+    }
+
+    #[test]
+    fn read_reports_document() {
+        let source = [
+            "---",
+            EVENT.trim(),
+            "---",
+            &EVENT.trim().replace("event:\n", "event: [\n"),
+            "---",
+            EVENT.trim(),
+        ].join("\n");
+
+        let messages = read(&source);
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].is_ok());
+        assert!(messages[2].is_ok());
+        let issue = messages[1].as_ref().unwrap_err();
+        assert_eq!(issue.document, 2);
+        assert_eq!(issue.lines, 11..=20);
+        assert!(issue.to_string().starts_with("document #2 (lines 11-20)"), "{}", issue);
+    }
+}
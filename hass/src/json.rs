@@ -1,13 +1,18 @@
 use std::fmt;
+use std::str::FromStr;
 use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 use crate::error::Error;
 
+mod context;
+
+pub use context::{context_chain, ContextIndex};
+
 pub type Id = u64;
 
 /// WebSocket message format for Home Assistant, as described at
 /// https://developers.home-assistant.io/docs/api/websocket/
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum WsMessage {
 
@@ -40,16 +45,70 @@ pub enum WsMessage {
     },
 
     // Calling a service
-    // TODO: https://developers.home-assistant.io/docs/api/websocket/#calling-a-service
+    CallService {
+        id: Id,
+        domain: String,
+        service: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        service_data: Option<serde_json::Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<serde_json::Value>,
+    },
 
     // Fetching states
+    // The reply carries a list of StateObject in "result", not an object
     GetStates { id: Id },
-    // TODO: this provides a list in "result", not an object
+
+    // Fetching services
+    GetServices { id: Id },
+
+    // Fetching the entity registry
+    // The reply carries a list of EntityRegistryEntry in "result"
+    #[serde(rename = "config/entity_registry/list")]
+    EntityRegistryList { id: Id },
+
+    // Validate config
+    ValidateConfig {
+        id: Id,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        trigger: Option<serde_json::Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        condition: Option<serde_json::Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        action: Option<serde_json::Value>,
+    },
 
     // Pings and Pongs
     Ping { id: Id },
     Pong { id: Id },
 
+    /// Any message of a type not listed above, kept as it is so that it may
+    /// still be dispatched, logged or replayed
+    #[serde(untagged)]
+    Other {
+        #[serde(rename = "type", deserialize_with = "deserialize_unknown_type")]
+        type_tag: String,
+        #[serde(flatten)]
+        fields: serde_json::Map<String, serde_json::Value>,
+    },
+
+}
+
+/// Tags of the message types known to [WsMessage], i.e. all but `Other`.
+const KNOWN_TYPES: &[&str] = &[
+    "auth_required", "auth", "auth_ok", "auth_invalid", "result", "subscribe_events",
+    "event", "unsubscribe_events", "fire_event", "call_service", "get_states",
+    "get_services", "config/entity_registry/list", "validate_config", "ping", "pong",
+];
+
+/// Deserializes the type of [WsMessage::Other], failing for known types so
+/// that malformed messages of those types are still reported as errors.
+fn deserialize_unknown_type<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let type_tag = String::deserialize(deserializer)?;
+    if KNOWN_TYPES.contains(&type_tag.as_str()) {
+        return Err(serde::de::Error::custom(format!("malformed {} message", type_tag)));
+    }
+    Ok(type_tag)
 }
 
 impl WsMessage {
@@ -77,9 +136,14 @@ impl WsMessage {
             UnsubscribeEvents { id, .. } => Some(*id),
             Event { id, .. } => Some(*id),
             FireEvent { id, .. } => Some(*id),
+            CallService { id, .. } => Some(*id),
             GetStates { id } => Some(*id),
+            GetServices { id } => Some(*id),
+            EntityRegistryList { id } => Some(*id),
+            ValidateConfig { id, .. } => Some(*id),
             Ping { id } => Some(*id),
             Pong { id } => Some(*id),
+            Other { fields, .. } => fields.get("id").and_then(serde_json::Value::as_u64),
 
             //  Variants without
             //* (avoid `_ => None` to get compile errors when missing some variants)
@@ -111,15 +175,33 @@ impl WsMessage {
             FireEvent { event_data, event_type, .. } => {
                 FireEvent { id: new_id, event_data, event_type}
             },
+            CallService { domain, service, service_data, target, .. } => {
+                CallService { id: new_id, domain, service, service_data, target }
+            },
             GetStates { .. } => {
                 GetStates { id: new_id }
             },
+            GetServices { .. } => {
+                GetServices { id: new_id }
+            },
+            EntityRegistryList { .. } => {
+                EntityRegistryList { id: new_id }
+            },
+            ValidateConfig { trigger, condition, action, .. } => {
+                ValidateConfig { id: new_id, trigger, condition, action }
+            },
             Ping { .. } => {
                 Ping { id: new_id }
             },
             Pong { .. } => {
                 Pong { id: new_id }
             },
+            Other { type_tag, mut fields } => {
+                if fields.contains_key("id") {
+                    fields.insert("id".to_owned(), new_id.into());
+                }
+                Other { type_tag, fields }
+            },
 
             //  Variants without
             //* (avoid `_ => self` to get compile errors when missing some variants)
@@ -130,36 +212,287 @@ impl WsMessage {
         }
     }
 
+    /// Retrieves the id of the entity whose state changed, if the message is
+    /// a `state_changed` event.
+    ///
+    /// The id is looked up at `data.new_state.entity_id` first, then at
+    /// `data.entity_id`. Returns `None` for any other message, or when the
+    /// event lacks both fields.
+    pub fn entity_id(&self) -> Option<&str> {
+        match self {
+            WsMessage::Event {
+                event: EventObj::Event { data, event_type: EventType::StateChanged, .. },
+                ..
+            } => {
+                data.pointer("/new_state/entity_id")
+                    .or_else(|| data.get("entity_id"))
+                    .and_then(serde_json::Value::as_str)
+            },
+            _ => None,
+        }
+    }
+
+    /// Retrieves the context of the event carried by the message, if any, or
+    /// the one of a `Result` replying to a command, e.g. `call_service`.
+    ///
+    /// The latter is the context of the events fired by HA while handling the command.
+    pub fn context(&self) -> Option<&ContextObject> {
+        match self {
+            WsMessage::Event { event: EventObj::Event { context, .. }, .. } => Some(context),
+            WsMessage::Event { event: EventObj::Trigger { context, .. }, .. } => Some(context),
+            WsMessage::Result { data: ResultBody::Result { result: Some(ResultObject::Object { context }) }, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Retrieves the typed data of a `state_changed` event, or `None` for any
+    /// other message or when the data does not match the expected shape.
+    pub fn as_state_changed(&self) -> Option<StateChangedData> {
+        match self {
+            WsMessage::Event {
+                event: EventObj::Event { data, event_type: EventType::StateChanged, .. },
+                ..
+            } => StateChangedData::deserialize(data).ok(),
+            _ => None,
+        }
+    }
+
+    /// Retrieves the typed data of a `call_service` event, or `None` for any
+    /// other message or when the data does not match the expected shape.
+    pub fn as_call_service(&self) -> Option<CallServiceData> {
+        match self {
+            WsMessage::Event {
+                event: EventObj::Event { data, event_type: EventType::CallService, .. },
+                ..
+            } => CallServiceData::deserialize(data).ok(),
+            _ => None,
+        }
+    }
+
+    /// Retrieves the typed `trigger` variables of an event received through a
+    /// trigger subscription, or `None` for any other message or when they do
+    /// not match the expected shape.
+    pub fn as_trigger(&self) -> Option<TriggerVariables> {
+        match self {
+            WsMessage::Event { event: EventObj::Trigger { variables, .. }, .. } => {
+                variables.get("trigger")
+                    .and_then(|trigger| TriggerVariables::deserialize(trigger).ok())
+            },
+            _ => None,
+        }
+    }
+
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 #[serde(untagged, rename_all = "snake_case")]
 pub enum ResultBody {
-    Result { result: Option<ResultObject> },
+    // Tried first: `result` being optional, the other variant would match errors too
     Error { error: ErrorObject },
+    Result { result: Option<ResultObject> },
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 #[serde(untagged)]
 pub enum ResultObject {
     Object { context: ContextObject },
     Array(Vec<serde_json::Value>),
+    /// Any other object, e.g. the reply to `validate_config`
+    Map(serde_json::Map<String, serde_json::Value>),
 }
 
-#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Clone, Debug)]
 pub struct ContextObject {
     pub id: String,
     pub parent_id: Option<String>,
     pub user_id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+impl ContextObject {
+    /// Returns whether `self` was caused by the `parent` context.
+    pub fn is_child_of(&self, parent: &ContextObject) -> bool {
+        self.parent_id.as_deref() == Some(parent.id.as_str())
+    }
+}
+
+/// Result of a `validate_config` command, with the outcome for each of the
+/// sections that were sent.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ValidationResult {
+    pub trigger: Option<SectionValidation>,
+    pub condition: Option<SectionValidation>,
+    pub action: Option<SectionValidation>,
+}
+
+/// Outcome of the validation of a single section of a `validate_config` command.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct SectionValidation {
+    pub valid: bool,
+    pub error: Option<String>,
+}
+
+/// State of an entity, as found in the reply to `get_states`.
+///
+/// Attributes are kept as they are, whatever their keys.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct StateObject {
+    pub entity_id: String,
+    pub state: String,
+    #[serde(default)]
+    pub attributes: serde_json::Value,
+    pub last_changed: DateTime<Utc>,
+    pub last_updated: DateTime<Utc>,
+    pub context: ContextObject,
+}
+
+/// Changes to the state of an entity, in the compressed form used by HA under
+/// the `c` key of `subscribe_entities` events: `+` holds what was added or
+/// changed, `-` what was removed.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
+pub struct EntityStateDiff {
+    #[serde(rename = "+", default, skip_serializing_if = "Option::is_none")]
+    pub additions: Option<EntityStateAdditions>,
+    #[serde(rename = "-", default, skip_serializing_if = "Option::is_none")]
+    pub removals: Option<EntityStateRemovals>,
+}
+
+/// The `+` part of an [EntityStateDiff]. Timestamps are in seconds since the epoch.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
+pub struct EntityStateAdditions {
+    #[serde(rename = "s", default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(rename = "lc", default, skip_serializing_if = "Option::is_none")]
+    pub last_changed: Option<f64>,
+    #[serde(rename = "lu", default, skip_serializing_if = "Option::is_none")]
+    pub last_updated: Option<f64>,
+    #[serde(rename = "c", default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<CompressedContext>,
+}
+
+/// The `-` part of an [EntityStateDiff].
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Default, Debug)]
+pub struct EntityStateRemovals {
+    /// Keys of the removed attributes.
+    #[serde(rename = "a", default)]
+    pub attributes: Vec<String>,
+}
+
+/// A context within an [EntityStateDiff], either just its new id or the fields
+/// that changed.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(untagged)]
+pub enum CompressedContext {
+    Id(String),
+    Object {
+        #[serde(default)]
+        id: Option<String>,
+        #[serde(default)]
+        parent_id: Option<String>,
+        #[serde(default)]
+        user_id: Option<String>,
+    },
+}
+
+impl EntityStateDiff {
+    /// Applies the diff to `base`, the last known full state of the entity.
+    ///
+    /// Just like HA does, a new `last_changed` also sets `last_updated`, unless
+    /// the latter is provided as well.
+    pub fn apply(&self, base: &mut StateObject) {
+        if let Some(add) = &self.additions {
+            if let Some(state) = &add.state {
+                base.state = state.clone();
+            }
+            if let Some(attributes) = &add.attributes {
+                let base_attributes = attributes_mut(&mut base.attributes);
+                for (key, value) in attributes {
+                    base_attributes.insert(key.clone(), value.clone());
+                }
+            }
+            if let Some(lc) = add.last_changed.and_then(from_timestamp) {
+                base.last_changed = lc;
+                base.last_updated = lc;
+            }
+            if let Some(lu) = add.last_updated.and_then(from_timestamp) {
+                base.last_updated = lu;
+            }
+            match &add.context {
+                Some(CompressedContext::Id(id)) => base.context.id = id.clone(),
+                Some(CompressedContext::Object { id, parent_id, user_id }) => {
+                    if let Some(id) = id {
+                        base.context.id = id.clone();
+                    }
+                    if parent_id.is_some() {
+                        base.context.parent_id = parent_id.clone();
+                    }
+                    if user_id.is_some() {
+                        base.context.user_id = user_id.clone();
+                    }
+                },
+                None => (),
+            }
+        }
+        if let Some(remove) = &self.removals {
+            let base_attributes = attributes_mut(&mut base.attributes);
+            for key in &remove.attributes {
+                base_attributes.remove(key);
+            }
+        }
+    }
+}
+
+/// Returns the attributes of a state as a map, turning them into an empty one
+/// if they were anything else.
+fn attributes_mut(attributes: &mut serde_json::Value) -> &mut serde_json::Map<String, serde_json::Value> {
+    if !attributes.is_object() {
+        *attributes = serde_json::Value::Object(Default::default());
+    }
+    attributes.as_object_mut().unwrap()
+}
+
+fn from_timestamp(secs: f64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_micros((secs * 1e6).round() as i64)
+}
+
+/// Description of a service, as found in the reply to `get_services` under
+/// its domain and name.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ServiceDescription {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Fields accepted by the service, keyed by their name
+    #[serde(default)]
+    pub fields: serde_json::Value,
+}
+
+/// Entry of the entity registry, as found in the reply to
+/// `config/entity_registry/list`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct EntityRegistryEntry {
+    pub entity_id: String,
+    /// Area the entity was assigned to, if any, overriding that of its device
+    #[serde(default)]
+    pub area_id: Option<String>,
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// Name set by the user, if any
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Integration providing the entity
+    pub platform: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct ErrorObject {
     pub code: String,
     pub message: String,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 #[serde(untagged)]
 pub enum EventObj {
     // https://developers.home-assistant.io/docs/api/websocket/#subscribe-to-events
@@ -177,9 +510,46 @@ pub enum EventObj {
     },
 }
 
+/// Data of a `state_changed` event, see [WsMessage::as_state_changed()].
+///
+/// Either state is `None` when the entity was just added or removed.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct StateChangedData {
+    pub entity_id: String,
+    #[serde(default)]
+    pub old_state: Option<StateObject>,
+    #[serde(default)]
+    pub new_state: Option<StateObject>,
+}
+
+/// Data of a `call_service` event, see [WsMessage::as_call_service()].
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct CallServiceData {
+    pub domain: String,
+    pub service: String,
+    #[serde(default)]
+    pub service_data: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Common shape of the `trigger` variable of events fired by trigger
+/// subscriptions, as described at
+/// https://www.home-assistant.io/docs/automation/templating/#available-trigger-data
+///
+/// Fields specific to each trigger platform are kept in `other`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct TriggerVariables {
+    pub platform: String,
+    pub entity_id: Option<String>,
+    pub from_state: Option<serde_json::Value>,
+    pub to_state: Option<serde_json::Value>,
+
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
 /// Event types as described on the Home Assistant webiste at
 /// https://www.home-assistant.io/docs/configuration/events/
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum EventType {
     CallService,
@@ -207,11 +577,59 @@ pub enum EventType {
 
     HaevloStart,
     HaevloStop,
+    PiresenceLoglevel,
 
+    #[default]
     #[serde(other)]
     Unknown,
 }
 
+impl EventType {
+    /// Returns all the known event types, i.e. all but [EventType::Unknown].
+    pub fn all() -> &'static [EventType] {
+        use EventType::*;
+        &[
+            CallService,
+            ComponentLoaded,
+            CoreConfigUpdated,
+            DataEntryFlowProgressed,
+            HomeassistantStart,
+            HomeassistantStarted,
+            HomeassistantStop,
+            HomeassistantFinalWrite,
+            HomeassistantClose,
+            LogbookEntry,
+            ServiceRegistered,
+            ServiceRemoved,
+            StateChanged,
+            ThemesUpdated,
+            TimerOutOfSync,
+            TimeChanged,
+            UserAdded,
+            UserRemoved,
+            AutomationReloaded,
+            AutomationTriggered,
+            SceneReloaded,
+            ScriptStarted,
+            HaevloStart,
+            HaevloStop,
+            PiresenceLoglevel,
+        ]
+    }
+}
+
+/// Parses the name HA uses for a known event type, e.g. `state_changed`.
+impl FromStr for EventType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EventType, String> {
+        EventType::all().iter()
+            .copied()
+            .find(|event_type| serde_json::to_value(event_type).ok().as_ref().and_then(serde_json::Value::as_str) == Some(s))
+            .ok_or_else(|| format!("unknown event type: {}", s))
+    }
+}
+
 fn fmt_json(f: &mut fmt::Formatter<'_>, obj: &impl Serialize) -> fmt::Result {
     match serde_json::to_string(&obj) {
         Ok(s) => write!(f, "{}", s),
@@ -225,10 +643,6 @@ impl fmt::Display for WsMessage {
     }
 }
 
-impl Default for EventType {
-    fn default() -> Self { EventType::Unknown }
-}
-
 impl fmt::Display for EventType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt_json(f,&self)
@@ -252,11 +666,46 @@ mod tests {
     use tracing_test::traced_test;
     use super::*;
 
+    #[test]
+    fn event_type_all_exhaustive() {
+        use EventType::*;
+        for event_type in [CallService, ComponentLoaded, CoreConfigUpdated, DataEntryFlowProgressed,
+                HomeassistantStart, HomeassistantStarted, HomeassistantStop, HomeassistantFinalWrite,
+                HomeassistantClose, LogbookEntry, ServiceRegistered, ServiceRemoved, StateChanged,
+                ThemesUpdated, TimerOutOfSync, TimeChanged, UserAdded, UserRemoved, AutomationReloaded,
+                AutomationTriggered, SceneReloaded, ScriptStarted, HaevloStart, HaevloStop,
+                PiresenceLoglevel, Unknown] {
+            // No wildcard: new variants must be added here, and to EventType::all()
+            let known = match event_type {
+                CallService | ComponentLoaded | CoreConfigUpdated | DataEntryFlowProgressed
+                | HomeassistantStart | HomeassistantStarted | HomeassistantStop | HomeassistantFinalWrite
+                | HomeassistantClose | LogbookEntry | ServiceRegistered | ServiceRemoved | StateChanged
+                | ThemesUpdated | TimerOutOfSync | TimeChanged | UserAdded | UserRemoved | AutomationReloaded
+                | AutomationTriggered | SceneReloaded | ScriptStarted | HaevloStart | HaevloStop
+                | PiresenceLoglevel => true,
+                Unknown => false,
+            };
+            assert_eq!(EventType::all().contains(&event_type), known, "{:?}", event_type);
+        }
+        assert_eq!(EventType::all().len(), 25);
+    }
+
+    #[test]
+    fn event_type_all_roundtrip() {
+        for event_type in EventType::all() {
+            let json = serde_json::to_string(event_type).unwrap();
+            assert_eq!(serde_json::from_str::<EventType>(&json).unwrap(), *event_type);
+            assert_eq!(json.trim_matches('"').parse::<EventType>().unwrap(), *event_type);
+        }
+        assert!("unknown".parse::<EventType>().is_err());
+        assert!("not_an_event".parse::<EventType>().is_err());
+    }
+
     fn log_and_check(val: &WsMessage, json: &str) {
         tracing::debug!("{:?} <~~> {}", val, json);
-        let deserialized : WsMessage = deserialize(&json).unwrap();
+        let deserialized : WsMessage = deserialize(json).unwrap();
         assert_eq!(deserialized, *val);
-        let serialized = serialize(&val).unwrap();
+        let serialized = serialize(val).unwrap();
         let roundtrip = serialize(&deserialized).unwrap();
         assert_eq!(serialized, roundtrip);
     }
@@ -302,6 +751,27 @@ mod tests {
             \"result\": null
         }");
 
+    serde_test!(msg_result_error,
+        WsMessage::Result {
+            id: 19,
+            success: false,
+            data: ResultBody::Error {
+                error: ErrorObject {
+                    code: String::from("invalid_format"),
+                    message: String::from("Message incorrectly formatted."),
+                }
+            }
+        },
+        "{
+            \"id\": 19,
+            \"type\": \"result\",
+            \"success\": false,
+            \"error\": {
+                \"code\": \"invalid_format\",
+                \"message\": \"Message incorrectly formatted.\"
+            }
+        }");
+
     serde_test!(msg_auth_result_object,
         WsMessage::Result {
             id: 18,
@@ -381,13 +851,14 @@ mod tests {
         et_test(EventType::AutomationTriggered, "automation_triggered");
         et_test(EventType::SceneReloaded, "scene_reloaded");
         et_test(EventType::ScriptStarted, "script_started");
+        et_test(EventType::PiresenceLoglevel, "piresence_loglevel");
     }
 
     #[test]
     #[traced_test]
     fn event_type_unknown() {
         let unknown = "\"an_unknown_event\"";
-        let deserialized : EventType = serde_json::from_str(&unknown).unwrap();
+        let deserialized : EventType = serde_json::from_str(unknown).unwrap();
         assert_eq!(&deserialized, &EventType::Unknown);
     }
 
@@ -432,10 +903,79 @@ mod tests {
         "{\"id\": 56412, \"type\": \"fire_event\",\"event_type\": \"homeassistant_started\"}");
 
 
+    serde_test!(msg_call_service,
+        WsMessage::CallService {
+            id: 24,
+            domain: String::from("light"),
+            service: String::from("turn_on"),
+            service_data: Some(serde_json::from_str("{\"color_name\": \"beige\", \"brightness\": 101}").unwrap()),
+            target: Some(serde_json::from_str("{\"entity_id\": \"light.kitchen\"}").unwrap()),
+        },
+        "{\"id\": 24, \"type\": \"call_service\", \"domain\": \"light\", \"service\": \"turn_on\",
+            \"service_data\": {\"color_name\": \"beige\", \"brightness\": 101},
+            \"target\": {\"entity_id\": \"light.kitchen\"}}");
+
     serde_test!(msg_get_states,
         WsMessage::GetStates { id: 78923 },
         "{\"id\": 78923, \"type\": \"get_states\"}");
 
+    serde_test!(msg_other,
+        WsMessage::Other {
+            type_tag: String::from("entity_registry_updated"),
+            fields: serde_json::from_str("{\"id\": 7, \"success\": true, \"changes\": [1, 2]}").unwrap(),
+        },
+        "{\"type\": \"entity_registry_updated\", \"id\": 7, \"success\": true, \"changes\": [1, 2]}");
+
+    #[test]
+    fn other_id() {
+        let msg = deserialize("{\"type\": \"future_thing\", \"id\": 7}").unwrap();
+        assert_eq!(msg.id(), Some(7));
+        assert_eq!(serde_json::to_value(&msg).unwrap()["type"], "future_thing");
+        assert_eq!(msg.set_id(9).id(), Some(9));
+
+        let msg = deserialize("{\"type\": \"future_thing\"}").unwrap();
+        assert_eq!(msg.id(), None);
+        assert_eq!(msg.set_id(9).id(), None);
+    }
+
+    #[test]
+    fn known_type_malformed() {
+        assert!(deserialize("{\"type\": \"ping\"}").is_err());
+        assert!(deserialize("{\"type\": \"result\", \"id\": \"x\"}").is_err());
+        assert!(deserialize("{\"id\": 1}").is_err());
+    }
+
+    serde_test!(msg_get_services,
+        WsMessage::GetServices { id: 78924 },
+        "{\"id\": 78924, \"type\": \"get_services\"}");
+
+    serde_test!(msg_entity_registry_list,
+        WsMessage::EntityRegistryList { id: 12 },
+        "{\"id\": 12, \"type\": \"config/entity_registry/list\"}");
+
+    serde_test!(msg_validate_config,
+        WsMessage::ValidateConfig {
+            id: 4,
+            trigger: Some(serde_json::from_str("{\"platform\": \"state\", \"entity_id\": \"light.kitchen\"}").unwrap()),
+            condition: None,
+            action: Some(serde_json::from_str("[{\"service\": \"light.turn_on\"}]").unwrap()),
+        },
+        "{\"id\": 4, \"type\": \"validate_config\",
+            \"trigger\": {\"platform\": \"state\", \"entity_id\": \"light.kitchen\"},
+            \"action\": [{\"service\": \"light.turn_on\"}]}");
+
+    serde_test!(msg_validate_config_result,
+        WsMessage::Result {
+            id: 4,
+            success: true,
+            data: ResultBody::Result {
+                result: Some(ResultObject::Map(serde_json::from_str(
+                    "{\"trigger\": {\"valid\": true, \"error\": null}}").unwrap())),
+            }
+        },
+        "{\"id\": 4, \"type\": \"result\", \"success\": true,
+            \"result\": {\"trigger\": {\"valid\": true, \"error\": null}}}");
+
     serde_test!(msg_ping,
         WsMessage::Ping { id: 789423 },
         "{\"id\": 789423, \"type\": \"ping\"}");
@@ -443,4 +983,232 @@ mod tests {
     serde_test!(msg_pong,
         WsMessage::Pong { id: 789423 },
         "{\"id\": 789423, \"type\": \"pong\"}");
-}
\ No newline at end of file
+
+    fn event(event_type: EventType, data: &str) -> WsMessage {
+        WsMessage::Event {
+            id: 18,
+            event: EventObj::Event {
+                data: serde_json::from_str(data).unwrap(),
+                event_type,
+                time_fired: DateTime::from(DateTime::parse_from_rfc3339("2022-01-09T10:33:04.391956+01:00").unwrap()),
+                origin: String::from("LOCAL"),
+                context: ContextObject::default(),
+            }
+        }
+    }
+
+    #[test]
+    fn entity_id_state_changed() {
+        let msg = event(EventType::StateChanged,
+            "{\"entity_id\": \"light.kitchen\", \"new_state\": {\"entity_id\": \"light.kitchen\", \"state\": \"on\"}}");
+        assert_eq!(msg.entity_id(), Some("light.kitchen"));
+
+        let msg = event(EventType::StateChanged, "{\"entity_id\": \"light.kitchen\", \"new_state\": null}");
+        assert_eq!(msg.entity_id(), Some("light.kitchen"));
+    }
+
+    #[test]
+    fn entity_id_other_messages() {
+        let msg = event(EventType::CallService, "{\"entity_id\": \"light.kitchen\"}");
+        assert_eq!(msg.entity_id(), None);
+        assert_eq!(WsMessage::Ping { id: 1 }.entity_id(), None);
+    }
+
+    #[test]
+    fn entity_id_malformed() {
+        let msg = event(EventType::StateChanged, "{\"new_state\": {\"entity_id\": 42}}");
+        assert_eq!(msg.entity_id(), None);
+        let msg = event(EventType::StateChanged, "[\"light.kitchen\"]");
+        assert_eq!(msg.entity_id(), None);
+    }
+
+    const TRIGGER_EVENT: &str = "{ \"id\": 2, \"type\": \"event\", \"event\": {
+        \"variables\": {
+            \"trigger\": {
+                \"id\": \"0\",
+                \"idx\": \"0\",
+                \"platform\": \"state\",
+                \"entity_id\": \"binary_sensor.hallway_motion\",
+                \"from_state\": {
+                    \"entity_id\": \"binary_sensor.hallway_motion\",
+                    \"state\": \"off\",
+                    \"attributes\": {\"device_class\": \"motion\"},
+                    \"last_changed\": \"2022-01-09T10:33:04.391956+00:00\",
+                    \"last_updated\": \"2022-01-09T10:33:04.391956+00:00\"
+                },
+                \"to_state\": {
+                    \"entity_id\": \"binary_sensor.hallway_motion\",
+                    \"state\": \"on\",
+                    \"attributes\": {\"device_class\": \"motion\"},
+                    \"last_changed\": \"2022-01-09T10:35:12.000000+00:00\",
+                    \"last_updated\": \"2022-01-09T10:35:12.000000+00:00\"
+                },
+                \"for\": null,
+                \"attribute\": null,
+                \"description\": \"state of binary_sensor.hallway_motion\"
+            }
+        },
+        \"context\": {
+            \"id\": \"9b263f9e4e899819a0515a97f6ddfb47\",
+            \"parent_id\": null,
+            \"user_id\": null
+        }
+    }}";
+
+    #[test]
+    #[traced_test]
+    fn trigger_variables() {
+        let msg = deserialize(TRIGGER_EVENT).unwrap();
+        assert_eq!(deserialize(&serialize(&msg).unwrap()).unwrap(), msg);
+
+        let trigger = msg.as_trigger().unwrap();
+        assert_eq!(trigger.platform, "state");
+        assert_eq!(trigger.entity_id.as_deref(), Some("binary_sensor.hallway_motion"));
+        assert_eq!(trigger.from_state.unwrap()["state"], "off");
+        assert_eq!(trigger.to_state.unwrap()["state"], "on");
+        assert_eq!(trigger.other["description"], "state of binary_sensor.hallway_motion");
+        assert_eq!(trigger.other["for"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn trigger_variables_other_messages() {
+        let msg = event(EventType::StateChanged, "{\"trigger\": {\"platform\": \"state\"}}");
+        assert!(msg.as_trigger().is_none());
+
+        let msg = WsMessage::Event {
+            id: 2,
+            event: EventObj::Trigger {
+                variables: serde_json::from_str("{\"trigger\": {\"entity_id\": \"light.kitchen\"}}").unwrap(),
+                context: ContextObject::default(),
+            }
+        };
+        assert!(msg.as_trigger().is_none());
+    }
+
+    fn state_object(json: &str) -> StateObject {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn entity_state_diff_sequence() {
+        let mut state = state_object("{
+            \"entity_id\": \"light.kitchen\",
+            \"state\": \"off\",
+            \"attributes\": {\"friendly_name\": \"Kitchen\", \"supported_features\": 40},
+            \"last_changed\": \"2022-05-10T23:30:00+00:00\",
+            \"last_updated\": \"2022-05-10T23:30:00+00:00\",
+            \"context\": {\"id\": \"A\", \"parent_id\": null, \"user_id\": null}
+        }");
+        let diffs: Vec<EntityStateDiff> = serde_json::from_str("[
+            {\"+\": {\"s\": \"on\", \"a\": {\"brightness\": 101}, \"lc\": 1652225460.5, \"c\": \"B\"}},
+            {\"+\": {\"a\": {\"brightness\": 200}, \"lu\": 1652225520.25, \"c\": {\"id\": \"C\", \"user_id\": \"U\"}}},
+            {\"-\": {\"a\": [\"supported_features\"]}}
+        ]").unwrap();
+        for diff in &diffs {
+            diff.apply(&mut state);
+        }
+
+        let expected = state_object("{
+            \"entity_id\": \"light.kitchen\",
+            \"state\": \"on\",
+            \"attributes\": {\"friendly_name\": \"Kitchen\", \"brightness\": 200},
+            \"last_changed\": \"2022-05-10T23:31:00.500+00:00\",
+            \"last_updated\": \"2022-05-10T23:32:00.250+00:00\",
+            \"context\": {\"id\": \"C\", \"parent_id\": null, \"user_id\": \"U\"}
+        }");
+        assert_eq!(state, expected);
+    }
+
+    #[test]
+    fn entity_state_diff_last_changed_sets_last_updated() {
+        let mut state = state_object("{
+            \"entity_id\": \"sensor.power\",
+            \"state\": \"10\",
+            \"last_changed\": \"2022-05-10T23:30:00+00:00\",
+            \"last_updated\": \"2022-05-10T23:31:00+00:00\",
+            \"context\": {\"id\": \"A\", \"parent_id\": null, \"user_id\": null}
+        }");
+        let diff: EntityStateDiff = serde_json::from_str("{\"+\": {\"s\": \"12\", \"a\": {\"unit_of_measurement\": \"W\"}, \"lc\": 1652225400}}").unwrap();
+        diff.apply(&mut state);
+        assert_eq!(state.state, "12");
+        assert_eq!(state.attributes["unit_of_measurement"], "W");
+        assert_eq!(state.last_changed, state.last_updated);
+        assert_eq!(state.last_changed.to_rfc3339(), "2022-05-10T23:30:00+00:00");
+    }
+
+    #[test]
+    fn state_changed_data() {
+        let state = |state: &str| format!("{{\"entity_id\": \"light.kitchen\", \"state\": \"{}\",
+            \"last_changed\": \"2022-05-10T23:30:00+00:00\", \"last_updated\": \"2022-05-10T23:30:00+00:00\",
+            \"context\": {{\"id\": \"A\", \"parent_id\": null, \"user_id\": null}}}}", state);
+        let msg = event(EventType::StateChanged, &format!("{{\"entity_id\": \"light.kitchen\", \"old_state\": {}, \"new_state\": {}}}", state("off"), state("on")));
+        let data = msg.as_state_changed().unwrap();
+        assert_eq!(data.entity_id, "light.kitchen");
+        assert_eq!(data.old_state.unwrap().state, "off");
+        assert_eq!(data.new_state.unwrap().state, "on");
+
+        let msg = event(EventType::StateChanged, &format!("{{\"entity_id\": \"light.kitchen\", \"old_state\": {}, \"new_state\": null}}", state("off")));
+        assert!(msg.as_state_changed().unwrap().new_state.is_none());
+
+        let msg = event(EventType::StateChanged, "{\"entity_id\": \"light.kitchen\", \"new_state\": {\"state\": \"on\"}}");
+        assert!(msg.as_state_changed().is_none());
+        let msg = event(EventType::CallService, "{\"entity_id\": \"light.kitchen\"}");
+        assert!(msg.as_state_changed().is_none());
+    }
+
+    const CALL_SERVICE_EVENT: &str = "{
+        \"id\": 18,
+        \"type\": \"event\",
+        \"event\": {
+            \"event_type\": \"call_service\",
+            \"data\": {
+                \"domain\": \"light\",
+                \"service\": \"turn_on\",
+                \"service_data\": {\"entity_id\": \"light.kitchen\", \"brightness_pct\": 40}
+            },
+            \"origin\": \"LOCAL\",
+            \"time_fired\": \"2022-01-09T09:33:04.391956Z\",
+            \"context\": {\"id\": \"01GBZ6NQ0K7XJ1A1H6Z2WQ9F8C\", \"parent_id\": null, \"user_id\": \"4c7f2e4b9d\"}
+        }
+    }";
+
+    serde_test!(msg_event_call_service,
+        WsMessage::Event {
+            id: 18,
+            event: EventObj::Event {
+                data: serde_json::json!({
+                    "domain": "light",
+                    "service": "turn_on",
+                    "service_data": {"entity_id": "light.kitchen", "brightness_pct": 40},
+                }),
+                event_type: EventType::CallService,
+                time_fired: "2022-01-09T09:33:04.391956Z".parse().unwrap(),
+                origin: String::from("LOCAL"),
+                context: ContextObject {
+                    id: String::from("01GBZ6NQ0K7XJ1A1H6Z2WQ9F8C"),
+                    parent_id: None,
+                    user_id: Some(String::from("4c7f2e4b9d")),
+                },
+            },
+        },
+        CALL_SERVICE_EVENT);
+
+    #[test]
+    fn call_service_data() {
+        let msg = deserialize(CALL_SERVICE_EVENT).unwrap();
+        let data = msg.as_call_service().unwrap();
+        assert_eq!(data.domain, "light");
+        assert_eq!(data.service, "turn_on");
+        assert_eq!(data.service_data["entity_id"], "light.kitchen");
+        assert_eq!(data.service_data["brightness_pct"], 40);
+
+        // service_data is optional
+        let msg = event(EventType::CallService, "{\"domain\": \"homeassistant\", \"service\": \"restart\"}");
+        assert!(msg.as_call_service().unwrap().service_data.is_empty());
+
+        let msg = event(EventType::CallService, "{\"domain\": \"light\"}");
+        assert!(msg.as_call_service().is_none());
+        let msg = event(EventType::StateChanged, "{\"domain\": \"light\", \"service\": \"turn_on\"}");
+        assert!(msg.as_call_service().is_none());
+    }
+}
@@ -0,0 +1,188 @@
+//! Context Tracing
+//!
+//! HA tags events and service calls with a [ContextObject], whose `parent_id`
+//! refers to the context that caused them, e.g. the state change triggering an
+//! automation, which in turn calls a service. This module indexes contexts to
+//! follow such chains back to their root cause.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{ContextObject, WsMessage};
+
+/// Contexts carried by a set of messages, indexed by their id.
+#[derive(Default, Debug)]
+pub struct ContextIndex {
+    contexts: HashMap<String, ContextObject>,
+}
+
+impl ContextIndex {
+    /// Indexes the contexts of `msgs`. When several messages share the same
+    /// context, the first one stands for it.
+    pub fn new<'a>(msgs: impl IntoIterator<Item = &'a WsMessage>) -> ContextIndex {
+        let mut index = ContextIndex::default();
+        for msg in msgs {
+            if let Some(context) = msg.context() {
+                index.insert(context.clone());
+            }
+        }
+        index
+    }
+
+    /// Adds `context` to the index, unless one with the same id already is.
+    pub fn insert(&mut self, context: ContextObject) {
+        self.contexts.entry(context.id.clone()).or_insert(context);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ContextObject> {
+        self.contexts.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+
+    /// Follows the `parent_id` links from the context `id` up to its root cause,
+    /// returning the contexts met along the way, starting from `id` itself.
+    ///
+    /// The trace stops at the first parent not found in the index, and before
+    /// visiting a context twice should the links form a cycle. It is empty if
+    /// `id` is not indexed. See [context_chain()] for the messages
+    /// carrying the contexts instead.
+    pub fn trace_to_root(&self, id: &str) -> Vec<&ContextObject> {
+        let mut trace = Vec::new();
+        let mut visited = HashSet::new();
+        let mut next = self.contexts.get(id);
+        while let Some(context) = next {
+            if !visited.insert(context.id.as_str()) {
+                tracing::warn!("trace_to_root: cycle found at context id={}", context.id);
+                break;
+            }
+            trace.push(context);
+            next = context.parent_id.as_deref().and_then(|parent_id| self.contexts.get(parent_id));
+        }
+        trace
+    }
+
+    /// Returns the root cause of the context `id`, i.e. the last context of
+    /// [ContextIndex::trace_to_root()], if `id` is indexed.
+    pub fn root(&self, id: &str) -> Option<&ContextObject> {
+        self.trace_to_root(id).pop()
+    }
+}
+
+/// Reconstructs the causal chain leading to `msg` by following the `parent_id`
+/// of its context across `events`, e.g. the state change that triggered an
+/// automation, which in turn called the service that changed the state of `msg`.
+///
+/// The chain is returned from its root cause down to `msg` itself, and is built
+/// out of [ContextIndex::trace_to_root()]: it stops at the first parent not
+/// found in `events`, and before visiting a context twice. When several events
+/// share the same context, the earliest one in `events` stands for it.
+pub fn context_chain<'a>(events: &'a [WsMessage], msg: &'a WsMessage) -> Vec<&'a WsMessage> {
+    let Some(context) = msg.context() else {
+        return vec![msg];
+    };
+    let index = ContextIndex::new(std::iter::once(msg).chain(events));
+    let mut chain: Vec<&WsMessage> = index.trace_to_root(&context.id)
+        .into_iter()
+        .skip(1)
+        .filter_map(|parent| events.iter().find(|ev| ev.context().is_some_and(|c| c.id == parent.id)))
+        .collect();
+    chain.reverse();
+    chain.push(msg);
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{EventObj, EventType, ResultBody, ResultObject};
+
+    fn context(id: &str, parent_id: Option<&str>) -> ContextObject {
+        ContextObject {
+            id: id.to_owned(),
+            parent_id: parent_id.map(str::to_owned),
+            user_id: None,
+        }
+    }
+
+    fn call_service_result(context: ContextObject) -> WsMessage {
+        WsMessage::Result {
+            id: 1,
+            success: true,
+            data: ResultBody::Result {
+                result: Some(ResultObject::Object { context }),
+            },
+        }
+    }
+
+    fn event_with_context(id: &str, parent_id: Option<&str>) -> WsMessage {
+        WsMessage::Event {
+            id: 1,
+            event: EventObj::Event {
+                data: serde_json::json!({}),
+                event_type: EventType::StateChanged,
+                time_fired: chrono::DateTime::<chrono::Utc>::from_timestamp(1_652_225_690, 0).unwrap(),
+                origin: "LOCAL".to_owned(),
+                context: context(id, parent_id),
+            },
+        }
+    }
+
+    #[test]
+    fn three_level_chain() {
+        // Motion triggers an automation, which turns on a light
+        let msgs = vec![
+            call_service_result(context("light", Some("automation"))),
+            call_service_result(context("motion", None)),
+            call_service_result(context("automation", Some("motion"))),
+            call_service_result(context("unrelated", Some("missing"))),
+        ];
+        let index = ContextIndex::new(&msgs);
+        assert_eq!(index.len(), 4);
+
+        let ids: Vec<&str> = index.trace_to_root("light").iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, ["light", "automation", "motion"]);
+        assert_eq!(index.root("automation"), Some(&context("motion", None)));
+        assert_eq!(index.trace_to_root("unrelated").len(), 1);
+        assert!(index.trace_to_root("missing").is_empty());
+        assert_eq!(index.root("missing"), None);
+    }
+
+    #[test]
+    fn cycle() {
+        let mut index = ContextIndex::default();
+        index.insert(context("a", Some("b")));
+        index.insert(context("b", Some("c")));
+        index.insert(context("c", Some("a")));
+        let ids: Vec<&str> = index.trace_to_root("a").iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn context_chain_order() {
+        let state_changed = event_with_context("A", None);
+        let automation = event_with_context("B", Some("A"));
+        let call_service = event_with_context("C", Some("B"));
+        let unrelated = event_with_context("D", Some("Z"));
+        let events = vec![unrelated.clone(), call_service.clone(), state_changed.clone(), automation.clone()];
+
+        let chain = context_chain(&events, &call_service);
+        assert_eq!(chain, vec![&state_changed, &automation, &call_service]);
+        assert!(automation.context().unwrap().is_child_of(state_changed.context().unwrap()));
+
+        assert_eq!(context_chain(&events, &unrelated), vec![&unrelated]);
+    }
+
+    #[test]
+    fn context_chain_loop() {
+        let a = event_with_context("A", Some("B"));
+        let b = event_with_context("B", Some("A"));
+        let events = vec![a.clone(), b.clone()];
+        assert_eq!(context_chain(&events, &a), vec![&b, &a]);
+    }
+}
@@ -1,11 +1,16 @@
+// `Error` carries whole `WsMessage`s in some of its variants
+#![allow(clippy::result_large_err)]
+
 pub mod sync;
 
 pub mod pirengine;
 pub mod json;
 pub mod wsapi;
 pub mod error;
+pub mod stream;
+pub mod logging;
 
-#[cfg(any(feature = "hast-client", feature = "hast-server", test))]
+#[cfg(any(feature = "hast-client", feature = "hast-server", feature = "scenario", test))]
 pub mod hast;
 
 
@@ -0,0 +1,105 @@
+//! Logging whose filter may be changed at runtime
+//!
+//! Long-running binaries, e.g. `haevlo` and `piresence`, set up logging with
+//! [init()] and replace the `RUST_LOG` filter whenever they receive a
+//! `piresence_loglevel` control event with a `level` field in its data, e.g.
+//! `{"level": "debug"}`, see [set_log_level()].
+use serde_json::Value;
+use tokio::sync::mpsc::Receiver;
+use tracing_subscriber::{prelude::*, reload, EnvFilter, Registry};
+
+use crate::json::{EventObj, EventType, WsMessage};
+
+/// Handle to the logging filter set up by [init()].
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Initializes the logging framework with the filter found in `RUST_LOG`,
+/// returning a handle to change it at runtime.
+pub fn init() -> LogHandle {
+    let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    handle
+}
+
+/// Replaces the logging filter with the directives found in the `level`
+/// field of the control event `data`, returning the new filter.
+pub fn set_log_level(handle: &LogHandle, data: &Value) -> Result<String, String> {
+    let level = data.get("level")
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("missing level in event data: {}", data))?;
+    let filter = EnvFilter::try_new(level)
+        .map_err(|e| format!("invalid level {}: {}", level, e))?;
+    let applied = filter.to_string();
+    handle.reload(filter).map_err(|e| e.to_string())?;
+    Ok(applied)
+}
+
+/// Applies the `piresence_loglevel` events received from `events` to the
+/// logging filter of `handle`, ignoring any other message.
+///
+/// Returns when `events` gets closed.
+pub async fn follow(handle: LogHandle, mut events: Receiver<WsMessage>) {
+    while let Some(msg) = events.recv().await {
+        let WsMessage::Event { event: EventObj::Event { event_type: EventType::PiresenceLoglevel, data, .. }, .. } = msg else {
+            continue;
+        };
+        match set_log_level(&handle, &data) {
+            Ok(level) => tracing::info!("piresence_loglevel event: log level set to {}", level),
+            Err(e) => tracing::error!("piresence_loglevel event: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::ContextObject;
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use tracing::Level;
+
+    #[test]
+    fn loglevel_control_event() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _guard = tracing_subscriber::registry().with(filter).set_default();
+        assert!(tracing::enabled!(Level::INFO));
+        assert!(!tracing::enabled!(Level::DEBUG));
+
+        assert_eq!(set_log_level(&handle, &json!({"level": "debug"})).unwrap(), "debug");
+        assert!(tracing::enabled!(Level::DEBUG));
+        assert!(!tracing::enabled!(Level::TRACE));
+
+        // Malformed events leave the filter untouched
+        assert!(set_log_level(&handle, &json!({"lvl": "trace"})).is_err());
+        assert!(set_log_level(&handle, &json!({"level": "[not a filter"})).is_err());
+        assert!(tracing::enabled!(Level::DEBUG));
+        assert!(!tracing::enabled!(Level::TRACE));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn follow_loglevel_events() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _guard = tracing_subscriber::registry().with(filter).set_default();
+        let event = |event_type, data| WsMessage::Event {
+            id: 1,
+            event: EventObj::Event {
+                data,
+                event_type,
+                time_fired: chrono::Utc::now(),
+                origin: "LOCAL".to_owned(),
+                context: ContextObject::default(),
+            },
+        };
+
+        let (tx, rx) = mpsc::channel(4);
+        tx.send(event(EventType::StateChanged, json!({"level": "trace"}))).await.unwrap();
+        tx.send(event(EventType::PiresenceLoglevel, json!({"level": "debug"}))).await.unwrap();
+        drop(tx);
+        follow(handle, rx).await;
+        assert!(tracing::enabled!(Level::DEBUG));
+        assert!(!tracing::enabled!(Level::TRACE));
+    }
+}
@@ -3,4 +3,4 @@
 //! Contains data, tools, and the event processor engine itself that
 //! perform PIR-based room presence detection.
 
-pub mod home;
\ No newline at end of file
+pub mod home;
@@ -4,17 +4,62 @@
 //! the home, how they're connected to each other, and which smart devices
 //! they do contain.
 
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
 pub type AreaId = String;
 
+/// Estimate of how many people are in an area.
+///
+/// Estimates are ordered from the least to the most occupied, so that e.g. the
+/// [Ord::max()] of those of several sensors is the most confident occupancy:
+/// `NoOne < AtMost(_) < AtLeast(_)`, with ties between `AtMost` or `AtLeast`
+/// broken by their count.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
 pub enum Presence {
     NoOne,
     AtLeast(u8),
     AtMost(u8),
 }
 
+impl Presence {
+    /// Rank of the variant in the ordering of estimates, followed by the count.
+    fn rank(&self) -> (u8, u8) {
+        match *self {
+            Presence::NoOne => (0, 0),
+            Presence::AtMost(n) => (1, n),
+            Presence::AtLeast(n) => (2, n),
+        }
+    }
+}
+
+impl Ord for Presence {
+    fn cmp(&self, other: &Presence) -> Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
+impl PartialOrd for Presence {
+    fn partial_cmp(&self, other: &Presence) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 pub struct Area {
     id: String,
     pub presence_esimate: Presence,
+    /// When someone was last detected in the area, if ever
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Entity ids of the HA sensors detecting activity in the area
+    sensors: Vec<String>,
 }
 
 impl Area {
@@ -22,12 +67,231 @@ impl Area {
         Area {
             id: id.to_owned(),
             presence_esimate: Presence::NoOne,
+            last_seen: None,
+            sensors: Vec::new(),
         }
     }
 
+    /// Adds the HA sensor `entity_id` to those detecting activity in the area.
+    pub fn with_sensor(mut self, entity_id: &str) -> Area {
+        self.sensors.push(entity_id.to_owned());
+        self
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
+
+    pub fn sensors(&self) -> &[String] {
+        &self.sensors
+    }
+
+    /// Returns how much someone is still believed to be in the area at `now`,
+    /// from `1.0` when just seen down to `0.0` when never seen, halving every
+    /// `half_life` since [Area::last_seen].
+    pub fn confidence(&self, now: DateTime<Utc>, half_life: Duration) -> f64 {
+        let Some(last_seen) = self.last_seen else {
+            return 0.0;
+        };
+        let elapsed = (now - last_seen).to_std().unwrap_or_default();
+        0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64())
+    }
+}
+
+/// The home, i.e. its areas and how they are connected to each other.
+pub struct Home {
+    areas: VecGraph<Area>,
+}
+
+impl From<VecGraph<Area>> for Home {
+    fn from(areas: VecGraph<Area>) -> Home {
+        Home { areas }
+    }
+}
+
+/// Presence state of an [Area], as persisted by [Home::save_snapshot()].
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct AreaSnapshot {
+    presence: Presence,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+impl Home {
+    /// Creates a new home with room for up to `capacity` areas.
+    pub fn new(capacity: usize) -> Home {
+        Home {
+            areas: VecGraph::new_undirected(capacity),
+        }
+    }
+
+    /// Adds the `area` to the home, returning its node in the graph of areas,
+    /// or `None` if the home is already at capacity.
+    pub fn add_area(&mut self, area: Area) -> Option<NodeId> {
+        self.areas.add_node(area)
+    }
+
+    /// Connects two areas, e.g. when there's a door between them.
+    pub fn connect(&mut self, from: NodeId, to: NodeId) {
+        self.areas.add_edge(from, to);
+    }
+
+    pub fn area(&self, id: &str) -> Option<&Area> {
+        self.areas.find_node_id(|a| a.id == id)
+            .map(|node_id| self.areas.get_node(node_id))
+    }
+
+    pub fn area_mut(&mut self, id: &str) -> Option<&mut Area> {
+        self.areas.find_node_id(|a| a.id == id)
+            .map(|node_id| self.areas.get_node_mut(node_id))
+    }
+
+    pub fn graph(&self) -> &VecGraph<Area> {
+        &self.areas
+    }
+
+    /// Returns the graph of the areas, dropping the home.
+    pub fn into_graph(self) -> VecGraph<Area> {
+        self.areas
+    }
+
+    /// Persists the presence estimates and last-seen timestamps of all areas
+    /// as JSON to the file at `path`.
+    ///
+    /// The snapshot is written to a temporary file next to `path` first, then
+    /// renamed into place, so that a crash halfway never leaves it truncated.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let snapshot: BTreeMap<&str, AreaSnapshot> = (0..self.areas.node_count())
+            .map(|node_id| self.areas.get_node(node_id))
+            .map(|area| (area.id(), AreaSnapshot { presence: area.presence_esimate, last_seen: area.last_seen }))
+            .collect();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&snapshot)?)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Restores the state saved by [Home::save_snapshot()] from the file at `path`
+    /// into the areas of the home with a matching id, returning whether it
+    /// succeeded.
+    ///
+    /// A missing or corrupt snapshot leaves the home as it is, so that it starts
+    /// fresh: that is not an error, only a warning is logged.
+    pub fn load_snapshot(&mut self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        let snapshot: BTreeMap<AreaId, AreaSnapshot> = match fs::read(path) {
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    tracing::warn!("ignoring corrupt snapshot {}: {}", path.display(), e);
+                    return false;
+                },
+            },
+            Err(e) => {
+                tracing::warn!("ignoring snapshot {}: {}", path.display(), e);
+                return false;
+            },
+        };
+        for (id, state) in snapshot {
+            match self.area_mut(&id) {
+                Some(area) => {
+                    area.presence_esimate = state.presence;
+                    area.last_seen = state.last_seen;
+                },
+                None => tracing::warn!("snapshot {}: ignoring unknown area {}", path.display(), id),
+            }
+        }
+        true
+    }
+}
+
+/// Layout of a home as described in YAML files, see [load_home()].
+///
+/// ```yaml
+/// areas:
+///   - id: entrance
+///   - id: living room
+/// edges:
+///   - [entrance, living room]
+/// ```
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct HomeLayout {
+    pub areas: Vec<AreaSpec>,
+    /// Connected areas, by id
+    #[serde(default)]
+    pub edges: Vec<(AreaId, AreaId)>,
+}
+
+/// An area of a [HomeLayout].
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct AreaSpec {
+    pub id: AreaId,
+    /// Entity ids of the sensors in the area, see [Area::with_sensor()]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sensors: Vec<String>,
+}
+
+impl HomeLayout {
+    /// Builds the graph of the areas, failing when an edge names an unknown area
+    /// or several areas share the same id.
+    pub fn to_graph(&self) -> Result<VecGraph<Area>, String> {
+        let mut graph = VecGraph::new_undirected(self.areas.len());
+        for spec in &self.areas {
+            if graph.find_node_id(|a: &Area| a.id == spec.id).is_some() {
+                return Err(format!("duplicate area: {}", spec.id));
+            }
+            let area = spec.sensors.iter().fold(Area::new(&spec.id), |area, sensor| area.with_sensor(sensor));
+            graph.add_node(area);
+        }
+        for (from, to) in &self.edges {
+            let node_id = |id: &str| graph.find_node_id(|a| a.id == id)
+                .ok_or_else(|| format!("edge {} - {}: unknown area {}", from, to, id));
+            let (from, to) = (node_id(from)?, node_id(to)?);
+            graph.add_edge(from, to);
+        }
+        Ok(graph)
+    }
+
+    /// Describes the areas of `graph` and how they are connected.
+    pub fn from_graph(graph: &VecGraph<Area>) -> HomeLayout {
+        let areas = (0..graph.node_count())
+            .map(|node_id| graph.get_node(node_id))
+            .map(|area| AreaSpec { id: area.id.clone(), sensors: area.sensors.clone() })
+            .collect();
+        let edges = (0..graph.node_count())
+            .flat_map(|from| graph.neighbours(from).into_iter()
+                .filter(move |to| *to > from)
+                .map(move |to| (from, to)))
+            .map(|(from, to)| (graph.get_node(from).id.clone(), graph.get_node(to).id.clone()))
+            .collect();
+        HomeLayout { areas, edges }
+    }
+}
+
+/// Reads the [HomeLayout] stored as YAML in the file at `path` and builds the
+/// graph of its areas.
+#[cfg(feature = "serde_yaml")]
+pub fn load_home(path: &str) -> io::Result<VecGraph<Area>> {
+    let layout: HomeLayout = serde_yaml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?;
+    layout.to_graph()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))
+}
+
+/// Saves the areas of `graph` and how they are connected as a YAML [HomeLayout]
+/// to the file at `path`, to be read back by [load_home()].
+#[cfg(feature = "serde_yaml")]
+pub fn save_home(graph: &VecGraph<Area>, path: &str) -> io::Result<()> {
+    let yaml = serde_yaml::to_string(&HomeLayout::from_graph(graph))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(path, yaml)
+}
+
+impl VecGraph<Area> {
+    /// Returns the node of the area the HA sensor `entity_id` belongs to, if any.
+    pub fn area_for_sensor(&self, entity_id: &str) -> Option<NodeId> {
+        self.find_node_id(|area| area.sensors.iter().any(|sensor| sensor == entity_id))
+    }
 }
 
 pub type NodeId = usize;
@@ -73,7 +337,11 @@ impl<N> VecGraph<N> {
     }
 
     pub fn get_node(&self, node_id: NodeId) -> &N {
-        &self.nodes[node_id as usize]
+        &self.nodes[node_id]
+    }
+
+    pub fn get_node_mut(&mut self, node_id: NodeId) -> &mut N {
+        &mut self.nodes[node_id]
     }
 
     pub fn add_edge(&mut self, from: NodeId, to: NodeId) {
@@ -117,6 +385,35 @@ impl<N> VecGraph<N> {
         ns
     }
 
+    /// Returns one of the shortest sequences of nodes leading from `from` to `to`,
+    /// both included, or `None` if they are not connected.
+    pub fn shortest_path(&self, from: NodeId, to: NodeId) -> Option<Vec<NodeId>> {
+        let mut previous: Vec<Option<NodeId>> = vec![None; self.nodes.len()];
+        let mut visited = vec![false; self.nodes.len()];
+        let mut queue = VecDeque::from([from]);
+        visited[from] = true;
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut path = vec![to];
+                let mut current = to;
+                while let Some(prev) = previous[current] {
+                    path.push(prev);
+                    current = prev;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for next in self.neighbours(node) {
+                if !visited[next] {
+                    visited[next] = true;
+                    previous[next] = Some(node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
 }
 
 
@@ -178,4 +475,125 @@ mod tests {
 
         assert!(home.neighbours(id_kitchen).is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn shortest_path() {
+        let mut home = VecGraph::<Room>::new_undirected(3);
+        let entrance = home.add_node(Room::new("entrance")).unwrap();
+        let living = home.add_node(Room::new("living room")).unwrap();
+        let kitchen = home.add_node(Room::new("kitchen")).unwrap();
+        home.add_edge(entrance, living);
+        home.add_edge(living, kitchen);
+
+        assert_eq!(home.shortest_path(entrance, kitchen), Some(vec![entrance, living, kitchen]));
+        assert_eq!(home.shortest_path(kitchen, entrance), Some(vec![kitchen, living, entrance]));
+        assert_eq!(home.shortest_path(living, living), Some(vec![living]));
+
+        home.remove_edge(living, kitchen);
+        assert_eq!(home.shortest_path(entrance, kitchen), None);
+    }
+
+    fn small_home() -> Home {
+        let mut home = Home::new(3);
+        let entrance = home.add_area(Area::new("entrance").with_sensor("binary_sensor.hall_motion")).unwrap();
+        let living = home.add_area(Area::new("living room")).unwrap();
+        let kitchen = home.add_area(Area::new("kitchen")).unwrap();
+        home.connect(entrance, living);
+        home.connect(living, kitchen);
+        home
+    }
+
+    #[test]
+    fn area_for_sensor() {
+        let home = small_home();
+        let entrance = home.graph().area_for_sensor("binary_sensor.hall_motion").unwrap();
+        assert_eq!(home.graph().get_node(entrance).id(), "entrance");
+        assert_eq!(home.graph().get_node(entrance).sensors(), ["binary_sensor.hall_motion"]);
+        assert!(home.graph().area_for_sensor("binary_sensor.garage_motion").is_none());
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.json");
+        let seen: DateTime<Utc> = "2022-05-10T23:34:50.163029Z".parse().unwrap();
+
+        let mut home = small_home();
+        let living = home.area_mut("living room").unwrap();
+        living.presence_esimate = Presence::AtLeast(2);
+        living.last_seen = Some(seen);
+        home.area_mut("kitchen").unwrap().presence_esimate = Presence::AtMost(1);
+        home.save_snapshot(&path).unwrap();
+
+        let mut restored = small_home();
+        assert!(restored.load_snapshot(&path));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1, "temporary file left behind");
+        for area in ["entrance", "living room", "kitchen"] {
+            let (expected, actual) = (home.area(area).unwrap(), restored.area(area).unwrap());
+            assert_eq!(expected.presence_esimate, actual.presence_esimate);
+            assert_eq!(expected.last_seen, actual.last_seen);
+        }
+        assert_eq!(restored.area("living room").unwrap().last_seen, Some(seen));
+    }
+
+    #[test]
+    #[cfg(feature = "serde_yaml")]
+    fn home_layout_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("layout.yaml");
+        let path = path.to_str().unwrap();
+        let home = small_home();
+        save_home(home.graph(), path).unwrap();
+        let graph = load_home(path).unwrap();
+
+        assert_eq!(HomeLayout::from_graph(&graph), HomeLayout::from_graph(home.graph()));
+        let entrance = graph.find_node_id(|a| a.id() == "entrance").unwrap();
+        let living = graph.find_node_id(|a| a.id() == "living room").unwrap();
+        let kitchen = graph.find_node_id(|a| a.id() == "kitchen").unwrap();
+        assert_eq!(graph.neighbours(living), vec![entrance, kitchen]);
+        assert_eq!(graph.area_for_sensor("binary_sensor.hall_motion"), Some(entrance));
+    }
+
+    #[test]
+    #[cfg(feature = "serde_yaml")]
+    fn home_layout_unknown_area() {
+        let layout: HomeLayout = serde_yaml::from_str("
+            areas:
+              - id: entrance
+              - id: kitchen
+            edges:
+              - [entrance, garage]
+        ").unwrap();
+        let err = layout.to_graph().err().unwrap();
+        assert_eq!(err, "edge entrance - garage: unknown area garage");
+
+        let layout: HomeLayout = serde_yaml::from_str("areas: [{id: kitchen}, {id: kitchen}]").unwrap();
+        assert!(layout.to_graph().is_err());
+    }
+
+    #[test]
+    fn snapshot_missing_or_corrupt() {
+        let mut home = small_home();
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!home.load_snapshot(dir.path().join("no-such-snapshot.json")));
+
+        let path = dir.path().join("corrupt-snapshot.json");
+        fs::write(&path, "{ \"kitchen\": ").unwrap();
+        assert!(!home.load_snapshot(&path));
+        assert_eq!(home.area("kitchen").unwrap().presence_esimate, Presence::NoOne);
+    }
+
+    #[test]
+    fn presence_ordering() {
+        use Presence::*;
+        let ordered = [NoOne, AtMost(0), AtMost(1), AtMost(3), AtLeast(0), AtLeast(1), AtLeast(2)];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(a.cmp(b), i.cmp(&j), "{:?} vs {:?}", a, b);
+            }
+        }
+        assert!(AtMost(200) < AtLeast(1));
+        assert_eq!([AtMost(2), NoOne, AtLeast(1)].into_iter().max(), Some(AtLeast(1)));
+        assert_eq!(AtLeast(2).partial_cmp(&AtLeast(2)), Some(Ordering::Equal));
+    }
+}
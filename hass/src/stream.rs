@@ -0,0 +1,125 @@
+//! Stream combinators for event receivers
+//!
+//! Helpers turning the raw `mpsc::Receiver`s handed out by [crate::WsApi]
+//! into [Stream]s with higher-level semantics.
+
+use std::mem;
+use std::time::Duration;
+
+use futures_util::stream::{self, Stream};
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+/// What [window()] should do about time windows during which nothing arrived.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EmptyWindows {
+    /// Emit an empty `Vec` for each of them.
+    Emit,
+    /// Emit nothing, silently moving on to the next window.
+    Skip,
+}
+
+/// Batches the items received from `rx` over consecutive time windows lasting
+/// `period` each, emitting every batch as a `Vec` as soon as its window closes.
+///
+/// The first window starts when `window()` is called, so it must be called
+/// from within a tokio runtime. The stream ends when `rx` is closed, right
+/// after emitting the last, possibly shorter, window if anything arrived in it.
+pub fn window<T>(rx: mpsc::Receiver<T>, period: Duration, empty: EmptyWindows) -> impl Stream<Item = Vec<T>> {
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let state = Window {
+        rx: Some(rx),
+        interval,
+        empty,
+        batch: Vec::new(),
+    };
+    stream::unfold(state, |mut state| async move {
+        state.next_batch().await.map(|batch| (batch, state))
+    })
+}
+
+struct Window<T> {
+    rx: Option<mpsc::Receiver<T>>,
+    interval: Interval,
+    empty: EmptyWindows,
+    batch: Vec<T>,
+}
+
+impl<T> Window<T> {
+    async fn next_batch(&mut self) -> Option<Vec<T>> {
+        loop {
+            let rx = self.rx.as_mut()?;
+            tokio::select! {
+                item = rx.recv() => match item {
+                    Some(item) => self.batch.push(item),
+                    None => {
+                        self.rx = None;
+                        return if self.batch.is_empty() {
+                            None
+                        } else {
+                            Some(mem::take(&mut self.batch))
+                        };
+                    },
+                },
+
+                _ = self.interval.tick() => {
+                    if !self.batch.is_empty() || self.empty == EmptyWindows::Emit {
+                        return Some(mem::take(&mut self.batch));
+                    }
+                },
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    /// Sends `items` on a new channel, each after the given delay from the start.
+    fn timed_channel(items: &[(u64, u32)]) -> mpsc::Receiver<u32> {
+        let (tx, rx) = mpsc::channel(16);
+        let items = items.to_vec();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            for (delay_ms, item) in items {
+                time::sleep_until(start + Duration::from_millis(delay_ms)).await;
+                tx.send(item).await.unwrap();
+            }
+        });
+        rx
+    }
+
+    const ITEMS: [(u64, u32); 4] = [(10, 1), (20, 2), (150, 3), (350, 4)];
+
+    #[tokio::test(start_paused = true)]
+    async fn window_skip_empty() {
+        let rx = timed_channel(&ITEMS);
+        let batches: Vec<_> = window(rx, Duration::from_millis(100), EmptyWindows::Skip)
+            .collect()
+            .await;
+        assert_eq!(batches, vec![vec![1, 2], vec![3], vec![4]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_emit_empty() {
+        let rx = timed_channel(&ITEMS);
+        let batches: Vec<_> = window(rx, Duration::from_millis(100), EmptyWindows::Emit)
+            .collect()
+            .await;
+        assert_eq!(batches, vec![vec![1, 2], vec![3], vec![], vec![4]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_closed_empty() {
+        let (tx, rx) = mpsc::channel::<u32>(1);
+        drop(tx);
+        let batches: Vec<_> = window(rx, Duration::from_millis(100), EmptyWindows::Emit)
+            .collect()
+            .await;
+        assert!(batches.is_empty());
+    }
+}
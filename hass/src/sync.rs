@@ -4,4 +4,6 @@
 //! async rust projects using `hast` easier.
 
 pub mod atomic;
+pub mod clock;
+pub mod runtime;
 pub mod shutdown;
@@ -1,43 +1,118 @@
 //! Atomic data types for shared memory that do not use locking
-//! 
+//!
 //! The structs and types provided by this module use Rust atomic
 //! types for providing shared memory access to raw types used by
 //! `hass`.
-use std::sync::atomic::{self, AtomicU64};
-use crate::json::Id;
+use std::sync::atomic::{self, AtomicU32, AtomicU64};
+
+/// Atomic integer types an [AtomicIdOf] generator may be built upon.
+pub trait AtomicInteger {
+    /// The plain integer type wrapped by the atomic one.
+    type Value: Copy;
+
+    /// The first identifier generated.
+    const FIRST: Self::Value;
+
+    /// Creates a new atomic integer holding `value`.
+    fn with_value(value: Self::Value) -> Self;
+
+    /// Loads the current value.
+    fn load_value(&self) -> Self::Value;
+
+    /// Stores `value`, replacing the current one.
+    fn store_value(&self, value: Self::Value);
+
+    /// Increments the current value, wrapping around on overflow, and
+    /// returns the previous one.
+    fn fetch_increment(&self) -> Self::Value;
+}
+
+macro_rules! atomic_integer {
+    ($atomic:ty, $value:ty) => {
+        impl AtomicInteger for $atomic {
+            type Value = $value;
+
+            const FIRST: $value = 1;
+
+            fn with_value(value: $value) -> Self {
+                <$atomic>::new(value)
+            }
+
+            fn load_value(&self) -> $value {
+                self.load(atomic::Ordering::SeqCst)
+            }
+
+            fn store_value(&self, value: $value) {
+                self.store(value, atomic::Ordering::SeqCst)
+            }
+
+            fn fetch_increment(&self) -> $value {
+                self.fetch_add(1, atomic::Ordering::SeqCst)
+            }
+        }
+    };
+}
+
+atomic_integer!(AtomicU64, u64);
+atomic_integer!(AtomicU32, u32);
 
 /// An atomic unique [crate::json::Id] generator.
-/// 
+///
 /// Lightweight atomic counter that generates [crate::json::Id]
 /// incrementally, starting from `0`.
+pub type AtomicId = AtomicIdOf<AtomicU64>;
+
+/// An atomic unique identifier generator over a 32 bits space.
+///
+/// Same as [AtomicId], but wrapping around after `u32::MAX`.
+pub type AtomicId32 = AtomicIdOf<AtomicU32>;
+
+/// An atomic unique identifier generator, generic over the width of
+/// its identifiers.
+///
+/// Usually referred to via either [AtomicId] or [AtomicId32].
 #[derive(Debug)]
-pub struct AtomicId(AtomicU64);
+pub struct AtomicIdOf<A: AtomicInteger>(A);
 
-impl AtomicId {
-    /// Create a new `AtomicId`.
-    /// 
+impl<A: AtomicInteger> AtomicIdOf<A> {
+    /// Create a new `AtomicIdOf`.
+    ///
     /// The sequence of identifiers starts from `0`.
-    pub fn new() -> AtomicId {
-        AtomicId(AtomicU64::new(1))
+    pub fn new() -> Self {
+        AtomicIdOf(A::with_value(A::FIRST))
+    }
+
+    /// Returns the next unique identifier.
+    ///
+    /// After reaching the maximum value of its integer type, `next()`
+    /// will start over from `0`.
+    pub fn next(&self) -> A::Value {
+        self.0.fetch_increment()
     }
 
-    /// Returns the next unique `Id`.
-    /// 
-    /// After reaching `u64::MAX`, `next()` will start over from `0`.
-    pub fn next(&self) -> Id {
-        self.0.fetch_add(1, atomic::Ordering::SeqCst) as Id
+    /// Returns the identifier the next call to [AtomicIdOf::next()] would
+    /// return, without consuming it.
+    pub fn current(&self) -> A::Value {
+        self.0.load_value()
+    }
+
+    /// Restarts the sequence of identifiers as if `self` had just been
+    /// created.
+    pub fn reset(&self) {
+        self.0.store_value(A::FIRST)
     }
 }
 
-impl Default for AtomicId {
+impl<A: AtomicInteger> Default for AtomicIdOf<A> {
     fn default() -> Self {
-        AtomicId::new()
+        AtomicIdOf::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::json::Id;
 
     fn assert_sequence(id: &AtomicId, count: Id) {
         for expected in 1..=count {
@@ -62,10 +137,37 @@ mod tests {
 
     #[test]
     fn overflow() {
-        let id = AtomicId(AtomicU64::new(u64::MAX));
+        let id = AtomicIdOf(AtomicU64::new(u64::MAX));
         assert_eq!(u64::MAX, id.next());
         for expected in 0..10 {
             assert_eq!(expected, id.next());
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn current_and_reset() {
+        let id = AtomicId::new();
+        assert_eq!(1, id.current());
+        assert_sequence(&id, 10);
+        assert_eq!(11, id.current());
+        id.reset();
+        assert_sequence(&id, 10);
+    }
+
+    #[test]
+    fn new_32() {
+        let id = AtomicId32::new();
+        for expected in 1..=10u32 {
+            assert_eq!(expected, id.next());
+        }
+    }
+
+    #[test]
+    fn overflow_32() {
+        let id = AtomicIdOf(AtomicU32::new(u32::MAX));
+        assert_eq!(u32::MAX, id.next());
+        for expected in 0..10u32 {
+            assert_eq!(expected, id.next());
+        }
+    }
+}
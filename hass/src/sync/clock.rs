@@ -0,0 +1,173 @@
+//! Sources of time that may be swapped for testing
+//!
+//! Features depending on time, such as debouncing or presence decay, should
+//! rely on a [Clock] rather than on `Utc::now()` and `tokio::time` directly:
+//! [SystemClock] is the real thing, whereas [MockClock] follows the tokio
+//! clock, which tests may freeze with `tokio::time::pause()` and move forward
+//! exactly with `tokio::time::advance()`.
+//!
+//! Time may also be taken from the events processed, see [EventClock].
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tokio::time::{self, Instant};
+
+/// Future returned by [Clock::sleep()].
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A source of time.
+pub trait Clock: Send + Sync + Debug {
+    /// Returns the current time.
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Waits until `duration` has elapsed.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The system clock.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(time::sleep(duration))
+    }
+}
+
+/// A clock starting at a given time and then following the tokio clock, so
+/// that it stands still while the latter is paused.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: DateTime<Utc>,
+    base: Instant,
+}
+
+impl MockClock {
+    /// Creates a clock whose current time is `start`.
+    pub fn new(start: DateTime<Utc>) -> MockClock {
+        MockClock {
+            start,
+            base: Instant::now(),
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        let elapsed = chrono::Duration::from_std(self.base.elapsed()).unwrap_or(chrono::Duration::MAX);
+        self.start + elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(time::sleep(duration))
+    }
+}
+
+/// A clock following the times of the events processed, e.g. their
+/// `time_fired`: it reads the latest time observed plus the time elapsed
+/// since on the tokio clock.
+///
+/// Timings relying on it rather than on the system clock behave the same
+/// whether events come live from HA or from a recorded scenario played by
+/// `hast`, even faster than it happened. Deadlines computed from the times of
+/// events may still be checked against it when no more events are coming.
+#[derive(Debug)]
+pub struct EventClock {
+    latest: Mutex<(DateTime<Utc>, Instant)>,
+}
+
+impl EventClock {
+    /// Creates a clock whose current time is `start` until the first time is
+    /// observed.
+    pub fn new(start: DateTime<Utc>) -> EventClock {
+        EventClock {
+            latest: Mutex::new((start, Instant::now())),
+        }
+    }
+
+    /// Moves the clock to `time`, e.g. the `time_fired` of the event just
+    /// received, even when that's backwards.
+    pub fn observe(&self, time: DateTime<Utc>) {
+        *self.latest.lock().unwrap() = (time, Instant::now());
+    }
+}
+
+impl Clock for EventClock {
+    fn now(&self) -> DateTime<Utc> {
+        let (latest, base) = *self.latest.lock().unwrap();
+        latest + chrono::Duration::from_std(base.elapsed()).unwrap_or(chrono::Duration::MAX)
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(time::sleep(duration))
+    }
+}
+
+/// Waits until `clock` reaches `deadline`, or forever if there is none, e.g.
+/// to be raced against incoming events with `tokio::select!`.
+pub async fn sleep_until(clock: &dyn Clock, deadline: Option<DateTime<Utc>>) {
+    match deadline {
+        Some(deadline) => clock.sleep((deadline - clock.now()).to_std().unwrap_or_default()).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pirengine::home::Area;
+
+    #[tokio::test(start_paused = true)]
+    async fn mock_clock_decay() {
+        let start = DateTime::<Utc>::from_timestamp(1_652_225_690, 0).unwrap();
+        let clock = MockClock::new(start);
+        let mut area = Area::new("studio");
+        area.last_seen = Some(clock.now());
+        let half_life = Duration::from_secs(60);
+        assert_eq!(area.confidence(clock.now(), half_life), 1.0);
+
+        clock.sleep(Duration::from_secs(120)).await;
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(120));
+        assert_eq!(area.confidence(clock.now(), half_life), 0.25);
+
+        time::advance(Duration::from_secs(60)).await;
+        assert_eq!(area.confidence(clock.now(), half_life), 0.125);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn event_clock() {
+        let start = DateTime::<Utc>::from_timestamp(1_652_225_690, 0).unwrap();
+        let clock = EventClock::new(Utc::now());
+        clock.observe(start);
+        assert_eq!(clock.now(), start);
+
+        time::advance(Duration::from_secs(2)).await;
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(2));
+
+        clock.observe(start + chrono::Duration::seconds(1));
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sleep_until_deadline() {
+        let start = DateTime::<Utc>::from_timestamp(1_652_225_690, 0).unwrap();
+        let clock = MockClock::new(start);
+        sleep_until(&clock, Some(start + chrono::Duration::seconds(2))).await;
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(2));
+
+        // Deadlines already past are due right away
+        sleep_until(&clock, Some(start)).await;
+        assert_eq!(clock.now(), start + chrono::Duration::seconds(2));
+
+        let forever = tokio::time::timeout(Duration::from_secs(3600), sleep_until(&clock, None)).await;
+        assert!(forever.is_err());
+    }
+}
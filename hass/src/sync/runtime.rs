@@ -0,0 +1,15 @@
+//! Tokio runtimes for binaries choosing their flavor at startup, e.g. via a
+//! `--single-thread` flag, rather than with `#[tokio::main]`.
+use std::io;
+
+use tokio::runtime::{Builder, Runtime};
+
+/// Builds the tokio runtime, either multi-threaded or, if `single_thread`, current-thread.
+pub fn build(single_thread: bool) -> io::Result<Runtime> {
+    let mut builder = if single_thread {
+        Builder::new_current_thread()
+    } else {
+        Builder::new_multi_thread()
+    };
+    builder.enable_all().build()
+}
@@ -14,6 +14,10 @@
 //! 
 //! The component may also query [Shutdown::is_shutdown()] to check whether the shutdown
 //! signal has already been received.
+//!
+//! Systems needing an ordered shutdown may subscribe components to distinct phases via
+//! [Manager::subscribe_phase()], and then use [Manager::shutdown_phased()] to shut them
+//! down one phase after the other.
 //! 
 //! This module derives from the [Tokio.rs documentation](https://tokio.rs/tokio/topics/shutdown)
 //! for graceful shutdown, which made use broadcast channels to
@@ -50,6 +54,9 @@
 //! ```
 
 
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::sync::{mpsc, watch};
 
 /// Receives and remembers shutdown signals
@@ -62,11 +69,13 @@ use tokio::sync::{mpsc, watch};
 /// or via [Shutdown::clone()]. The former is more common for top-level
 /// components, while the latter is the way to go when a component needs
 /// to spawn another but has no direct access to the [Manager].
-#[derive(Clone,Debug)]
+#[derive(Debug)]
 pub struct Shutdown {
     shutdown: bool,
     notify: watch::Receiver<()>,
     _shutdown_complete: mpsc::Sender<()>,
+    /// Number of instances still alive for the same [Manager], `self` included
+    active: Arc<AtomicUsize>,
 }
 
 impl Shutdown {
@@ -93,6 +102,34 @@ impl Shutdown {
         let _ = self.notify.changed().await;
         self.shutdown = true;
     }
+
+    /// Returns `true` if `self` is the only instance still alive among those
+    /// subscribed to its [Manager], across all phases.
+    ///
+    /// This is meant for components deciding whether to perform some final
+    /// cleanup during shutdown. It is advisory only: other instances may be
+    /// cloned or dropped concurrently, right after the check.
+    pub fn is_last(&self) -> bool {
+        self.active.load(Ordering::SeqCst) == 1
+    }
+}
+
+impl Clone for Shutdown {
+    fn clone(&self) -> Self {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Shutdown {
+            shutdown: self.shutdown,
+            notify: self.notify.clone(),
+            _shutdown_complete: self._shutdown_complete.clone(),
+            active: self.active.clone(),
+        }
+    }
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 /// Manages [Shutdown] instances, coordinates signals, and waits for termination.
@@ -101,42 +138,113 @@ impl Shutdown {
 /// shutdown with all components that subscribed to it and thus received a
 /// [Shutdown] instance, and (2) let the owner of the manager request and wait
 /// at the same time for the shutdown to complete.
+///
+/// Components may optionally subscribe to a given shutdown phase via
+/// [Manager::subscribe_phase()], for systems needing their shutdown to happen
+/// in order, e.g. to stop accepting connections before draining workers.
+/// Phases are only honoured by [Manager::shutdown_phased()].
 pub struct Manager {
+    phases: Mutex<BTreeMap<u8, Phase>>,
+    active: Arc<AtomicUsize>,
+}
+
+/// Signalling channels of a single shutdown phase.
+struct Phase {
     notify_shutdown: watch::Sender<()>,
     shutdown_complete_rx: mpsc::Receiver<()>,
     shutdown_complete_tx: mpsc::Sender<()>,
 }
 
-impl Manager {
-
-    /// Creates a new manager.
-    pub fn new() -> Manager {
+impl Phase {
+    fn new() -> Phase {
         let (notify_shutdown, _) = watch::channel(());
         let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
 
-        Manager {
+        Phase {
             notify_shutdown,
             shutdown_complete_rx,
             shutdown_complete_tx
         }
     }
 
-    /// Returns a [Shutdown] object subscribed to the manager.
-    pub fn subscribe(&self) -> Shutdown {
+    fn subscribe(&self, active: &Arc<AtomicUsize>) -> Shutdown {
+        active.fetch_add(1, Ordering::SeqCst);
         Shutdown {
             shutdown: false,
             notify: self.notify_shutdown.subscribe(),
             _shutdown_complete: self.shutdown_complete_tx.clone(),
+            active: active.clone(),
+        }
+    }
+
+    /// Sends the shutdown signal, returning the receiver to wait on for the
+    /// termination of the phase.
+    fn signal(self) -> mpsc::Receiver<()> {
+        drop(self.notify_shutdown);
+        drop(self.shutdown_complete_tx);
+        self.shutdown_complete_rx
+    }
+}
+
+impl Manager {
+
+    /// Creates a new manager.
+    pub fn new() -> Manager {
+        Manager {
+            phases: Mutex::new(BTreeMap::new()),
+            active: Arc::new(AtomicUsize::new(0)),
         }
     }
 
+    /// Returns a [Shutdown] object subscribed to the manager.
+    ///
+    /// Same as subscribing to phase `0` via [Manager::subscribe_phase()].
+    pub fn subscribe(&self) -> Shutdown {
+        self.subscribe_phase(0)
+    }
+
+    /// Returns a [Shutdown] object subscribed to the given `phase` of the manager.
+    ///
+    /// See [Manager::shutdown_phased()].
+    pub fn subscribe_phase(&self, phase: u8) -> Shutdown {
+        self.phases.lock().unwrap()
+            .entry(phase)
+            .or_insert_with(Phase::new)
+            .subscribe(&self.active)
+    }
+
+    /// Returns the number of [Shutdown] instances subscribed to the manager
+    /// that are still alive, across all phases.
+    pub fn active_count(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
     /// Consumes the manager and waits for all [Shutdown] subscribed instances
     /// to terminate. Subscribed instances include both those created via 
     /// [Manager::subscribe()] and [Shutdown::clone()].
-    pub async fn shutdown(mut self) {
-        drop(self.notify_shutdown);
-        drop(self.shutdown_complete_tx);
-        let _ = self.shutdown_complete_rx.recv().await;
+    ///
+    /// All instances are signalled at once, regardless of their phase.
+    pub async fn shutdown(self) {
+        let phases = self.phases.into_inner().unwrap();
+        let mut completions: Vec<_> = phases.into_values()
+            .map(Phase::signal)
+            .collect();
+        for shutdown_complete_rx in completions.iter_mut() {
+            let _ = shutdown_complete_rx.recv().await;
+        }
+    }
+
+    /// Consumes the manager and shuts down its subscribed [Shutdown] instances
+    /// one phase at a time, in ascending order.
+    ///
+    /// Instances of a phase are signalled only after all of those of the
+    /// previous phases have terminated.
+    pub async fn shutdown_phased(self) {
+        let phases = self.phases.into_inner().unwrap();
+        for (phase, p) in phases {
+            tracing::debug!("shutdown: phase {}", phase);
+            let _ = p.signal().recv().await;
+        }
     }
 }
 
@@ -144,4 +252,81 @@ impl Default for Manager {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_phased() {
+        let manager = Manager::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        // Subscribe phase 1 first, to make sure order is not about subscription
+        let mut shutdown1 = manager.subscribe_phase(1);
+        let log1 = log.clone();
+        tokio::spawn(async move {
+            shutdown1.recv().await;
+            log1.lock().unwrap().push("phase 1 signalled");
+        });
+
+        let mut shutdown0 = manager.subscribe_phase(0);
+        let log0 = log.clone();
+        tokio::spawn(async move {
+            shutdown0.recv().await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            log0.lock().unwrap().push("phase 0 terminated");
+        });
+
+        manager.shutdown_phased().await;
+        assert_eq!(*log.lock().unwrap(), vec!["phase 0 terminated", "phase 1 signalled"]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_all_phases() {
+        let manager = Manager::new();
+        let workers: Vec<_> = [manager.subscribe(), manager.subscribe_phase(3)]
+            .into_iter()
+            .map(|mut shutdown| tokio::spawn(async move {
+                shutdown.recv().await;
+                shutdown.is_shutdown()
+            }))
+            .collect();
+        manager.shutdown().await;
+        for worker in workers {
+            assert!(worker.await.unwrap());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn is_last() {
+        let manager = Manager::new();
+        let workers: Vec<_> = (1..=3u64)
+            .map(|n| {
+                let mut shutdown = manager.subscribe();
+                tokio::spawn(async move {
+                    shutdown.recv().await;
+                    tokio::time::sleep(Duration::from_millis(n * 100)).await;
+                    shutdown.is_last()
+                })
+            })
+            .collect();
+        assert_eq!(manager.active_count(), 3);
+
+        let shutdown = manager.subscribe();
+        let cloned = shutdown.clone();
+        assert_eq!(manager.active_count(), 5);
+        drop(cloned);
+        drop(shutdown);
+        assert_eq!(manager.active_count(), 3);
+
+        manager.shutdown().await;
+        let last: Vec<_> = futures_util::future::join_all(workers).await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(last, vec![false, false, true]);
+    }
+}
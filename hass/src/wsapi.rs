@@ -1,17 +1,34 @@
+mod filter;
 mod messenger;
+mod options;
+mod record;
+mod request;
+mod subscription;
+mod token;
 
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::sync::{
     Arc,
+    Mutex,
 };
 
 use anyhow::anyhow;
+use futures_util::future;
+use futures_util::stream::{self, Stream, StreamExt};
 use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
     net::TcpStream,
-    sync::mpsc,
+    sync::{mpsc, oneshot, watch},
+    time,
 };
 use tokio_tungstenite::{
     self,
     connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderName, HeaderValue},
+    },
     MaybeTlsStream,
 };
 use tracing;
@@ -24,32 +41,113 @@ use crate::sync::{atomic::AtomicId, shutdown::Shutdown};
 
 use messenger::{
     Command,
+    RecentEvents,
     WsApiMessenger
 };
 
+pub use filter::{EventFilter, ORIGIN_LOCAL, ORIGIN_REMOTE};
+pub use options::{WsApiOptions, WsApiOptionsBuilder};
+pub use record::RecordFormat;
+pub use request::{CancellableRequest, RequestCanceller};
+pub use subscription::Subscription;
+pub use token::{AccessToken, StaticToken, TokenProvider};
+
 type WebSocketStream = tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>;
 
+/// Tracing target of the messages exchanged with HA, see [WsApiOptions::trace_traffic].
+pub const TRAFFIC_TARGET: &str = "hass::traffic";
+
 const MPSC_CHANNEL_BOUND: usize = 128;
 const KEEPALIVE_INTERVAL_SEC: u64 = 15;
+const CONNECT_TIMEOUT_SEC: u64 = 10;
+
+/// Domains whose own `turn_on`, `turn_off` and `toggle` services are called
+/// by [WsApi::turn_on()] and siblings, rather than the generic `homeassistant` ones
+const TOGGLE_DOMAINS: &[&str] = &[
+    "automation", "climate", "fan", "humidifier", "input_boolean", "light",
+    "media_player", "remote", "siren", "switch",
+];
 
 
 
+/// Connection to the WebSocket API of HA.
+///
+/// It is a cheap handle that may be cloned to issue commands and subscriptions
+/// from several tasks over the same connection, see [WsApi::clone()].
 #[derive(Debug)]
 pub struct WsApi {
     /// Endpoint URL
     url: Url,
-    // Endpoint authentication token
-    access_token: String,
+    /// Source of the tokens to authenticate with
+    token: Arc<dyn TokenProvider>,
 
     /// Transmission channel to send commands to the `WsApiMessenger`
     tx: mpsc::Sender<Command>,
 
     /// Receiver for all unhandled WsMessage, i.e. those not associated 
-    /// to any `WsApi::registration()`
+    /// to any `WsApi::registration()`, only read while authenticating. Only the
+    /// instance establishing the connection owns it, not its clones
     unhandled_rx: Option<mpsc::Receiver<WsMessage>>,
 
     /// Next available identifier, to be used for `WsMessage` requests
     id: Arc<AtomicId>,
+
+    /// Subscriptions made by `WsApi::subscribe_event_types_merged()`, mapping
+    /// the id handed to the caller to the ids of the actual HA subscriptions
+    merged: Arc<Mutex<BTreeMap<Id, Vec<Id>>>>,
+
+    /// Active event subscriptions, re-issued by `WsApi::reconnect()`
+    subscriptions: subscription::Subscriptions,
+
+    /// Entity registry as last fetched by `WsApi::entity_registry()`
+    entity_registry: Arc<Mutex<Option<Vec<json::EntityRegistryEntry>>>>,
+
+    /// Version of HA, as declared when authenticating
+    ha_version: String,
+
+    /// Options the connection was established with
+    options: WsApiOptions,
+
+    /// Most recent events received by the `WsApiMessenger`, if enabled
+    recent: Option<Arc<RecentEvents>>,
+
+    /// Set to `true` once the `WsApiMessenger` task has terminated
+    closed: watch::Receiver<bool>,
+
+    /// States of all entities fetched by the last `WsApi::reconnect()`, if
+    /// enabled by `WsApiOptions::resync_on_reconnect`
+    resync: Arc<watch::Sender<Option<Resync>>>,
+}
+
+/// States of all entities, as fetched by [WsApi::reconnect()] when
+/// [WsApiOptions::resync_on_reconnect] is enabled.
+pub type Resync = Arc<Vec<json::StateObject>>;
+
+impl Clone for WsApi {
+    /// Returns a new handle to the same connection, sharing its ids, subscriptions
+    /// and caches, so that e.g. several tasks may subscribe to events concurrently.
+    ///
+    /// Clones do not own the receiver of unhandled messages, which is only needed
+    /// to authenticate, until [WsApi::reconnect()] is called on them. Reconnecting
+    /// only moves the instance it is called on to the new connection: the others
+    /// are left with the previous one, which gets closed, see [WsApi::closed()].
+    fn clone(&self) -> WsApi {
+        WsApi {
+            url: self.url.clone(),
+            token: self.token.clone(),
+            tx: self.tx.clone(),
+            unhandled_rx: None,
+            id: self.id.clone(),
+            merged: self.merged.clone(),
+            subscriptions: self.subscriptions.clone(),
+            entity_registry: self.entity_registry.clone(),
+            ha_version: self.ha_version.clone(),
+            options: self.options.clone(),
+            recent: self.recent.clone(),
+            closed: self.closed.clone(),
+            resync: self.resync.clone(),
+        }
+    }
 }
 
 impl WsApi {
@@ -60,29 +158,55 @@ impl WsApi {
     {
         let scheme = if secure { "wss" } else { "ws" };
         let url = Url::parse(&format!("{}://{}:{}/api/websocket", scheme, host, port))?;
+        Self::connect_with(url, access_token, shutdown, WsApiOptions::default()).await
+    }
+
+    /// Connects to the HA WebSocket endpoint at `url` and performs authentication
+    /// with the `access_token`, behaving according to the given `options`.
+    ///
+    /// All other constructors are shorthands for this one with default options.
+    pub async fn connect_with(url: Url, access_token: &str, shutdown: Shutdown, options: WsApiOptions) -> Result<WsApi> {
+        let token = Arc::new(StaticToken(access_token.to_owned()));
+        Self::connect_with_token_provider(url, token, shutdown, options).await
+    }
 
+    /// Same as [WsApi::connect_with()], but the access token is asked to `token`
+    /// each time authentication is needed, i.e. now and on [WsApi::reconnect()].
+    pub async fn connect_with_token_provider(url: Url, token: Arc<dyn TokenProvider>, shutdown: Shutdown, options: WsApiOptions) -> Result<WsApi> {
+        match options.connect_timeout {
+            Some(timeout) => time::timeout(timeout, Self::connect(url, token, shutdown, options))
+                .await
+                .map_err(|_| Error::Timeout)?,
+            None => Self::connect(url, token, shutdown, options).await,
+        }
+    }
+
+    async fn connect(url: Url, token: Arc<dyn TokenProvider>, shutdown: Shutdown, options: WsApiOptions) -> Result<WsApi> {
         //? What to do with you? I need to guarantee all new messages sent requiring IDs are
         //? properly taking new ids from here.
         let id = Arc::new(AtomicId::new());
 
-        let id2 = id.clone();
-        let socket = connect_ws(&url).await?;
-        let (tx, rx) = mpsc::channel(MPSC_CHANNEL_BOUND);
-        let (unhandled_tx, unhandled_rx) = mpsc::channel(MPSC_CHANNEL_BOUND);
-        tokio::spawn(async move {
-            let messenger = WsApiMessenger::new(rx, socket, id2, Some(unhandled_tx), shutdown);
-            if let Err(e) = messenger.run().await {
-                tracing::error!("messenger task fatal error: {}", e);
-            }
-            tracing::info!("messenger task terminated");
-        });
+        let socket = connect_ws(&url, &options).await?;
+        let recent = match options.recent_events {
+            0 => None,
+            capacity => Some(Arc::new(RecentEvents::new(capacity))),
+        };
+        let (tx, unhandled_rx, closed) = spawn_messenger(Some(socket), id.clone(), shutdown, &options, recent.clone());
 
         let mut api = WsApi {
-            access_token: access_token.to_owned(),
+            token,
             url,
             tx,
             id,
             unhandled_rx: Some(unhandled_rx),
+            merged: Arc::new(Mutex::new(BTreeMap::new())),
+            subscriptions: Arc::new(Mutex::new(subscription::Registry::default())),
+            entity_registry: Arc::new(Mutex::new(None)),
+            ha_version: String::new(),
+            options,
+            recent,
+            closed,
+            resync: Arc::new(watch::channel(None).0),
         };
 
         api.authenticate().await?;
@@ -90,6 +214,158 @@ impl WsApi {
         Ok(api)
     }
 
+    /// Replaces the current connection with a new one to the same endpoint, which
+    /// is then authenticated again.
+    ///
+    /// Since HA expects ids to grow from scratch on each connection, they restart
+    /// from `1`, and the active subscriptions are issued again with fresh ids: their
+    /// receivers keep working, with events now carrying the new ids. Returns the
+    /// mapping from the old ids to the new ones, to be used from now on e.g. with
+    /// [WsApi::unsubscribe()], ids returned by [WsApi::subscribe_event_types_merged()]
+    /// included.
+    ///
+    /// Subscriptions can only be moved over while the current connection is still up:
+    /// otherwise, their receivers have already been closed, and they are dropped.
+    ///
+    /// The current connection is only given up once the new one is authenticated:
+    /// until then, failures leave it in place along with its subscriptions. Those
+    /// that could not be issued again afterwards are kept, with their receivers, for
+    /// the next reconnection to retry.
+    ///
+    /// With [WsApiOptions::resync_on_reconnect], the states of all entities are then
+    /// fetched and published via [WsApi::resyncs()]: since the subscriptions are
+    /// already active by then, no change goes missing in between.
+    ///
+    /// Fails with [Error::Cancelled] as soon as `shutdown` is signalled while the new
+    /// connection is being established, e.g. because HA is unresponsive.
+    pub async fn reconnect(&mut self, shutdown: Shutdown) -> Result<BTreeMap<Id, Id>> {
+        let mut cancel = shutdown.clone();
+        let socket = tokio::select! {
+            socket = connect_ws(&self.url, &self.options) => socket?,
+            _ = cancel.recv() => {
+                tracing::debug!("reconnect: cancelled by shutdown");
+                return Err(Error::Cancelled);
+            },
+        };
+        let (tx, unhandled_rx, closed) = spawn_messenger(Some(socket), self.id.clone(), shutdown, &self.options, self.recent.clone());
+        let old_tx = std::mem::replace(&mut self.tx, tx);
+        let old_unhandled_rx = self.unhandled_rx.replace(unhandled_rx);
+        let old_closed = std::mem::replace(&mut self.closed, closed);
+        let old_ha_version = self.ha_version.clone();
+        let auth = tokio::select! {
+            auth = self.authenticate() => auth,
+            _ = cancel.recv() => {
+                tracing::debug!("reconnect: cancelled by shutdown");
+                Err(Error::Cancelled)
+            },
+        };
+        drop(cancel);
+        if let Err(e) = auth {
+            // Dropping the new messenger's channel closes the new connection
+            self.tx = old_tx;
+            self.unhandled_rx = old_unhandled_rx;
+            self.closed = old_closed;
+            self.ha_version = old_ha_version;
+            return Err(e);
+        }
+
+        let (handover_tx, handover_rx) = oneshot::channel();
+        let mut senders = match old_tx.send(Command::Handover(handover_tx)).await {
+            Ok(()) => handover_rx.await.unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+        drop(old_tx);
+        let (subscriptions, detached) = {
+            let mut registry = self.subscriptions.lock().unwrap();
+            registry.generation += 1;
+            (std::mem::take(&mut registry.active), std::mem::take(&mut registry.detached))
+        };
+        self.id.reset();
+
+        let mut pending: Vec<_> = detached.into_iter().collect();
+        for (old_id, (event_type, filter)) in subscriptions {
+            match senders.remove(&old_id) {
+                Some(sender) => pending.push((old_id, (event_type, filter, sender))),
+                None => tracing::debug!("reconnect: dropped subscription id={}: receiver closed", old_id),
+            }
+        }
+        let mut ids = BTreeMap::new();
+        let mut pending = pending.into_iter();
+        while let Some((old_id, (event_type, filter, sender))) = pending.next() {
+            if sender.is_closed() {
+                tracing::debug!("reconnect: dropped subscription id={}: receiver closed", old_id);
+                continue;
+            }
+            match self.subscribe_event_filtered_with_id(event_type, filter.clone()).await {
+                Ok((new_id, rx)) => {
+                    tracing::debug!("reconnect: subscription id={} is now id={}", old_id, new_id);
+                    spawn_forward(rx, sender);
+                    ids.insert(old_id, new_id);
+                },
+                Err(e) => {
+                    let mut registry = self.subscriptions.lock().unwrap();
+                    registry.detached.insert(old_id, (event_type, filter, sender));
+                    registry.detached.extend(pending);
+                    return Err(e);
+                },
+            }
+        }
+
+        let merged = std::mem::take(&mut *self.merged.lock().unwrap());
+        for (old_id, old_ids) in merged {
+            let new_ids: Vec<Id> = old_ids.iter().filter_map(|id| ids.get(id).copied()).collect();
+            if new_ids.is_empty() {
+                continue;
+            }
+            let new_id = self.id.next();
+            self.merged.lock().unwrap().insert(new_id, new_ids);
+            ids.insert(old_id, new_id);
+        }
+
+        if self.options.resync_on_reconnect {
+            let states = self.get_states().await?;
+            tracing::debug!("reconnect: resynced {} states", states.len());
+            self.resync.send_replace(Some(Arc::new(states)));
+        }
+
+        Ok(ids)
+    }
+
+    /// Creates a [WsApi] not connected to any HA instance, along with a [TestInjector]
+    /// to feed it messages as if they came from HA, for testing consumers of the API.
+    ///
+    /// Commands sent through it are acknowledged right away with a successful
+    /// result, hence e.g. subscriptions always succeed. It must be called from
+    /// within a tokio runtime.
+    #[cfg(any(feature = "test-support", test))]
+    pub fn channel_backed(shutdown: Shutdown) -> (WsApi, TestInjector) {
+        WsApi::channel_backed_with(shutdown, WsApiOptions::builder().keepalive_interval(None).build())
+    }
+
+    /// Same as [WsApi::channel_backed()], with custom `options`, e.g. to check
+    /// how consumers cope with [WsApiOptions::rate_limit].
+    #[cfg(any(feature = "test-support", test))]
+    pub fn channel_backed_with(shutdown: Shutdown, options: WsApiOptions) -> (WsApi, TestInjector) {
+        let id = Arc::new(AtomicId::new());
+        let (tx, unhandled_rx, closed) = spawn_messenger(None, id.clone(), shutdown, &options, None);
+        let api = WsApi {
+            token: Arc::new(StaticToken(String::new())),
+            url: Url::parse("ws://channel-backed/api/websocket").unwrap(),
+            tx: tx.clone(),
+            id,
+            unhandled_rx: Some(unhandled_rx),
+            merged: Arc::new(Mutex::new(BTreeMap::new())),
+            subscriptions: Arc::new(Mutex::new(subscription::Registry::default())),
+            entity_registry: Arc::new(Mutex::new(None)),
+            ha_version: String::new(),
+            options,
+            recent: None,
+            closed,
+            resync: Arc::new(watch::channel(None).0),
+        };
+        (api, TestInjector { tx })
+    }
+
     /// Connects to a given `host` and `port` with the provided authentication
     /// token `auth_token`.
     pub async fn new_unsecure(host: &str, port: u16, access_token: &str, shutdown: Shutdown) -> Result<WsApi> {
@@ -102,11 +378,59 @@ impl WsApi {
         Self::new(true, host, port, access_token, shutdown).await
     }
 
+    /// Returns a receiver of the states of all entities fetched by the last
+    /// [WsApi::reconnect()], which is `None` until the first one, and notified
+    /// of each following one. See [WsApiOptions::resync_on_reconnect].
+    ///
+    /// Receivers are shared by all the clones of `self`.
+    pub fn resyncs(&self) -> watch::Receiver<Option<Resync>> {
+        self.resync.subscribe()
+    }
+
     /// Returns the full URL of the WebSocket endpoint `self` is connected to.
     pub fn url(&self) -> &Url {
         &self.url
     }
 
+    /// Returns a future completing once the connection is over, be it because of
+    /// the socket getting closed, a shutdown request, or a fatal error.
+    ///
+    /// Unlike a shutdown, it does not initiate anything: it is meant e.g. for
+    /// supervisors deciding whether to [WsApi::reconnect()].
+    pub fn closed(&self) -> impl Future<Output = ()> {
+        let mut closed = self.closed.clone();
+        async move {
+            // An error means the sender is gone, hence closed as well
+            let _ = closed.wait_for(|closed| *closed).await;
+        }
+    }
+
+    /// Returns the most recent events received, oldest first, up to the capacity
+    /// set by [WsApiOptions::recent_events]. Always empty when it is `0`.
+    pub fn recent_events(&self) -> Vec<WsMessage> {
+        self.recent.as_ref().map_or_else(Vec::new, |recent| recent.to_vec())
+    }
+
+    /// Reconstructs the causal chain leading to `msg` out of the events buffered
+    /// as [WsApi::recent_events()], see [json::context_chain()].
+    pub fn context_chain(&self, msg: &WsMessage) -> Vec<WsMessage> {
+        let events = self.recent_events();
+        json::context_chain(&events, msg).into_iter().cloned().collect()
+    }
+
+    /// Collects all the messages currently buffered in `receiver`, without
+    /// waiting for new ones.
+    ///
+    /// Useful to batch-process whatever has been received so far, e.g. on
+    /// periodic ticks.
+    pub fn try_recv_all(receiver: &mut mpsc::Receiver<WsMessage>) -> Vec<WsMessage> {
+        let mut messages = Vec::with_capacity(receiver.len());
+        while let Ok(msg) = receiver.try_recv() {
+            messages.push(msg);
+        }
+        messages
+    }
+
     async fn recv_unhandled(&mut self) -> Result<WsMessage> {
         if let Some(unhandled_rx) = self.unhandled_rx.as_mut() {
             match unhandled_rx.recv().await {
@@ -131,6 +455,7 @@ impl WsApi {
         match self.recv_unhandled().await? {
             WsMessage::AuthRequired { ha_version } => {
                 tracing::info!("authentication: received auth_required message from HA {}", &ha_version);
+                self.ha_version = ha_version;
             },
             unexp => {
                 tracing::error!("authentication: failed for unexpected message: {:?}", unexp);
@@ -139,15 +464,17 @@ impl WsApi {
         }
 
         // Step 2. We reply with an auth message complete with auth_token
-        let auth_cmd = Command::Message(WsMessage::Auth { access_token: self.access_token.clone() });
+        let access_token = self.token.access_token().await?;
+        let auth_cmd = Command::Message(WsMessage::Auth { access_token });
         self.send_command(auth_cmd).await?;
         tracing::info!("authentication: auth_token sent");
 
         // Step 3. HA either validates the authentication with an auth_ok message, or
         //         rejects it with an auth_invalid message.
         match self.recv_unhandled().await? {
-            WsMessage::AuthOk { .. } => {
+            WsMessage::AuthOk { ha_version } => {
                 tracing::info!("authentication: successful");
+                self.ha_version = ha_version;
                 Ok(())
             },
             WsMessage::AuthInvalid {message} => {
@@ -183,67 +510,584 @@ impl WsApi {
     }
 
     async fn registration(&self) -> Result<(Id, mpsc::Receiver<WsMessage>)> {
-        let (tx, rx) = mpsc::channel(MPSC_CHANNEL_BOUND);
+        let (tx, rx) = mpsc::channel(self.options.channel_bound);
         self.registration_ch(tx).await.map(|id| { (id, rx) })
     }
 
-    pub async fn subscribe_event(&self, event_type: Option<json::EventType>) -> Result<mpsc::Receiver<WsMessage>> {
+    /// The version of HA, as declared by `auth_ok` when authenticating. It is
+    /// empty for instances that never authenticated, e.g. `WsApi::channel_backed()`.
+    pub fn ha_version(&self) -> &str {
+        &self.ha_version
+    }
+
+    /// Returns a new `Id`, unique within the connection, for messages sent
+    /// via [WsApi::send_raw()].
+    pub fn next_id(&self) -> Id {
+        self.id.next()
+    }
+
+    /// Sends `msg` to HA as it is, `Id` included, without waiting for replies.
+    ///
+    /// This is a low-level escape hatch: use [WsApi::register_oneshot()] first
+    /// to get the reply, if any.
+    pub async fn send_raw(&self, msg: WsMessage) -> Result<()> {
+        self.send_command(Command::Message(msg)).await
+    }
+
+    /// Registers a one-shot channel receiving the next message from HA carrying
+    /// the given `id`, e.g. the reply to a command sent via [WsApi::send_raw()].
+    pub async fn register_oneshot(&self, id: Id) -> Result<oneshot::Receiver<WsMessage>> {
+        let (tx, rx) = oneshot::channel();
+        self.send_command(Command::RegisterOneshot(id, tx)).await?;
+        Ok(rx)
+    }
+
+    /// Sends `msg` to HA with a new `Id`, replacing the one it carries, and
+    /// waits for the reply.
+    ///
+    /// Should HA reject the `Id` as already used, the request is sent once more
+    /// with a new one.
+    pub async fn request(&self, msg: WsMessage) -> Result<WsMessage> {
+        let reply = self.request_cancellable(msg.clone()).await?.response().await?;
+        if !is_id_reuse(&reply) {
+            return Ok(reply);
+        }
+        tracing::warn!("request: id={:?} rejected as already used, retrying with a new one", reply.id());
+        self.request_cancellable(msg).await?.response().await
+    }
+
+    /// Same as [WsApi::request()], but returns as soon as `msg` is sent, with a
+    /// [CancellableRequest] to either wait for the reply or abandon it.
+    pub async fn request_cancellable(&self, msg: WsMessage) -> Result<CancellableRequest> {
+        let (id, rx) = self.registration().await?;
+        self.send_command(Command::Message(msg.set_id(id))).await?;
+        Ok(CancellableRequest::new(id, rx, self.tx.clone()))
+    }
+
+    /// Sends all of `msgs` to HA back-to-back, each with a new `Id`, and only
+    /// then waits for their replies.
+    ///
+    /// Replies are returned in the same order as `msgs`. Failures of single
+    /// commands are reported by their unsuccessful `Result` in the vector, while
+    /// an `Err` means the connection itself failed.
+    pub async fn pipeline(&self, msgs: Vec<WsMessage>) -> Result<Vec<WsMessage>> {
+        let mut requests = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            requests.push(self.request_cancellable(msg).await?);
+        }
+        let mut replies = Vec::with_capacity(requests.len());
+        for request in requests {
+            replies.push(request.response().await?);
+        }
+        Ok(replies)
+    }
+
+    /// Calls the `service` of the given `domain`, optionally with some `service_data`
+    /// and a `target`, e.g. `{"entity_id": "light.kitchen"}`.
+    ///
+    /// Returns the successful `Result` sent back by HA.
+    pub async fn call_service(&self,
+        domain: &str,
+        service: &str,
+        service_data: Option<serde_json::Value>,
+        target: Option<serde_json::Value>) -> Result<WsMessage>
+    {
+        let msg = WsMessage::CallService {
+            id: 0,
+            domain: domain.to_owned(),
+            service: service.to_owned(),
+            service_data,
+            target,
+        };
+        match self.request(msg).await? {
+            reply @ WsMessage::Result { success: true, .. } => Ok(reply),
+            reply => result_or_error(reply, ()).and(Err(Error::JsonParsing("unexpected call_service result"))),
+        }
+    }
+
+    /// Fires an event of the given `event_type` on the event bus of HA, optionally
+    /// with some `event_data`.
+    ///
+    /// Returns the successful `Result` sent back by HA.
+    pub async fn fire_event(&self, event_type: json::EventType, event_data: Option<serde_json::Value>) -> Result<WsMessage> {
+        match self.request(WsMessage::FireEvent { id: 0, event_type, event_data }).await? {
+            reply @ WsMessage::Result { success: true, .. } => Ok(reply),
+            reply => result_or_error(reply, ()).and(Err(Error::JsonParsing("unexpected fire_event result"))),
+        }
+    }
+
+    /// Turns on `entity_id` through the `turn_on` service of its domain, or the
+    /// `homeassistant` one if the domain is not known to support it.
+    pub async fn turn_on(&self, entity_id: &str) -> Result<WsMessage> {
+        self.call_toggle_service(entity_id, "turn_on").await
+    }
+
+    /// Turns off `entity_id`, see [WsApi::turn_on()].
+    pub async fn turn_off(&self, entity_id: &str) -> Result<WsMessage> {
+        self.call_toggle_service(entity_id, "turn_off").await
+    }
+
+    /// Toggles `entity_id`, see [WsApi::turn_on()].
+    pub async fn toggle(&self, entity_id: &str) -> Result<WsMessage> {
+        self.call_toggle_service(entity_id, "toggle").await
+    }
+
+    async fn call_toggle_service(&self, entity_id: &str, service: &str) -> Result<WsMessage> {
+        let domain = entity_id.split_once('.')
+            .map(|(domain, _)| domain)
+            .filter(|domain| TOGGLE_DOMAINS.contains(domain))
+            .unwrap_or("homeassistant");
+        let target = serde_json::json!({ "entity_id": entity_id });
+        self.call_service(domain, service, None, Some(target)).await
+    }
+
+    /// Asks HA to validate the given `trigger`, `condition` and `action` sections
+    /// of an automation configuration, reporting the outcome of each of them.
+    ///
+    /// Sections passed as `None` are not sent, and are `None` in the result too.
+    pub async fn validate_config(&self,
+        trigger: Option<serde_json::Value>,
+        condition: Option<serde_json::Value>,
+        action: Option<serde_json::Value>) -> Result<json::ValidationResult>
+    {
+        let msg = WsMessage::ValidateConfig { id: 0, trigger, condition, action };
+        match self.request(msg).await? {
+            WsMessage::Result { success: true, data: json::ResultBody::Result { result: Some(json::ResultObject::Map(map)) }, .. } => {
+                Ok(serde_json::from_value(serde_json::Value::Object(map))?)
+            },
+            reply => result_or_error(reply, ()).and_then(|_| Err(Error::JsonParsing("unexpected validate_config result"))),
+        }
+    }
+
+    /// Fetches the services known to HA, keyed by domain and then by service name.
+    pub async fn get_services(&self) -> Result<HashMap<String, HashMap<String, json::ServiceDescription>>> {
+        match self.request(WsMessage::GetServices { id: 0 }).await? {
+            WsMessage::Result { success: true, data: json::ResultBody::Result { result: Some(json::ResultObject::Map(map)) }, .. } => {
+                Ok(serde_json::from_value(serde_json::Value::Object(map))?)
+            },
+            reply => result_or_error(reply, ()).and_then(|_| Err(Error::JsonParsing("unexpected get_services result"))),
+        }
+    }
+
+    /// Fetches the entity registry, i.e. the entities known to HA along with
+    /// e.g. the area and device they belong to, and caches it for
+    /// [WsApi::cached_entity_registry()].
+    pub async fn entity_registry(&self) -> Result<Vec<json::EntityRegistryEntry>> {
+        let entries: Vec<json::EntityRegistryEntry> = match self.request(WsMessage::EntityRegistryList { id: 0 }).await? {
+            WsMessage::Result { success: true, data: json::ResultBody::Result { result: Some(json::ResultObject::Array(entries)) }, .. } => {
+                entries.into_iter()
+                    .map(serde_json::from_value)
+                    .collect::<serde_json::Result<_>>()?
+            },
+            WsMessage::Result { success: true, data: json::ResultBody::Result { result: None }, .. } => Vec::new(),
+            reply => return result_or_error(reply, ()).and_then(|_| Err(Error::JsonParsing("unexpected entity_registry result"))),
+        };
+        *self.entity_registry.lock().unwrap() = Some(entries.clone());
+        Ok(entries)
+    }
+
+    /// Same as [WsApi::entity_registry()], but only fetches the registry if it
+    /// was not already, returning the cached one otherwise. Call
+    /// [WsApi::entity_registry()] to refresh it.
+    pub async fn cached_entity_registry(&self) -> Result<Vec<json::EntityRegistryEntry>> {
+        if let Some(entries) = self.entity_registry.lock().unwrap().as_ref() {
+            return Ok(entries.clone());
+        }
+        self.entity_registry().await
+    }
+
+    /// Fetches the state of all entities.
+    ///
+    /// A reply with no states, be it an empty list or no list at all, results in
+    /// an empty vector. See [WsApi::get_states_stream()] for large instances.
+    pub async fn get_states(&self) -> Result<Vec<json::StateObject>> {
+        match self.request(WsMessage::GetStates { id: 0 }).await? {
+            WsMessage::Result { success: true, data: json::ResultBody::Result { result: Some(json::ResultObject::Array(states)) }, .. } => {
+                Ok(states.into_iter()
+                    .map(serde_json::from_value)
+                    .collect::<serde_json::Result<_>>()?)
+            },
+            WsMessage::Result { success: true, data: json::ResultBody::Result { result: None }, .. } => Ok(Vec::new()),
+            reply => result_or_error(reply, ()).and_then(|_| Err(Error::JsonParsing("unexpected get_states result"))),
+        }
+    }
+
+    /// Fetches the state of all entities, handing them out one at a time.
+    ///
+    /// Each state is only parsed when the stream gets to it, and the raw JSON it
+    /// comes from is dropped right after: on large instances, memory does not have
+    /// to hold both the whole reply and all of the parsed states at once. States
+    /// that cannot be parsed are logged and skipped.
+    pub async fn get_states_stream(&self) -> Result<impl Stream<Item = json::StateObject>> {
+        let states = match self.request(WsMessage::GetStates { id: 0 }).await? {
+            WsMessage::Result { success: true, data: json::ResultBody::Result { result: Some(json::ResultObject::Array(states)) }, .. } => states,
+            WsMessage::Result { success: true, data: json::ResultBody::Result { result: None }, .. } => Vec::new(),
+            reply => return result_or_error(reply, ()).and_then(|_| Err(Error::JsonParsing("unexpected get_states result"))),
+        };
+        Ok(stream::iter(states.into_iter().filter_map(|state| {
+            match serde_json::from_value(state) {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    tracing::warn!("get_states_stream: skipping unparsable state: {}", e);
+                    None
+                },
+            }
+        })))
+    }
+
+    /// Subscribes to `state_changed` events, handing out their typed data.
+    ///
+    /// Only changes between two known states are yielded: events of entities
+    /// just added or removed, as well as malformed ones, are skipped.
+    pub async fn subscribe_state_changes(&self) -> Result<impl Stream<Item = json::StateChangedData>> {
+        let rx = self.subscribe_event(Some(json::EventType::StateChanged)).await?;
+        let events = stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|msg| (msg, rx))
+        });
+        Ok(events.filter_map(|msg| future::ready(match msg.as_state_changed() {
+            Some(data) if data.old_state.is_some() && data.new_state.is_some() => Some(data),
+            Some(_) => None,
+            None => {
+                tracing::warn!("subscribe_state_changes: skipping malformed event: {}", msg);
+                None
+            },
+        })))
+    }
+
+    /// Subscribes to events of `event_type`, or all of them if `None`, writing each
+    /// one to `writer` in the given `format`, until either `cancel` is signalled or
+    /// the subscription ends, e.g. because the connection got closed.
+    ///
+    /// Returns the number of events written. Events that cannot be serialized
+    /// are logged and skipped.
+    pub async fn record_subscription<W>(&self, event_type: Option<json::EventType>, writer: &mut W, format: RecordFormat, mut cancel: Shutdown) -> Result<usize>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let (id, mut rx) = self.subscribe_event_with_id(event_type).await?;
+        let mut count = 0;
+        loop {
+            tokio::select! {
+                msg = rx.recv() => {
+                    let Some(msg) = msg else {
+                        break;
+                    };
+                    match format.serialize(&msg) {
+                        Ok(document) => {
+                            writer.write_all(document.as_bytes()).await?;
+                            count += 1;
+                        },
+                        Err(e) => tracing::warn!("record_subscription: skipping event id={:?}: {}", msg.id(), e),
+                    }
+                },
+                _ = cancel.recv() => {
+                    if let Err(e) = self.unsubscribe(id).await {
+                        tracing::debug!("record_subscription: could not unsubscribe {}: {}", id, e);
+                    }
+                    break;
+                },
+            }
+        }
+        writer.flush().await?;
+        Ok(count)
+    }
+
+    /// Subscribes to the events of `event_type`, or to all of them if `None`.
+    ///
+    /// The returned [Subscription] unsubscribes from HA once dropped.
+    pub async fn subscribe_event(&self, event_type: Option<json::EventType>) -> Result<Subscription> {
+        self.subscribe_event_filtered(event_type, EventFilter::default()).await
+    }
+
+    /// Same as [WsApi::subscribe_event()], but returns the id of the subscription
+    /// and a plain receiver, leaving it up to the caller to cancel the subscription
+    /// with [WsApi::unsubscribe()].
+    pub async fn subscribe_event_with_id(&self, event_type: Option<json::EventType>) -> Result<(Id, mpsc::Receiver<WsMessage>)> {
+        self.subscribe_event_filtered_with_id(event_type, EventFilter::default()).await
+    }
+
+    /// Same as [WsApi::subscribe_event()], but only the events matching `filter`,
+    /// e.g. those of `LOCAL` origin, reach the returned [Subscription].
+    ///
+    /// Events are filtered by the messenger before being forwarded, and the filter
+    /// is kept across [WsApi::reconnect()].
+    pub async fn subscribe_event_filtered(&self, event_type: Option<json::EventType>, filter: EventFilter) -> Result<Subscription> {
+        let (id, rx, generation) = self.subscribe_stamped(event_type, filter).await?;
+        Ok(Subscription::new(id, generation, rx, self.tx.clone(), self.id.clone(), self.subscriptions.clone()))
+    }
+
+    /// Same as [WsApi::subscribe_event_filtered()], but returns the id of the
+    /// subscription and a plain receiver, as [WsApi::subscribe_event_with_id()].
+    pub async fn subscribe_event_filtered_with_id(&self, event_type: Option<json::EventType>, filter: EventFilter) -> Result<(Id, mpsc::Receiver<WsMessage>)> {
+        self.subscribe_stamped(event_type, filter).await.map(|(id, rx, _)| (id, rx))
+    }
+
+    /// Same as [WsApi::subscribe_event_filtered_with_id()], but also returns the
+    /// generation of the registry the subscription was added to.
+    async fn subscribe_stamped(&self, event_type: Option<json::EventType>, filter: EventFilter) -> Result<(Id, mpsc::Receiver<WsMessage>, u64)> {
         let (id, mut rx) = self.registration().await?;
         tracing::debug!("subscribe_event: registration()=({}, {:p})", id, &rx);
+        if !filter.is_empty() {
+            self.send_command(Command::Filter(id, filter.clone())).await?;
+        }
         self.send_command(Command::Message(WsMessage::SubscribeEvents { id, event_type })).await?;
         tracing::debug!("subscribe_event: send_command()");
 
-        let reply = rx.recv().await
-            .ok_or(Error::InternalError { cause: anyhow!("missing response")})?;
+        let mut early = Vec::new();
+        subscription_ack(&mut rx, &mut early).await?;
+        let generation = {
+            let mut registry = self.subscriptions.lock().unwrap();
+            registry.active.insert(id, (event_type, filter));
+            registry.generation
+        };
+        Ok((id, with_early(rx, early, self.options.channel_bound), generation))
+    }
 
-        tracing::debug!("subscribe_event: recv()={:?}", &reply);
-        result_or_error(reply, rx)
+    /// Returns the event type the active subscription `id` was created for,
+    /// which is `Some(None)` when subscribed to all events, or `None` if there
+    /// is no such subscription.
+    pub fn subscription_event_type(&self, id: Id) -> Option<Option<json::EventType>> {
+        self.subscriptions.lock().unwrap().active.get(&id).map(|(event_type, _)| *event_type)
+    }
+
+    /// Same as [WsApi::subscribe_event()], but events whose context has either
+    /// `user_id` or `parent_id` equal to `context_id` are dropped before reaching
+    /// the returned receiver.
+    ///
+    /// This allows e.g. automations to ignore the events caused by their own
+    /// service calls, avoiding feedback loops.
+    pub async fn subscribe_event_excluding_context(&self, event_type: Option<json::EventType>, context_id: &str) -> Result<mpsc::Receiver<WsMessage>> {
+        let mut events = self.subscribe_event(event_type).await?;
+        let (tx, rx) = mpsc::channel(self.options.channel_bound);
+        let context_id = context_id.to_owned();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    msg = events.recv() => match msg {
+                        Some(msg) if is_from_context(&msg, &context_id) => {
+                            tracing::trace!("subscribe_event_excluding_context: dropped {}", msg);
+                        },
+                        Some(msg) => {
+                            if tx.send(msg).await.is_err() {
+                                break;
+                            }
+                        },
+                        None => break,
+                    },
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+        Ok(rx)
     }
 
     pub async fn subscribe_events(&self, event_types: &[json::EventType]) -> Result<mpsc::Receiver<WsMessage>> {
-        let (tx, mut rx) = mpsc::channel(MPSC_CHANNEL_BOUND);
+        self.subscribe_events_ids(event_types).await.map(|(_, rx)| rx)
+    }
+
+    /// Subscribes to all the given `event_types` at once, returning a single
+    /// `Id` standing for the whole group together with the receiver of their events.
+    ///
+    /// HA only accepts one event type per `subscribe_events` command, hence this is
+    /// still implemented as one subscription per type under the hood: events keep
+    /// carrying the `Id` of their own subscription. The returned `Id` is never sent
+    /// to HA, and is meant to be passed to [WsApi::unsubscribe()] to cancel all of
+    /// the underlying subscriptions in one go.
+    pub async fn subscribe_event_types_merged(&self, event_types: &[json::EventType]) -> Result<(Id, mpsc::Receiver<WsMessage>)> {
+        let (ids, rx) = self.subscribe_events_ids(event_types).await?;
+        let merged_id = self.id.next();
+        tracing::debug!("subscribe_event_types_merged: id={} stands for {:?}", merged_id, ids);
+        self.merged.lock().unwrap().insert(merged_id, ids);
+        Ok((merged_id, rx))
+    }
+
+    async fn subscribe_events_ids(&self, event_types: &[json::EventType]) -> Result<(Vec<Id>, mpsc::Receiver<WsMessage>)> {
+        let (tx, mut rx) = mpsc::channel(self.options.channel_bound);
+        let mut ids = Vec::with_capacity(event_types.len());
+        // Events of the subscriptions acknowledged so far may arrive while
+        // waiting for the next acknowledgement, and are held back as well
+        let mut early = Vec::new();
         for event_type in event_types {
             let id = self.registration_ch(tx.clone()).await?;
             self.send_command(Command::Message(WsMessage::SubscribeEvents {
                 id, event_type: Some(*event_type)
             })).await?;
 
-            loop {
-                let reply = rx.recv().await
-                    .ok_or(Error::InternalError { cause: anyhow!("missing response") })?;
-                match result_or_error(reply, ()) {
-                    Ok(_) => break,
-                    Err(Error::UnexpectedMessage(e)) => {
-                        tracing::warn!("unexpected message dropped: {:?}", e);
-                        continue;
-                    },
-                    Err(e) => return Err(e)
-                }
-            }
+            subscription_ack(&mut rx, &mut early).await?;
+            self.subscriptions.lock().unwrap().active.insert(id, (Some(*event_type), EventFilter::default()));
+            ids.push(id);
         }
-        Ok(rx)
+        Ok((ids, with_early(rx, early, self.options.channel_bound)))
     }
 
+    /// Cancels the given `subscription`, which may either be the `Id` of a single
+    /// subscription or one returned by [WsApi::subscribe_event_types_merged()].
+    ///
+    /// In the latter case, all of the underlying subscriptions are cancelled, and
+    /// the reply to the last one is returned. Replies other than a `Result` make
+    /// it fail with [Error::UnexpectedMessage].
     pub async fn unsubscribe(&self, subscription: Id) -> Result<WsMessage> {
-        let (id, mut rx) = self.registration().await?;
-        // Unsubscribe from WS
-        self.send_command(Command::Message(
-            WsMessage::UnsubscribeEvents { id, subscription }
-        )).await?;
-        // Collect result
-        let res = rx.recv().await.ok_or(Error::NoNextMessage);
+        let merged = self.merged.lock().unwrap().remove(&subscription);
+        match merged {
+            Some(ids) => {
+                let mut res = WsMessage::new_result_success(subscription);
+                for id in ids {
+                    res = self.unsubscribe_single(id).await?;
+                }
+                Ok(res)
+            },
+            None => self.unsubscribe_single(subscription).await,
+        }
+    }
+
+    /// Temporarily stops delivering the events of `subscription` to its
+    /// receiver, without unsubscribing from HA, e.g. to shed load while busy.
+    ///
+    /// Events received meanwhile are buffered, up to [WsApiOptions::pause_buffer]
+    /// of them, after which the oldest ones are dropped.
+    pub async fn pause_subscription(&self, subscription: Id) -> Result<()> {
+        for id in self.subscription_ids(subscription) {
+            self.send_command(Command::Pause(id)).await?;
+        }
+        Ok(())
+    }
+
+    /// Delivers the events buffered since [WsApi::pause_subscription()], and
+    /// then the new ones as usual.
+    pub async fn resume_subscription(&self, subscription: Id) -> Result<()> {
+        for id in self.subscription_ids(subscription) {
+            self.send_command(Command::Resume(id)).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the ids of the HA subscriptions behind `subscription`, which are
+    /// more than one for those made via [WsApi::subscribe_event_types_merged()].
+    fn subscription_ids(&self, subscription: Id) -> Vec<Id> {
+        self.merged.lock().unwrap()
+            .get(&subscription)
+            .cloned()
+            .unwrap_or_else(|| vec![subscription])
+    }
+
+    async fn unsubscribe_single(&self, subscription: Id) -> Result<WsMessage> {
+        let res = match self.request(WsMessage::UnsubscribeEvents { id: 0, subscription }).await {
+            Ok(reply @ WsMessage::Result { .. }) => Ok(reply),
+            Ok(reply) => Err(Error::UnexpectedMessage(reply)),
+            Err(e) => Err(e),
+        };
         // Unregister message dispatching
+        self.subscriptions.lock().unwrap().active.remove(&subscription);
         self.send_command(Command::Unregister(subscription)).await?;
-        self.send_command(Command::Unregister(id)).await?;
         res
     }
 }
 
+/// Spawns the `WsApiMessenger` task handling `socket`, returning the channels to
+/// send it commands, to receive the messages not associated to any registration,
+/// and to watch for its termination.
+fn spawn_messenger(socket: Option<WebSocketStream>, id: Arc<AtomicId>, shutdown: Shutdown, options: &WsApiOptions, recent: Option<Arc<RecentEvents>>)
+    -> (mpsc::Sender<Command>, mpsc::Receiver<WsMessage>, watch::Receiver<bool>)
+{
+    let (tx, rx) = mpsc::channel(options.channel_bound);
+    let (unhandled_tx, unhandled_rx) = mpsc::channel(options.channel_bound);
+    let (closed_tx, closed_rx) = watch::channel(false);
+    let messenger = WsApiMessenger::new(rx, socket, id, Some(unhandled_tx), shutdown, options, recent);
+    tokio::spawn(async move {
+        if let Err(e) = messenger.run().await {
+            tracing::error!("messenger task fatal error: {}", e);
+        }
+        tracing::info!("messenger task terminated");
+        let _ = closed_tx.send(true);
+    });
+    (tx, unhandled_rx, closed_rx)
+}
+
+/// Waits on `rx` for HA to acknowledge a subscription, failing if it is rejected.
+///
+/// HA may send events before acknowledging the subscription under races: they
+/// are held back in `early`, to be delivered first via [with_early()].
+async fn subscription_ack(rx: &mut mpsc::Receiver<WsMessage>, early: &mut Vec<WsMessage>) -> Result<()> {
+    loop {
+        let reply = rx.recv().await
+            .ok_or(Error::InternalError { cause: anyhow!("missing response") })?;
+        tracing::debug!("subscription_ack: recv()={:?}", &reply);
+        match reply {
+            WsMessage::Event { .. } => early.push(reply),
+            reply => return result_or_error(reply, ()),
+        }
+    }
+}
+
+/// Returns a receiver yielding the `early` messages first, and then those of `rx`.
+fn with_early(rx: mpsc::Receiver<WsMessage>, early: Vec<WsMessage>, bound: usize) -> mpsc::Receiver<WsMessage> {
+    if early.is_empty() {
+        return rx;
+    }
+    let (tx, early_rx) = mpsc::channel(bound.max(early.len()));
+    for msg in early {
+        let _ = tx.try_send(msg);
+    }
+    spawn_forward(rx, tx);
+    early_rx
+}
+
+/// Spawns a task forwarding all messages from `rx` to `tx`, until either gets closed.
+fn spawn_forward(mut rx: mpsc::Receiver<WsMessage>, tx: mpsc::Sender<WsMessage>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some(msg) => {
+                        if tx.send(msg).await.is_err() {
+                            break;
+                        }
+                    },
+                    None => break,
+                },
+                _ = tx.closed() => break,
+            }
+        }
+    });
+}
+
+/// Feeds messages to a [WsApi::channel_backed()] instance.
+#[cfg(any(feature = "test-support", test))]
+#[derive(Clone, Debug)]
+pub struct TestInjector {
+    tx: mpsc::Sender<Command>,
+}
+
+#[cfg(any(feature = "test-support", test))]
+impl TestInjector {
+    /// Dispatches `msg` to the receivers of the [WsApi] as if it came from HA,
+    /// e.g. an event for one of its subscriptions.
+    pub async fn inject(&self, msg: WsMessage) -> Result<()> {
+        self.tx.send(Command::Inject(msg)).await
+            .map_err(|e| Error::InternalError { cause: anyhow!("could not inject message: {}", e) })
+    }
+}
+
+fn is_from_context(msg: &WsMessage, context_id: &str) -> bool {
+    msg.context().is_some_and(|context| {
+        context.user_id.as_deref() == Some(context_id)
+            || context.parent_id.as_deref() == Some(context_id)
+    })
+}
+
+/// Whether `reply` is the error sent by HA for commands whose id was already used.
+fn is_id_reuse(reply: &WsMessage) -> bool {
+    matches!(reply, WsMessage::Result { success: false, data: json::ResultBody::Error { error }, .. } if error.code == "id_reuse")
+}
+
 fn result_or_error<T>(reply: WsMessage, result: T) -> Result<T> {
     match reply {
         WsMessage::Result { success: true, data: json::ResultBody::Result { .. }, .. } => {
             Ok(result)
         },
+        WsMessage::Result { id, .. } if is_id_reuse(&reply) => {
+            Err(Error::IdReuse(id))
+        },
         WsMessage::Result { success: false, data: json::ResultBody::Error { error }, ..} => {
             Err(Error::from(error))
         },
@@ -254,9 +1098,28 @@ fn result_or_error<T>(reply: WsMessage, result: T) -> Result<T> {
 }
 
 
-async fn connect_ws(url: &Url) -> Result<WebSocketStream> {
-    let (socket, response) = connect_async(url).await?;
+async fn connect_ws(url: &Url, options: &WsApiOptions) -> Result<WebSocketStream> {
+    let mut request = url.as_str().into_client_request()?;
+    for (name, value) in &options.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| Error::InvalidHeader(format!("{}: {}", name, e)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| Error::InvalidHeader(format!("{}: {}", name, e)))?;
+        request.headers_mut().append(name, value);
+    }
+    if !options.subprotocols.is_empty() {
+        let value = HeaderValue::from_str(&options.subprotocols.join(", "))
+            .map_err(|e| Error::InvalidHeader(format!("{}: {}", SEC_WEBSOCKET_PROTOCOL, e)))?;
+        request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+    }
+    let (socket, response) = connect_async(request).await?;
     tracing::trace!("connect({}): {:?}", url, response);
+    // Not checked by tungstenite, see RFC 6455, section 4.1
+    if let Some(protocol) = response.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+        if !options.subprotocols.iter().any(|p| protocol == p.as_str()) {
+            return Err(Error::InvalidHeader(format!("{}: {:?} was not offered", SEC_WEBSOCKET_PROTOCOL, protocol)));
+        }
+    }
     Ok(socket)
 }
 
@@ -268,6 +1131,178 @@ mod tests {
     use super::*;
     use crate::sync::shutdown;
 
+    fn event(id: Id, context_id: &str) -> WsMessage {
+        WsMessage::Event {
+            id,
+            event: json::EventObj::Event {
+                data: serde_json::json!({}),
+                event_type: json::EventType::StateChanged,
+                time_fired: chrono::Utc::now(),
+                origin: "LOCAL".to_owned(),
+                context: json::ContextObject { id: context_id.to_owned(), ..Default::default() },
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_backed_inject() {
+        let manager = shutdown::Manager::new();
+        let (wsapi, injector) = WsApi::channel_backed(manager.subscribe());
+        let (id, mut rx) = wsapi.subscribe_event_with_id(Some(json::EventType::StateChanged)).await.unwrap();
+
+        injector.inject(event(id, "first")).await.unwrap();
+        injector.inject(event(id + 1, "elsewhere")).await.unwrap();
+        injector.inject(event(id, "second")).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().context().unwrap().id, "first");
+        assert_eq!(rx.recv().await.unwrap().context().unwrap().id, "second");
+        assert!(rx.try_recv().is_err());
+
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn channel_backed_unsubscribe() {
+        let manager = shutdown::Manager::new();
+        let (wsapi, injector) = WsApi::channel_backed(manager.subscribe());
+        let (id, mut rx) = wsapi.subscribe_event_with_id(None).await.unwrap();
+
+        let res = wsapi.unsubscribe(id).await.unwrap();
+        assert!(matches!(res, WsMessage::Result { success: true, .. }));
+
+        // Nothing is dispatched to the cancelled subscription anymore
+        injector.inject(event(id, "late")).await.unwrap();
+        assert!(rx.recv().await.is_none());
+
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn channel_backed_pause() {
+        let manager = shutdown::Manager::new();
+        let (wsapi, injector) = WsApi::channel_backed(manager.subscribe());
+        let (id, mut rx) = wsapi.subscribe_event_with_id(None).await.unwrap();
+        let buffer = wsapi.options.pause_buffer;
+
+        wsapi.pause_subscription(id).await.unwrap();
+        for n in 0..buffer + 2 {
+            injector.inject(event(id, &n.to_string())).await.unwrap();
+        }
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(50), rx.recv()).await.is_err());
+
+        // The two oldest events were dropped
+        wsapi.resume_subscription(id).await.unwrap();
+        for n in 2..buffer + 2 {
+            assert_eq!(rx.recv().await.unwrap().context().unwrap().id, n.to_string());
+        }
+        injector.inject(event(id, "resumed")).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().context().unwrap().id, "resumed");
+
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn channel_backed_filter_after() {
+        let manager = shutdown::Manager::new();
+        let (wsapi, injector) = WsApi::channel_backed(manager.subscribe());
+        let since = chrono::Utc::now();
+        let filter = EventFilter::new().after(since);
+        let (id, mut rx) = wsapi.subscribe_event_filtered_with_id(None, filter.clone()).await.unwrap();
+
+        let fired_at = |context_id, at| {
+            let mut msg = event(id, context_id);
+            if let WsMessage::Event { event: json::EventObj::Event { time_fired, .. }, .. } = &mut msg {
+                *time_fired = at;
+            }
+            msg
+        };
+        injector.inject(fired_at("old", since)).await.unwrap();
+        injector.inject(fired_at("new", since + chrono::Duration::milliseconds(1))).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().context().unwrap().id, "new");
+        assert!(rx.try_recv().is_err());
+        assert_eq!(wsapi.subscriptions.lock().unwrap().active.get(&id), Some(&(None, filter)));
+
+        manager.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_burst() {
+        let manager = shutdown::Manager::new();
+        let options = WsApiOptions::builder().keepalive_interval(None).rate_limit(Some(10)).build();
+        let (wsapi, _injector) = WsApi::channel_backed_with(manager.subscribe(), options);
+
+        // The first 10 go right away, the other 15 one every 100ms
+        let start = tokio::time::Instant::now();
+        let fired = future::join_all((0..25).map(|_| wsapi.fire_event(json::EventType::HaevloStart, None))).await;
+        assert!(fired.iter().all(|res| matches!(res, Ok(WsMessage::Result { success: true, .. }))));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= std::time::Duration::from_millis(1500), "{:?}", elapsed);
+        assert!(elapsed < std::time::Duration::from_millis(1600), "{:?}", elapsed);
+
+        // A quiet second refills the bucket
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let start = tokio::time::Instant::now();
+        future::join_all((0..10).map(|_| wsapi.fire_event(json::EventType::HaevloStart, None))).await;
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+
+        manager.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_queue_does_not_stall() {
+        let manager = shutdown::Manager::new();
+        let options = WsApiOptions::builder().keepalive_interval(None).rate_limit(Some(1)).build();
+        let (wsapi, injector) = WsApi::channel_backed_with(manager.subscribe(), options);
+        let (id, mut rx) = wsapi.subscribe_event_with_id(None).await.unwrap();
+
+        // The bucket is empty: these are queued, 1 per second
+        for _ in 0..5 {
+            let msg = WsMessage::FireEvent { id: wsapi.next_id(), event_type: json::EventType::HaevloStart, event_data: None };
+            wsapi.send_raw(msg).await.unwrap();
+        }
+        let start = tokio::time::Instant::now();
+        injector.inject(event(id, "meanwhile")).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().context().unwrap().id, "meanwhile");
+
+        // Pings skip the queue
+        let ping = wsapi.next_id();
+        let pong = wsapi.register_oneshot(ping).await.unwrap();
+        wsapi.send_raw(WsMessage::Ping { id: ping }).await.unwrap();
+        assert!(matches!(pong.await.unwrap(), WsMessage::Pong { .. }));
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unhandled_flood_does_not_stall() {
+        let manager = shutdown::Manager::new();
+        let (wsapi, injector) = WsApi::channel_backed(manager.subscribe());
+        let (id, mut rx) = wsapi.subscribe_event_with_id(None).await.unwrap();
+
+        // Nobody reads messages with no id past authentication
+        let unhandled: WsMessage = serde_json::from_str("{\"type\": \"future_thing\"}").unwrap();
+        let flood = async {
+            for _ in 0..wsapi.options.channel_bound * 3 {
+                injector.inject(unhandled.clone()).await.unwrap();
+            }
+            injector.inject(event(id, "after")).await.unwrap();
+            rx.recv().await
+        };
+        let msg = tokio::time::timeout(std::time::Duration::from_secs(1), flood).await
+            .expect("subscription stalled by unhandled messages");
+        assert_eq!(msg.unwrap().context().unwrap().id, "after");
+
+        manager.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn channel_backed_get_states_empty() {
+        let manager = shutdown::Manager::new();
+        let (wsapi, _injector) = WsApi::channel_backed(manager.subscribe());
+        assert!(wsapi.get_states().await.unwrap().is_empty());
+        manager.shutdown().await;
+    }
+
     #[tokio::test]
     async fn new_unknown_host() {
         let manager = shutdown::Manager::new();
@@ -288,5 +1323,32 @@ mod tests {
         manager.shutdown().await;
         println!("completed shutdown? how?");
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn late_message_for_unregistered_id() {
+        use crate::hast::server::{Hast, HastConfig};
+
+        const PORT: u16 = 18124;
+        let manager = shutdown::Manager::new();
+        let yaml_dir = format!("{}/tests/resources/", env!("CARGO_MANIFEST_DIR"));
+        let cfg = HastConfig::new_with_scenario(PORT, "letmein".to_owned(), yaml_dir, Some("000-base.yaml".to_owned()));
+        let hast = Hast::new(cfg, manager.subscribe());
+        let mut startup_notifier = hast.startup_notifier();
+        tokio::spawn(hast.run());
+        let _ = startup_notifier.changed().await;
+
+        let wsapi = WsApi::new_unsecure("127.0.0.1", PORT, "letmein", manager.subscribe()).await.unwrap();
+        let (ids, _rx) = wsapi.subscribe_events_ids(&[json::EventType::StateChanged]).await.unwrap();
+        wsapi.unsubscribe(ids[0]).await.unwrap();
+
+        // Have hast reply with the id of the cancelled subscription
+        wsapi.send_command(Command::Message(WsMessage::Ping { id: ids[0] })).await.unwrap();
+
+        // The messenger must still be serving requests
+        let mut rx = wsapi.subscribe_event(None).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(WsMessage::Event { .. })));
+
+        manager.shutdown().await;
+    }
 }
 
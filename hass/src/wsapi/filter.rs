@@ -0,0 +1,59 @@
+use chrono::{DateTime, Utc};
+
+use crate::json::{EventObj, WsMessage};
+
+/// Origin of the events fired by the HA instance itself.
+pub const ORIGIN_LOCAL: &str = "LOCAL";
+
+/// Origin of the events fired by other instances or API clients.
+pub const ORIGIN_REMOTE: &str = "REMOTE";
+
+/// Filter on the events of a subscription, see [super::WsApi::subscribe_event_filtered()].
+///
+/// Events are dropped by the messenger before reaching the receiver of the
+/// subscription unless they match all the criteria set. The default filter
+/// has none, and lets every event through.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct EventFilter {
+    origin: Option<String>,
+    after: Option<DateTime<Utc>>,
+}
+
+impl EventFilter {
+    /// Returns a filter letting every event through, to be narrowed down.
+    pub fn new() -> EventFilter {
+        EventFilter::default()
+    }
+
+    /// Keeps only the events whose `origin` is `origin`, e.g. [ORIGIN_LOCAL].
+    pub fn origin(mut self, origin: impl Into<String>) -> EventFilter {
+        self.origin = Some(origin.into());
+        self
+    }
+
+    /// Keeps only the events fired by the HA instance itself.
+    pub fn local_only(self) -> EventFilter {
+        self.origin(ORIGIN_LOCAL)
+    }
+
+    /// Keeps only the events fired strictly after `instant`.
+    pub fn after(mut self, instant: DateTime<Utc>) -> EventFilter {
+        self.after = Some(instant);
+        self
+    }
+
+    /// Whether the filter has no criteria, letting every event through.
+    pub fn is_empty(&self) -> bool {
+        self.origin.is_none() && self.after.is_none()
+    }
+
+    /// Whether `msg` passes the filter. Messages other than events, e.g. the
+    /// results of requests, and trigger events always do.
+    pub fn matches(&self, msg: &WsMessage) -> bool {
+        let WsMessage::Event { event: EventObj::Event { time_fired, origin, .. }, .. } = msg else {
+            return true;
+        };
+        self.origin.as_ref().is_none_or(|o| o == origin)
+            && self.after.is_none_or(|after| *time_fired > after)
+    }
+}
@@ -1,71 +1,238 @@
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::anyhow;
 use futures_util::{SinkExt, StreamExt};
-use tokio::sync::mpsc;
-use tokio::time::{self, MissedTickBehavior};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant, Interval, MissedTickBehavior, Sleep};
 use tokio_tungstenite::{self, tungstenite::Message};
 
 use crate::error::{Error, Result};
 use crate::json::{self, Id, WsMessage};
 use crate::sync::{atomic::AtomicId, shutdown::Shutdown};
 
-use super::{WebSocketStream, KEEPALIVE_INTERVAL_SEC};
+use super::{EventFilter, WebSocketStream, WsApiOptions, TRAFFIC_TARGET};
 
 /// Represents commands understood by the `WsApiMessenger`.
 #[derive(Debug)]
 pub enum Command {
     Message(WsMessage),
     Register(Id, mpsc::Sender<WsMessage>),
+    /// Registers a receiver for the next message with the id only
+    RegisterOneshot(Id, oneshot::Sender<WsMessage>),
     Unregister(Id),
+    /// Drops the events for the id not matching the filter
+    Filter(Id, EventFilter),
+    /// Buffers the messages for the id instead of dispatching them
+    Pause(Id),
+    /// Dispatches the messages buffered for the id, and then the new ones
+    Resume(Id),
+    /// Hands the registered receivers over, then closes the connection
+    Handover(oneshot::Sender<BTreeMap<Id, mpsc::Sender<WsMessage>>>),
+    /// Dispatches the message as if it had been received from HA
+    #[cfg(any(feature = "test-support", test))]
+    Inject(WsMessage),
 }
 
+/// Bounded buffer of the most recent events received by the `WsApiMessenger`,
+/// shared with the `WsApi`.
+#[derive(Debug)]
+pub struct RecentEvents {
+    capacity: usize,
+    events: Mutex<VecDeque<WsMessage>>,
+}
+
+impl RecentEvents {
+    pub fn new(capacity: usize) -> RecentEvents {
+        RecentEvents {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Appends `msg`, evicting the oldest event when full
+    fn push(&self, msg: &WsMessage) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(msg.clone());
+    }
+
+    /// Returns the buffered events, oldest first
+    pub fn to_vec(&self) -> Vec<WsMessage> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Token bucket limiting the rate of the messages sent by the `WsApiMessenger`.
+#[derive(Debug)]
+struct RateLimiter {
+    /// Tokens added per second, which is also the capacity of the bucket
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32) -> RateLimiter {
+        let rate = f64::from(per_second.max(1));
+        RateLimiter { rate, tokens: rate, refilled: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+
+    /// Takes a token from the bucket, if any is left.
+    fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// The instant the next token becomes available.
+    fn next_token(&self) -> Instant {
+        self.refilled + Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / self.rate)
+    }
+}
 
 pub struct WsApiMessenger {
     rx: mpsc::Receiver<Command>,
-    socket: WebSocketStream,
+    /// Connection to HA, or `None` when backed by channels only, for testing
+    socket: Option<WebSocketStream>,
     id: Arc<AtomicId>,
     receivers: BTreeMap<Id, mpsc::Sender<WsMessage>>,
+    oneshots: BTreeMap<Id, oneshot::Sender<WsMessage>>,
+    /// Filters of the events dispatched to registered receivers
+    filters: BTreeMap<Id, EventFilter>,
+    /// Messages held back for paused ids, oldest first
+    paused: BTreeMap<Id, VecDeque<WsMessage>>,
     unhandled: Option<mpsc::Sender<WsMessage>>,
     
     /// Receives shutdown signal and notifies back about completed shutdown
     /// once dropped.
     shutdown: Shutdown,
+
+    /// Interval between keepalive pings, if enabled
+    keepalive: Option<Duration>,
+
+    /// Whether keepalive pings are sent even with no registered receivers
+    keepalive_always: bool,
+
+    /// Time without messages from HA after which the connection is closed, if enabled
+    idle_timeout: Option<Duration>,
+
+    /// Most recent events received, if enabled
+    recent: Option<Arc<RecentEvents>>,
+
+    /// Maximum number of messages held back for each paused id
+    pause_buffer: usize,
+
+    /// Whether messages sent and received are logged to [TRAFFIC_TARGET]
+    trace_traffic: bool,
+
+    /// Limits the rate of the messages sent, if enabled
+    limiter: Option<RateLimiter>,
+
+    /// Messages held back by the rate limit, oldest first
+    outbox: VecDeque<WsMessage>,
 }
 
 impl WsApiMessenger {
-    pub fn new(rx: mpsc::Receiver<Command>, socket: WebSocketStream, id: Arc<AtomicId>, unhandled: Option<mpsc::Sender<WsMessage>>, shutdown: Shutdown) -> WsApiMessenger {
+    pub fn new(rx: mpsc::Receiver<Command>, socket: Option<WebSocketStream>, id: Arc<AtomicId>, unhandled: Option<mpsc::Sender<WsMessage>>, shutdown: Shutdown, options: &WsApiOptions, recent: Option<Arc<RecentEvents>>) -> WsApiMessenger {
         WsApiMessenger {
             rx,
             socket,
             id,
             unhandled,
             shutdown,
+            keepalive: options.keepalive_interval,
+            keepalive_always: options.keepalive_always,
+            idle_timeout: options.idle_timeout,
+            recent,
+            pause_buffer: options.pause_buffer,
+            trace_traffic: options.trace_traffic,
+            limiter: options.rate_limit.map(RateLimiter::new),
+            outbox: VecDeque::new(),
             receivers: BTreeMap::new(),
+            oneshots: BTreeMap::new(),
+            filters: BTreeMap::new(),
+            paused: BTreeMap::new(),
         }
     }
 
     pub async fn run(mut self) -> Result<()> {
-        let mut keepalive = time::interval(Duration::from_secs(KEEPALIVE_INTERVAL_SEC));
-        keepalive.tick().await;
-        keepalive.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut keepalive = match self.keepalive {
+            Some(period) => {
+                let mut keepalive = time::interval(period);
+                keepalive.tick().await;
+                keepalive.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                Some(keepalive)
+            },
+            None => None,
+        };
+        let mut idle = self.idle_timeout.map(|timeout| Box::pin(time::sleep(timeout)));
 
         loop {
+            let release_at = self.next_release();
             tokio::select! {
                 // Event on the command channel
                 cmd = self.rx.recv() => match cmd {
                     Some(cmd) => match cmd {
                         Command::Message(msg) => {
-                            self.send(msg).await?;
-                            keepalive.reset();
+                            self.submit(msg).await?;
+                            if let Some(keepalive) = keepalive.as_mut() {
+                                keepalive.reset();
+                            }
                         },
                         Command::Register(id, reg_sender) => {
                             self.register(id, reg_sender);
                         },
+                        Command::RegisterOneshot(id, reg_sender) => {
+                            tracing::debug!("registered oneshot receiver for id={}", id);
+                            self.oneshots.insert(id, reg_sender);
+                        },
                         Command::Unregister(id) => {
                             self.receivers.remove(&id);
+                            self.oneshots.remove(&id);
+                            self.filters.remove(&id);
+                            self.paused.remove(&id);
+                        },
+                        Command::Filter(id, filter) => {
+                            tracing::debug!("filtering events for id={}: {:?}", id, filter);
+                            self.filters.insert(id, filter);
+                        },
+                        Command::Pause(id) => {
+                            if self.receivers.contains_key(&id) {
+                                self.paused.entry(id).or_default();
+                            } else {
+                                tracing::debug!("ignored pause of id={}: no receiver", id);
+                            }
+                        },
+                        Command::Resume(id) => {
+                            if let Err(e) = self.resume(id).await {
+                                tracing::warn!("{}", e);
+                            }
+                        },
+                        Command::Handover(tx) => {
+                            tracing::info!("handing over {} receivers", self.receivers.len());
+                            let _ = tx.send(std::mem::take(&mut self.receivers));
+                            break;
+                        },
+                        #[cfg(any(feature = "test-support", test))]
+                        Command::Inject(msg) => {
+                            if let Err(e) = self.dispatch(msg).await {
+                                tracing::warn!("{}", e);
+                            }
                         },
                     },
                     None => {
@@ -75,12 +242,23 @@ impl WsApiMessenger {
                 },
 
                 // Event on the HA socket
-                rcv = self.socket.next() => match rcv {
+                rcv = next(&mut self.socket) => match rcv {
                     Some(Ok(rcv)) => {
                         if rcv.is_text() {
                             let msg = &rcv.into_text().unwrap();
+                            if self.trace_traffic {
+                                tracing::debug!(target: TRAFFIC_TARGET, "recv: {}", msg);
+                            }
                             let msg = json::deserialize(msg).unwrap();
-                            self.dispatch(msg).await;
+                            // Replies to our own pings do not count as activity
+                            if let (Some(idle), Some(timeout)) = (idle.as_mut(), self.idle_timeout) {
+                                if !matches!(msg, WsMessage::Pong { .. }) {
+                                    idle.as_mut().reset(Instant::now() + timeout);
+                                }
+                            }
+                            if let Err(e) = self.dispatch(msg).await {
+                                tracing::warn!("{}", e);
+                            }
                         } else {
                             // We usually only expect text messages from HA
                             tracing::error!("unexpected messaage: {:?}", rcv);
@@ -98,8 +276,21 @@ impl WsApiMessenger {
 
                 // Keepalive ping event
                 // HA will close the connection should it stop receiving messages
-                _ = keepalive.tick() => {
-                    self.send_ping().await?;
+                _ = tick(&mut keepalive) => {
+                    if self.keepalive_always || !self.receivers.is_empty() || !self.oneshots.is_empty() {
+                        self.send_ping().await?;
+                    }
+                },
+
+                // Rate limit event: messages held back may be sent
+                _ = release(release_at) => {
+                    self.release().await?;
+                },
+
+                // Idle timeout event
+                _ = expire(&mut idle) => {
+                    tracing::info!("idle timeout: no messages received for {:?}", self.idle_timeout.unwrap_or_default());
+                    break;
                 },
 
                 // System-wide shutdown event
@@ -116,16 +307,74 @@ impl WsApiMessenger {
         }
 
         self.rx.close();
-        let _ = self.socket.close(None).await;
+        if !self.outbox.is_empty() {
+            tracing::debug!("dropped {} messages held back by the rate limit", self.outbox.len());
+        }
+        if let Some(socket) = self.socket.as_mut() {
+            let _ = socket.close(None).await;
+        }
+
+        Ok(())
+    }
+
+    /// Send the given `msg` to HA right away if allowed by the rate limit, if
+    /// any, or hold it back until [WsApiMessenger::release()] otherwise
+    ///
+    /// Pings are control messages, exempt from the rate limit.
+    async fn submit(&mut self, msg: WsMessage) -> Result<()> {
+        let allowed = match self.limiter.as_mut() {
+            Some(_) if matches!(msg, WsMessage::Ping { .. }) => true,
+            Some(limiter) => self.outbox.is_empty() && limiter.try_acquire(),
+            None => true,
+        };
+        if !allowed {
+            tracing::trace!("rate limit: holding back msg with id={:?}", msg.id());
+            self.outbox.push_back(msg);
+            return Ok(());
+        }
+        self.send(msg).await
+    }
+
+    /// The instant the messages held back by the rate limit may be sent, if any
+    fn next_release(&self) -> Option<Instant> {
+        match self.limiter.as_ref() {
+            Some(limiter) if !self.outbox.is_empty() => Some(limiter.next_token()),
+            _ => None,
+        }
+    }
 
+    /// Send the messages held back by the rate limit, as long as it allows
+    async fn release(&mut self) -> Result<()> {
+        while !self.outbox.is_empty() {
+            if !self.limiter.as_mut().is_some_and(RateLimiter::try_acquire) {
+                break;
+            }
+            let msg = self.outbox.pop_front().unwrap();
+            self.send(msg).await?;
+        }
         Ok(())
     }
 
-    /// Send the given `msg` to HA
+    /// Send the given `msg` to HA, regardless of the rate limit
+    ///
+    /// Without a socket, commands are instead acknowledged right away, pings
+    /// with a pong and everything else with a successful result.
     async fn send(&mut self, msg: WsMessage) -> Result<()> {
+        let Some(socket) = self.socket.as_mut() else {
+            let reply = match msg {
+                WsMessage::Ping { id } => Some(WsMessage::Pong { id }),
+                msg => msg.id().map(WsMessage::new_result_success),
+            };
+            if let Some(reply) = reply {
+                self.dispatch(reply).await?;
+            }
+            return Ok(());
+        };
         let msg = json::serialize(&msg)?;
-        tracing::trace!("send({})", &msg);
-        self.socket.send(Message::Text(msg)).await?;
+        if self.trace_traffic {
+            tracing::debug!(target: TRAFFIC_TARGET, "send: {}", &msg);
+        }
+        socket.send(Message::Text(msg)).await?;
         Ok(())
     }
 
@@ -142,9 +391,44 @@ impl WsApiMessenger {
         let _ = self.receivers.insert(id, reg_sender);
     }
 
+    /// Dispatches the messages held back for `id` while paused, if any, and
+    /// stops holding them back.
+    async fn resume(&mut self, id: Id) -> Result<()> {
+        let Some(buffer) = self.paused.remove(&id) else {
+            return Ok(());
+        };
+        tracing::debug!("resuming id={} with {} buffered messages", id, buffer.len());
+        for msg in buffer {
+            self.dispatch(msg).await?;
+        }
+        Ok(())
+    }
+
     async fn dispatch(&mut self, msg: WsMessage) -> Result<()> {
         let id = msg.id();
 
+        if let (Some(recent), WsMessage::Event { .. }) = (self.recent.as_ref(), &msg) {
+            recent.push(&msg);
+        }
+
+        if let Some(filter) = id.and_then(|id| self.filters.get(&id)) {
+            if !filter.matches(&msg) {
+                tracing::trace!("dropped msg with id={:?}: filtered out: {}", id, &msg);
+                return Ok(());
+            }
+        }
+
+        if let Some(buffer) = id.and_then(|id| self.paused.get_mut(&id)) {
+            if buffer.len() >= self.pause_buffer {
+                let dropped = buffer.pop_front();
+                tracing::debug!("paused id={:?}: buffer full, dropped msg: {:?}", id, dropped);
+            }
+            if self.pause_buffer > 0 {
+                buffer.push_back(msg);
+            }
+            return Ok(());
+        }
+
         // This commented variant dispatches to self.unhandled, if defined,
         // even messages with and id. I'd rather not to however, because
         // there must be a reason why nobody registered to wait for them
@@ -152,29 +436,93 @@ impl WsApiMessenger {
         ////    .and_then(|id| { self.receivers.get(&id) })
         ////    .or_else(|| self.unhandled.as_ref());
 
-        let receiver = id.map_or_else(
-            || self.unhandled.as_ref(),
-            |id| { self.receivers.get(&id) });
+        if let Some(oneshot) = id.and_then(|id| self.oneshots.remove(&id)) {
+            tracing::debug!("dispatch to oneshot receiver msg with id={:?}", id);
+            if oneshot.send(msg).is_err() {
+                tracing::debug!("dropped msg with id={:?}: oneshot receiver dropped", id);
+            }
+            return Ok(());
+        }
+
+        if id.is_none() {
+            return self.dispatch_unhandled(msg);
+        }
 
-        if let Some(receiver) = receiver {
+        if let Some(receiver) = id.and_then(|id| self.receivers.get(&id)) {
             tracing::debug!("dispatch to receiver={:p} msg with id={:?}", receiver, id);
             if let Err(e) = receiver.send(msg).await {
                 if let Some(id) = id {
                     self.receivers.remove(&id);
-                } else {
-                    self.unhandled.take();
                 }
                 return Err(Error::InternalError {
                     cause: anyhow!("could not dispatch message: send failed: {}", e.0)
                 });
             }
         } else {
+            // Most likely a late reply or event for a subscription that has
+            // just been cancelled: nobody is waiting for it anymore
+            tracing::debug!("dropped msg with id={:?}: no receiver: {}", id, &msg);
+        }
+
+        Ok(())
+    }
+
+    /// Dispatches a message with no id to the unhandled receiver, which is only
+    /// read while authenticating: rather than stalling all other messages once
+    /// it is full, the message is dropped.
+    fn dispatch_unhandled(&mut self, msg: WsMessage) -> Result<()> {
+        let Some(unhandled) = self.unhandled.as_ref() else {
             return Err(Error::InternalError {
                 cause: anyhow!("could not dispatch message: no receiver: {}", &msg)
             });
+        };
+        match unhandled.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(msg)) => {
+                tracing::warn!("dropped msg with no id: nobody reading unhandled messages: {}", msg);
+                Ok(())
+            },
+            Err(mpsc::error::TrySendError::Closed(msg)) => {
+                self.unhandled.take();
+                Err(Error::InternalError {
+                    cause: anyhow!("could not dispatch message: send failed: {}", msg)
+                })
+            },
         }
+    }
 
-        Ok(())
+}
+
+/// Waits for the next message from `socket`, or forever if there is none.
+async fn next(socket: &mut Option<WebSocketStream>) -> Option<std::result::Result<Message, tokio_tungstenite::tungstenite::Error>> {
+    match socket {
+        Some(socket) => socket.next().await,
+        None => std::future::pending().await,
     }
+}
 
+/// Waits for the next tick of `interval`, or forever if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits until `deadline`, or forever if there is none.
+async fn release(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for `sleep` to complete, or forever if there is none.
+async fn expire(sleep: &mut Option<Pin<Box<Sleep>>>) {
+    match sleep {
+        Some(sleep) => sleep.await,
+        None => std::future::pending().await,
+    }
 }
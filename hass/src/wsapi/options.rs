@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use super::{MPSC_CHANNEL_BOUND, KEEPALIVE_INTERVAL_SEC, CONNECT_TIMEOUT_SEC};
+
+/// Connection options for [super::WsApi::connect_with()].
+///
+/// Use [WsApiOptions::default()] for the same behaviour as the plain
+/// `WsApi` constructors, or [WsApiOptions::builder()] to customize it.
+#[derive(Clone, Debug)]
+pub struct WsApiOptions {
+    /// Bound of the internal channels, including those returned for
+    /// subscriptions. Defaults to `128`.
+    pub channel_bound: usize,
+
+    /// Interval between keepalive pings, sent only when nothing else has
+    /// been sent for that long. `None` disables them. Defaults to 15 seconds.
+    pub keepalive_interval: Option<Duration>,
+
+    /// When false, keepalive pings are only sent while at least one request or
+    /// subscription is registered, sparing idle connections from them.
+    /// Defaults to `true`.
+    pub keepalive_always: bool,
+
+    /// Maximum time allowed to connect and authenticate, after which
+    /// [crate::error::Error::Timeout] is returned. `None` waits indefinitely.
+    /// Defaults to 10 seconds.
+    pub connect_timeout: Option<Duration>,
+
+    /// Time after which the connection is closed if no messages are received
+    /// from HA, replies to keepalive pings excluded. Subscriptions are then
+    /// notified by their receivers getting closed. `None`, the default,
+    /// keeps the connection open indefinitely.
+    pub idle_timeout: Option<Duration>,
+
+    /// Number of events buffered for each subscription paused via
+    /// [super::WsApi::pause_subscription()], after which the oldest ones get
+    /// dropped. Defaults to `128`.
+    pub pause_buffer: usize,
+
+    /// Number of the most recent events kept for [super::WsApi::recent_events()].
+    /// `0` disables the buffer altogether. Defaults to `0`.
+    pub recent_events: usize,
+
+    /// Additional `(name, value)` headers sent with the WebSocket handshake
+    /// request, e.g. as required by some reverse proxies. Empty by default.
+    pub headers: Vec<(String, String)>,
+
+    /// WebSocket subprotocols offered with the handshake request, in order of
+    /// preference. The connection fails if the server picks any other one.
+    /// Empty by default, i.e. no subprotocol.
+    pub subprotocols: Vec<String>,
+
+    /// When true, every message sent to or received from HA is logged at debug
+    /// level to the [super::TRAFFIC_TARGET] tracing target, which can then be
+    /// enabled alone, e.g. with `RUST_LOG=hass::traffic=debug`. Defaults to `false`.
+    pub trace_traffic: bool,
+
+    /// Maximum number of messages sent to HA per second, on average, bursts of
+    /// up to as many messages being allowed after a quiet second. Messages beyond
+    /// the limit are queued until their turn, rather than being dropped, while
+    /// messages from HA keep being dispatched. Keepalive pings are exempt from it.
+    /// `None`, the default, sends them as soon as possible.
+    pub rate_limit: Option<u32>,
+
+    /// When true, [super::WsApi::reconnect()] fetches the states of all entities
+    /// once the subscriptions are moved over, publishing them via
+    /// [super::WsApi::resyncs()], so that changes missed meanwhile can be caught
+    /// up on. Defaults to `false`.
+    pub resync_on_reconnect: bool,
+}
+
+impl WsApiOptions {
+    /// Returns a builder starting from the default options.
+    pub fn builder() -> WsApiOptionsBuilder {
+        WsApiOptionsBuilder::default()
+    }
+}
+
+impl Default for WsApiOptions {
+    fn default() -> Self {
+        WsApiOptions {
+            channel_bound: MPSC_CHANNEL_BOUND,
+            keepalive_interval: Some(Duration::from_secs(KEEPALIVE_INTERVAL_SEC)),
+            keepalive_always: true,
+            connect_timeout: Some(Duration::from_secs(CONNECT_TIMEOUT_SEC)),
+            idle_timeout: None,
+            pause_buffer: MPSC_CHANNEL_BOUND,
+            recent_events: 0,
+            headers: Vec::new(),
+            subprotocols: Vec::new(),
+            trace_traffic: false,
+            rate_limit: None,
+            resync_on_reconnect: false,
+        }
+    }
+}
+
+/// Builder for [WsApiOptions].
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use hass::wsapi::WsApiOptions;
+///
+/// let options = WsApiOptions::builder()
+///     .keepalive_interval(None)
+///     .connect_timeout(Some(Duration::from_secs(3)))
+///     .build();
+/// assert!(options.keepalive_interval.is_none());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct WsApiOptionsBuilder {
+    options: WsApiOptions,
+}
+
+impl WsApiOptionsBuilder {
+    /// Sets [WsApiOptions::channel_bound].
+    pub fn channel_bound(mut self, bound: usize) -> Self {
+        self.options.channel_bound = bound;
+        self
+    }
+
+    /// Sets [WsApiOptions::keepalive_interval].
+    pub fn keepalive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.options.keepalive_interval = interval;
+        self
+    }
+
+    /// Sets [WsApiOptions::keepalive_always].
+    pub fn keepalive_always(mut self, always: bool) -> Self {
+        self.options.keepalive_always = always;
+        self
+    }
+
+    /// Sets [WsApiOptions::connect_timeout].
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets [WsApiOptions::idle_timeout].
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets [WsApiOptions::pause_buffer].
+    pub fn pause_buffer(mut self, capacity: usize) -> Self {
+        self.options.pause_buffer = capacity;
+        self
+    }
+
+    /// Sets [WsApiOptions::recent_events].
+    pub fn recent_events(mut self, capacity: usize) -> Self {
+        self.options.recent_events = capacity;
+        self
+    }
+
+    /// Appends a header to [WsApiOptions::headers].
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Appends a subprotocol to [WsApiOptions::subprotocols].
+    pub fn subprotocol(mut self, name: impl Into<String>) -> Self {
+        self.options.subprotocols.push(name.into());
+        self
+    }
+
+    /// Sets [WsApiOptions::trace_traffic].
+    pub fn trace_traffic(mut self, enabled: bool) -> Self {
+        self.options.trace_traffic = enabled;
+        self
+    }
+
+    /// Sets [WsApiOptions::rate_limit].
+    pub fn rate_limit(mut self, per_second: Option<u32>) -> Self {
+        self.options.rate_limit = per_second;
+        self
+    }
+
+    /// Sets [WsApiOptions::resync_on_reconnect].
+    pub fn resync_on_reconnect(mut self, enabled: bool) -> Self {
+        self.options.resync_on_reconnect = enabled;
+        self
+    }
+
+    /// Returns the options built so far.
+    pub fn build(self) -> WsApiOptions {
+        self.options
+    }
+}
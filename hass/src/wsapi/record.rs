@@ -0,0 +1,26 @@
+use crate::json::WsMessage;
+
+/// Format of the events written by [super::WsApi::record_subscription()].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecordFormat {
+    /// One YAML document per event, the same format `haevlo` records and
+    /// [crate::hast] plays back.
+    #[cfg(feature = "serde_yaml")]
+    Yaml,
+
+    /// One compact JSON object per line.
+    Jsonl,
+}
+
+impl RecordFormat {
+    /// Serializes `msg` into a document ready to be appended to a recording.
+    pub(super) fn serialize(&self, msg: &WsMessage) -> Result<String, String> {
+        match self {
+            #[cfg(feature = "serde_yaml")]
+            RecordFormat::Yaml => serde_yaml::to_string(msg).map_err(|e| e.to_string()),
+            RecordFormat::Jsonl => serde_json::to_string(msg)
+                .map(|json| json + "\n")
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
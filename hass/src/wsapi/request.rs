@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::mpsc;
+
+use crate::error::{Error, Result};
+use crate::json::{Id, WsMessage};
+
+use super::messenger::Command;
+
+/// A request sent to HA whose reply may still be abandoned, returned by
+/// [super::WsApi::request_cancellable()].
+///
+/// Await the reply with [CancellableRequest::response()], and cancel it from
+/// elsewhere through a [RequestCanceller].
+#[derive(Debug)]
+pub struct CancellableRequest {
+    rx: mpsc::Receiver<WsMessage>,
+    canceller: RequestCanceller,
+}
+
+/// Handle cancelling a [CancellableRequest], which may be cloned and moved
+/// to other tasks.
+#[derive(Clone, Debug)]
+pub struct RequestCanceller {
+    id: Id,
+    tx: mpsc::Sender<Command>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellableRequest {
+    pub(super) fn new(id: Id, rx: mpsc::Receiver<WsMessage>, tx: mpsc::Sender<Command>) -> CancellableRequest {
+        CancellableRequest {
+            rx,
+            canceller: RequestCanceller {
+                id,
+                tx,
+                cancelled: Arc::new(AtomicBool::new(false)),
+            },
+        }
+    }
+
+    /// The `Id` of the request.
+    pub fn id(&self) -> Id {
+        self.canceller.id
+    }
+
+    /// Returns a handle to cancel the request.
+    pub fn canceller(&self) -> RequestCanceller {
+        self.canceller.clone()
+    }
+
+    /// Waits for the reply of HA to the request.
+    ///
+    /// Returns [Error::Cancelled] if the request is cancelled first.
+    pub async fn response(mut self) -> Result<WsMessage> {
+        let reply = self.rx.recv().await;
+        if self.canceller.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        // Nothing else is expected for this id
+        let _ = self.canceller.unregister().await;
+        reply.ok_or(Error::NoNextMessage)
+    }
+}
+
+impl RequestCanceller {
+    /// Cancels the request, unregistering its `Id` so that a late reply
+    /// is dropped, and resolving [CancellableRequest::response()] with
+    /// [Error::Cancelled]. Cancelling more than once has no further effect.
+    pub async fn cancel(&self) -> Result<()> {
+        if !self.cancelled.swap(true, Ordering::SeqCst) {
+            self.unregister().await?;
+        }
+        Ok(())
+    }
+
+    /// Whether [RequestCanceller::cancel()] was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    async fn unregister(&self) -> Result<()> {
+        self.tx.send(Command::Unregister(self.id)).await
+            .map_err(|_| Error::InternalError {
+                cause: anyhow::anyhow!("could not unregister id={}: messenger terminated", self.id)
+            })
+    }
+}
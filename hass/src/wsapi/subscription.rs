@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+
+use crate::json::{EventType, Id, WsMessage};
+use crate::sync::atomic::AtomicId;
+
+use super::filter::EventFilter;
+use super::messenger::Command;
+
+/// Event subscriptions of a connection, shared by all of its handles.
+pub(super) type Subscriptions = Arc<Mutex<Registry>>;
+
+/// Active event subscriptions, by id, along with their event type and filter.
+#[derive(Debug, Default)]
+pub(super) struct Registry {
+    /// Bumped by [super::WsApi::reconnect()], since ids start over on the new
+    /// connection and may be reused by unrelated subscriptions
+    pub generation: u64,
+    pub active: BTreeMap<Id, (Option<EventType>, EventFilter)>,
+    /// Subscriptions handed over by a [super::WsApi::reconnect()] that failed
+    /// before issuing them again, left for the next one to retry
+    pub detached: BTreeMap<Id, (Option<EventType>, EventFilter, mpsc::Sender<WsMessage>)>,
+}
+
+/// Receiver of the events of a subscription, returned by
+/// [super::WsApi::subscribe_event()].
+///
+/// It derefs to the underlying [mpsc::Receiver], and unsubscribes from HA
+/// when dropped, so that HA stops sending events nobody is listening to.
+/// Use [Subscription::into_inner()] to keep the subscription active and
+/// cancel it manually with [super::WsApi::unsubscribe()] instead.
+///
+/// Subscriptions moved over to a new connection by [super::WsApi::reconnect()]
+/// are bound to their new ids, and are no longer cancelled on drop.
+#[derive(Debug)]
+pub struct Subscription {
+    id: Id,
+    /// [Registry::generation] the subscription was made in
+    generation: u64,
+    rx: Option<mpsc::Receiver<WsMessage>>,
+    tx: mpsc::Sender<Command>,
+    ids: Arc<AtomicId>,
+    subscriptions: Subscriptions,
+}
+
+impl Subscription {
+    pub(super) fn new(id: Id, generation: u64, rx: mpsc::Receiver<WsMessage>, tx: mpsc::Sender<Command>,
+            ids: Arc<AtomicId>, subscriptions: Subscriptions) -> Subscription {
+        Subscription { id, generation, rx: Some(rx), tx, ids, subscriptions }
+    }
+
+    /// The `Id` of the subscription, also carried by its events.
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// Returns the underlying receiver, leaving the subscription active
+    /// after it gets dropped.
+    pub fn into_inner(mut self) -> mpsc::Receiver<WsMessage> {
+        self.rx.take().expect("receiver already taken")
+    }
+}
+
+impl Deref for Subscription {
+    type Target = mpsc::Receiver<WsMessage>;
+
+    fn deref(&self) -> &Self::Target {
+        self.rx.as_ref().expect("receiver already taken")
+    }
+}
+
+impl DerefMut for Subscription {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.rx.as_mut().expect("receiver already taken")
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if self.rx.is_none() {
+            return;
+        }
+        {
+            let mut registry = self.subscriptions.lock().unwrap();
+            if registry.generation != self.generation || registry.active.remove(&self.id).is_none() {
+                // Already unsubscribed, or moved over to a new connection,
+                // where the id may stand for a different subscription
+                return;
+            }
+        }
+        let Ok(handle) = Handle::try_current() else {
+            tracing::warn!("subscription id={} dropped outside of a runtime: not unsubscribed", self.id);
+            return;
+        };
+        let subscription = self.id;
+        let id = self.ids.next();
+        let tx = self.tx.clone();
+        handle.spawn(async move {
+            let (reply_tx, mut reply_rx) = mpsc::channel(1);
+            let sent = tx.send(Command::Unregister(subscription)).await.is_ok()
+                && tx.send(Command::Register(id, reply_tx)).await.is_ok()
+                && tx.send(Command::Message(WsMessage::UnsubscribeEvents { id, subscription })).await.is_ok();
+            if !sent {
+                // The connection is gone, and the subscription along with it
+                return;
+            }
+            match reply_rx.recv().await {
+                Some(WsMessage::Result { success: true, .. }) => {
+                    tracing::debug!("subscription id={} dropped: unsubscribed", subscription);
+                },
+                reply => tracing::warn!("subscription id={} dropped: could not unsubscribe: {:?}", subscription, reply),
+            }
+            let _ = tx.send(Command::Unregister(id)).await;
+        });
+    }
+}
@@ -0,0 +1,28 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::error::Result;
+
+/// Future returned by [TokenProvider::access_token()].
+pub type AccessToken<'a> = Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+
+/// Source of the access tokens used to authenticate with HA, asked for one
+/// each time [super::WsApi] (re)authenticates.
+///
+/// Implementations may e.g. refresh short-lived tokens through an OAuth flow.
+pub trait TokenProvider: Send + Sync + Debug {
+    /// Returns the access token to authenticate with.
+    fn access_token(&self) -> AccessToken<'_>;
+}
+
+/// A long-lived access token, always the same.
+#[derive(Clone, Debug)]
+pub struct StaticToken(pub String);
+
+impl TokenProvider for StaticToken {
+    fn access_token(&self) -> AccessToken<'_> {
+        let token = self.0.clone();
+        Box::pin(async move { Ok(token) })
+    }
+}
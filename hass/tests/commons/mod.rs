@@ -1,6 +1,15 @@
+use futures_util::{SinkExt, StreamExt};
 use hass::WsApi;
+use hass::wsapi::WsApiOptions;
+use hass::WsMessage;
 use hass::sync::shutdown::Manager;
-use hass::hast::server::{HastConfig, Hast};
+use hass::hast::server::{HastConfig, Hast, HastStats};
+use std::sync::Arc;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::Message};
+
+pub type RawWebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 pub const WS_HOST: &str = "127.0.0.1";
 pub const WS_PORT: u16 = 8123;
@@ -9,6 +18,8 @@ pub const WS_YAML_DIR: &str = "tests/resources";
 
 /// Home assistant event log resource info: `(name, event_count)`
 pub const HAEVLO_000_BASE: (&str, u32) = ("000-base.yaml", 8);
+pub const HAEVLO_001_CONTEXTS: (&str, u32) = ("001-contexts.yaml", 4);
+pub const HAEVLO_002_ORIGINS: (&str, u32) = ("002-origins.yaml", 5);
 
 
 /// Starts a new Hast mock server with default configuration [WS_PORT]
@@ -20,16 +31,36 @@ pub const HAEVLO_000_BASE: (&str, u32) = ("000-base.yaml", 8);
 /// Tests using it should rely on `#[serial_test::serial]` to avoid
 /// clashes on the port binding.
 pub async fn hast_start(scenario: &str) -> Manager {
-    let manager = Manager::new();
+    hast_start_with_config(hast_config(scenario)).await
+}
+
+/// Returns the default configuration used by [hast_start()], which may be
+/// further customized and passed to [hast_start_with_config()].
+pub fn hast_config(scenario: &str) -> HastConfig {
     let yaml_dir = format!("{}/{}/", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR);
-    let cfg = HastConfig::new_with_scenario(
+    HastConfig::new_with_scenario(
         WS_PORT,
         WS_TOKEN.to_owned(),
         yaml_dir,
         Some(scenario.to_owned())
-    );
+    )
+}
+
+/// Starts a new Hast mock server with the given configuration.
+///
+/// See [hast_start()].
+pub async fn hast_start_with_config(cfg: HastConfig) -> Manager {
+    hast_start_with_stats(cfg).await.0
+}
 
+/// Starts a new Hast mock server with the given configuration, also returning
+/// the server-side statistics it collects.
+///
+/// See [hast_start()].
+pub async fn hast_start_with_stats(cfg: HastConfig) -> (Manager, Arc<HastStats>) {
+    let manager = Manager::new();
     let hast = Hast::new(cfg, manager.subscribe());
+    let stats = hast.stats();
     let mut startup_notifier = hast.startup_notifier();
 
     tokio::spawn(async move {
@@ -41,9 +72,62 @@ pub async fn hast_start(scenario: &str) -> Manager {
 
     let _ = startup_notifier.changed().await;
 
-    manager
+    (manager, stats)
 }
 
 pub async fn hast_connect(m: &Manager) -> hass::error::Result<WsApi> {
     WsApi::new_unsecure(WS_HOST, WS_PORT, WS_TOKEN, m.subscribe()).await
 }
+
+/// Same as [hast_connect()], but with custom `options`.
+pub async fn hast_connect_with(m: &Manager, options: WsApiOptions) -> hass::error::Result<WsApi> {
+    let url = hass::url::Url::parse(&format!("ws://{}:{}/api/websocket", WS_HOST, WS_PORT)).unwrap();
+    WsApi::connect_with(url, WS_TOKEN, m.subscribe(), options).await
+}
+
+/// Opens a bare WebSocket connection to the Hast mock server, for tests that
+/// need to send arbitrary frames rather than going through [WsApi].
+pub async fn raw_connect() -> RawWebSocket {
+    let url = format!("ws://{}:{}/api/websocket", WS_HOST, WS_PORT);
+    let (socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    socket
+}
+
+/// Sends `msg` serialized as JSON over a bare WebSocket connection.
+pub async fn raw_send<S>(ws: &mut WebSocketStream<S>, msg: &impl Serialize)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let msg = serde_json::to_string(msg).unwrap();
+    ws.send(Message::Text(msg)).await.unwrap();
+}
+
+/// Receives the next text frame from a bare WebSocket connection as a [WsMessage].
+pub async fn raw_recv(ws: &mut RawWebSocket) -> WsMessage {
+    loop {
+        let msg = ws.next().await.unwrap().unwrap();
+        if msg.is_text() {
+            return hass::json::deserialize(msg.to_text().unwrap()).unwrap();
+        }
+    }
+}
+
+/// Starts a stub HA WebSocket server accepting a single connection, which
+/// authenticates any client and then never replies to anything, e.g. to test
+/// requests left pending.
+///
+/// Returns the URL to connect to.
+pub async fn silent_stub_start() -> hass::url::Url {
+    let listener = tokio::net::TcpListener::bind((WS_HOST, 0)).await.unwrap();
+    let url = format!("ws://{}/api/websocket", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let ha_version = "stub".to_owned();
+        raw_send(&mut ws, &WsMessage::AuthRequired { ha_version: ha_version.clone() }).await;
+        let _auth = ws.next().await;
+        raw_send(&mut ws, &WsMessage::AuthOk { ha_version }).await;
+        while let Some(Ok(_)) = ws.next().await {}
+    });
+    hass::url::Url::parse(&url).unwrap()
+}
@@ -0,0 +1,35 @@
+#![cfg(feature = "hast-bin")]
+
+use std::process::Command;
+
+/// Exit code of `hast` for broken scenarios.
+const SCENARIO_ERROR: i32 = 2;
+
+#[test]
+fn broken_scenario_exit_code() {
+    let yaml_dir = std::env::temp_dir().join("hast-bin-broken");
+    std::fs::create_dir_all(&yaml_dir).unwrap();
+    std::fs::write(yaml_dir.join("broken.yaml"), "---\ntype: event\nid: [1, 2\n").unwrap();
+
+    // Refused before starting the mock service, rather than served half-broken
+    let output = Command::new(env!("CARGO_BIN_EXE_hast"))
+        .args(["--port", "18130", "--yaml-dir"])
+        .arg(&yaml_dir)
+        .arg("broken.yaml")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(SCENARIO_ERROR));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("broken.yaml"));
+
+    // Issues go to stderr, leaving only the summary on stdout
+    let output = Command::new(env!("CARGO_BIN_EXE_hast"))
+        .arg("lint")
+        .arg(yaml_dir.join("broken.yaml"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(SCENARIO_ERROR));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("broken.yaml"));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1 file(s) checked: 1 error(s), 0 warning(s)\n");
+
+    let _ = std::fs::remove_dir_all(yaml_dir);
+}
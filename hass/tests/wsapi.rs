@@ -1,9 +1,20 @@
 mod commons;
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
 use commons::*;
 use hass::WsApi;
 use hass::WsMessage;
 use hass::error as herror;
+use hass::json::{self, EventObj, EventType};
+use hass::hast::scenario;
+use hass::hast::server::{ChaosConfig, Expectation, Hast, HastConfig};
+use hass::hast::client::HastMessage;
+use hass::wsapi::{AccessToken, EventFilter, RecordFormat, TokenProvider, WsApiOptions, ORIGIN_LOCAL};
+use hass::sync::shutdown::Manager;
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 #[serial_test::serial]
@@ -53,4 +64,1619 @@ async fn receive_events_any() {
     }
 
     manager.shutdown().await;
-}
\ No newline at end of file
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn subscribe_merged_unsubscribe() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let (merged_id, mut rx) = wsapi
+        .subscribe_event_types_merged(&[EventType::HaevloStart, EventType::HaevloStop])
+        .await
+        .unwrap();
+
+    // Events keep carrying the id of their own underlying subscription
+    let msg = rx.recv().await.unwrap();
+    assert!(msg.id().is_some());
+    assert_ne!(msg.id(), Some(merged_id));
+
+    // Unsubscribing the merged id cancels all underlying subscriptions,
+    // so the receiver eventually runs dry
+    let msg = wsapi.unsubscribe(merged_id).await.unwrap();
+    assert!(matches!(msg, WsMessage::Result { success: true, ..}));
+    while let Some(msg) = rx.recv().await {
+        assert_ne!(msg.id(), Some(merged_id));
+    }
+
+    manager.shutdown().await;
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_invalid_bind_addr() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.bind_addr = "localhost.invalid".to_owned();
+    let manager = Manager::new();
+    let hast = Hast::new(cfg, manager.subscribe());
+    let e = hast.run().await.unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    assert!(e.to_string().contains("localhost.invalid"));
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_config_phase_ignores_unrecognized() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.skip_hast_messages = false;
+    let manager = hast_start_with_config(cfg).await;
+
+    let mut ws = raw_connect().await;
+    raw_send(&mut ws, &WsMessage::Auth { access_token: WS_TOKEN.to_owned() }).await;
+    raw_send(&mut ws, &HastMessage::Start).await;
+
+    // hast survived the unexpected message and moved on to the HA simulation,
+    // where the session goes on as usual
+    let msg = raw_recv(&mut ws).await;
+    assert!(matches!(msg, WsMessage::AuthRequired { .. }), "unexpected message: {:?}", msg);
+    raw_send(&mut ws, &WsMessage::Auth { access_token: WS_TOKEN.to_owned() }).await;
+    let msg = raw_recv(&mut ws).await;
+    assert!(matches!(msg, WsMessage::AuthOk { .. }), "unexpected message: {:?}", msg);
+
+    manager.shutdown().await;
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_config_phase_malformed() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.skip_hast_messages = false;
+    let manager = hast_start_with_config(cfg).await;
+
+    let mut ws = raw_connect().await;
+    assert!(matches!(raw_recv(&mut ws).await, WsMessage::AuthRequired { .. }));
+    ws.send(Message::Binary(vec![0xde, 0xad])).await.unwrap();
+    ws.send(Message::Text("{\"type\": \"name\", ".to_owned())).await.unwrap();
+    match raw_recv(&mut ws).await {
+        WsMessage::Result { success: false, data: json::ResultBody::Error { error }, .. } => {
+            assert_eq!(error.code, "invalid_hast_message");
+        },
+        msg => panic!("unexpected message: {:?}", msg),
+    }
+
+    // The configuration phase goes on
+    raw_send(&mut ws, &HastMessage::Start).await;
+    raw_send(&mut ws, &WsMessage::Auth { access_token: WS_TOKEN.to_owned() }).await;
+    assert!(matches!(raw_recv(&mut ws).await, WsMessage::AuthOk { .. }));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_config_phase_implicit_start() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.skip_hast_messages = false;
+    let manager = hast_start_with_config(cfg).await;
+
+    // WsApi knows nothing about HastMessage::Start
+    let wsapi = hast_connect(&manager).await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    assert!(matches!(rx.recv().await, Some(WsMessage::Event { .. })));
+
+    manager.shutdown().await;
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_path_scenario() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+
+    // The scenario in the path replaces the default one
+    let url = format!("ws://{}:{}/api/websocket/scenarios/{}", WS_HOST, WS_PORT, HAEVLO_001_CONTEXTS.0);
+    let url = hass::url::Url::parse(&url).unwrap();
+    let wsapi = WsApi::connect_with(url, WS_TOKEN, manager.subscribe(), WsApiOptions::default()).await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    for _ in 0..HAEVLO_001_CONTEXTS.1 {
+        assert!(matches!(rx.recv().await, Some(WsMessage::Event { .. })));
+    }
+    assert!(tokio::time::timeout(Duration::from_millis(300), rx.recv()).await.is_err());
+
+    // No way out of the YAML directory
+    let url = format!("ws://{}:{}/api/websocket/scenarios/../{}", WS_HOST, WS_PORT, HAEVLO_000_BASE.0);
+    assert!(tokio_tungstenite::connect_async(url).await.is_err());
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_restart_with_new_scenario() {
+    let context_ids = |n| -> Vec<String> {
+        (1..=n).map(|i| format!("01GA{:022}", i)).collect()
+    };
+    let manager = Manager::new();
+    let shared = Arc::new(hast_config(HAEVLO_002_ORIGINS.0));
+    let mut hast = Hast::new(shared.clone(), manager.subscribe());
+    assert!(Arc::ptr_eq(hast.config(), &shared));
+    hast.start().unwrap();
+
+    let first = hast_connect(&manager).await.unwrap();
+    let mut rx = first.subscribe_event(None).await.unwrap();
+    let mut received = Vec::new();
+    for _ in 0..HAEVLO_002_ORIGINS.1 {
+        received.push(rx.recv().await.unwrap().context().unwrap().id.clone());
+    }
+    assert_eq!(received, context_ids(HAEVLO_002_ORIGINS.1));
+
+    // The shared configuration is left untouched
+    hast.config_mut().yaml_scenario = Some(HAEVLO_001_CONTEXTS.0.to_owned());
+    assert_eq!(shared.yaml_scenario.as_deref(), Some(HAEVLO_002_ORIGINS.0));
+    hast.restart().await.unwrap();
+
+    let second = hast_connect(&manager).await.unwrap();
+    let mut rx = second.subscribe_event(None).await.unwrap();
+    let mut received = Vec::new();
+    for _ in 0..HAEVLO_001_CONTEXTS.1 {
+        received.push(rx.recv().await.unwrap().context().unwrap().id.clone());
+    }
+    assert_eq!(received, context_ids(HAEVLO_001_CONTEXTS.1));
+    assert!(tokio::time::timeout(Duration::from_millis(300), rx.recv()).await.is_err());
+
+    // The instance holds on to its shutdown handle until dropped
+    drop(hast);
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn subscribe_event_filtered_origin() {
+    let manager = hast_start(HAEVLO_002_ORIGINS.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let mut rx = wsapi.subscribe_event_filtered(None, EventFilter::new().local_only()).await.unwrap();
+    let mut fired = Vec::new();
+    for _ in 0..3 {
+        match rx.recv().await {
+            Some(WsMessage::Event { event: EventObj::Event { origin, time_fired, .. }, .. }) => {
+                assert_eq!(origin, ORIGIN_LOCAL);
+                fired.push(time_fired);
+            },
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+    assert!(tokio::time::timeout(Duration::from_millis(300), rx.recv()).await.is_err());
+    assert_eq!(fired.iter().map(|t| t.timestamp() % 10).collect::<Vec<_>>(), [0, 2, 4]);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_extra_scenarios() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.extra_scenarios = vec![HAEVLO_001_CONTEXTS.0.to_owned()];
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let event = |msg: WsMessage| match msg {
+        WsMessage::Event { event, .. } => event,
+        msg => panic!("unexpected message: {:?}", msg),
+    };
+    let mut expected = Vec::new();
+    for file in [HAEVLO_000_BASE.0, HAEVLO_001_CONTEXTS.0] {
+        let path = format!("{}/{}/{}", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR, file);
+        expected.extend(scenario::read_file(path).unwrap().into_iter().map(|msg| event(msg.unwrap())));
+    }
+    assert_eq!(expected.len(), (HAEVLO_000_BASE.1 + HAEVLO_001_CONTEXTS.1) as usize);
+
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    let mut received = Vec::new();
+    for _ in 0..expected.len() {
+        received.push(event(rx.recv().await.unwrap()));
+    }
+    assert_eq!(received, expected);
+    assert!(tokio::time::timeout(Duration::from_millis(300), rx.recv()).await.is_err());
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_stats_match_received_events() {
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    let mut count = 0;
+    let mut subscription = 0;
+    while let Some(msg) = rx.recv().await {
+        count += 1;
+        subscription = msg.id().unwrap();
+        if count == HAEVLO_000_BASE.1 {
+            break;
+        }
+    }
+    wsapi.unsubscribe(subscription).await.unwrap();
+
+    assert_eq!(stats.events_for_subscription(subscription), count as usize);
+
+    manager.shutdown().await;
+}
+
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn connect_with_options() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let options = WsApiOptions::builder()
+        .connect_timeout(Some(Duration::from_secs(3)))
+        .keepalive_interval(Some(Duration::from_millis(200)))
+        .channel_bound(16)
+        .build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+
+    // Outlive a few keepalive pings
+    tokio::time::sleep(Duration::from_millis(700)).await;
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    assert!(matches!(rx.recv().await, Some(WsMessage::Event { .. })));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn keepalive_over_idle_connection() {
+    const INTERVAL: Duration = Duration::from_millis(100);
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let options = WsApiOptions::builder()
+        .keepalive_interval(Some(INTERVAL))
+        .build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+
+    // Stay idle for several keepalive intervals
+    tokio::time::sleep(INTERVAL * 10).await;
+    let pings = stats.received().iter().filter(|msg| matches!(msg, WsMessage::Ping { .. })).count();
+    assert!(pings >= 5, "only {} pings sent", pings);
+
+    // The connection survived, pongs being dispatched without errors
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    assert!(matches!(rx.recv().await, Some(WsMessage::Event { .. })));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn keepalive_disabled() {
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let options = WsApiOptions::builder()
+        .keepalive_interval(None)
+        .build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+    let _rx = wsapi.subscribe_event(None).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert!(!stats.received().iter().any(|msg| matches!(msg, WsMessage::Ping { .. })));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn keepalive_only_with_registrations() {
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let options = WsApiOptions::builder()
+        .keepalive_interval(Some(Duration::from_millis(100)))
+        .keepalive_always(false)
+        .build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+    let pings = || stats.received().iter().filter(|msg| matches!(msg, WsMessage::Ping { .. })).count();
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(pings(), 0);
+
+    let _rx = wsapi.subscribe_event(None).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert!(pings() > 0);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn keepalive_with_pending_oneshot() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.responses.insert("validate_config".to_owned(), "no-reply.yaml".to_owned());
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let options = WsApiOptions::builder()
+        .keepalive_interval(Some(Duration::from_millis(100)))
+        .keepalive_always(false)
+        .build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+    let pings = || stats.received().iter().filter(|msg| matches!(msg, WsMessage::Ping { .. })).count();
+
+    let id = wsapi.next_id();
+    let _reply = wsapi.register_oneshot(id).await.unwrap();
+    wsapi.send_raw(WsMessage::ValidateConfig { id, trigger: None, condition: None, action: None }).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert!(pings() > 0);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn call_service_context() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let data = serde_json::json!({"brightness": 101});
+    let target = serde_json::json!({"entity_id": "light.kitchen"});
+    let reply = wsapi.call_service("light", "turn_on", Some(data), Some(target)).await.unwrap();
+    assert!(matches!(reply, WsMessage::Result { success: true, .. }), "unexpected reply: {:?}", reply);
+    assert!(!reply.context().unwrap().id.is_empty());
+
+    manager.shutdown().await;
+}
+
+/// Log lines written by a `tracing_subscriber::fmt` subscriber.
+#[derive(Clone, Default)]
+struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for CapturedLogs {
+    type Writer = CapturedLogs;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+// Single-threaded, for all tasks to log to the subscriber set for this thread
+#[tokio::test]
+#[serial_test::serial]
+async fn trace_traffic() {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(logs.clone())
+        .with_env_filter(tracing_subscriber::EnvFilter::new(format!("{}=debug", hass::wsapi::TRAFFIC_TARGET)))
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let options = WsApiOptions::builder().trace_traffic(true).build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    rx.recv().await.unwrap();
+    manager.shutdown().await;
+
+    let logs = logs.contents();
+    assert!(logs.lines().all(|line| line.contains(hass::wsapi::TRAFFIC_TARGET)), "{}", logs);
+    assert!(logs.contains("send: {\"type\":\"subscribe_events\""), "{}", logs);
+    assert!(logs.contains("recv: {\"type\":\"event\""), "{}", logs);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn reconnect_restarts_ids() {
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let mut wsapi = hast_connect(&manager).await.unwrap();
+
+    wsapi.turn_on("light.kitchen").await.unwrap();
+    wsapi.turn_on("light.studio").await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    for _ in 0..HAEVLO_000_BASE.1 {
+        assert_eq!(rx.recv().await.unwrap().id(), Some(3));
+    }
+
+    let ids = wsapi.reconnect(manager.subscribe()).await.unwrap();
+    assert_eq!(ids.into_iter().collect::<Vec<_>>(), vec![(3, 1)]);
+
+    // The scenario is played again for the new subscription
+    for _ in 0..HAEVLO_000_BASE.1 {
+        assert_eq!(rx.recv().await.unwrap().id(), Some(1));
+    }
+    let subscriptions: Vec<_> = stats.received().into_iter()
+        .filter_map(|msg| match msg {
+            WsMessage::SubscribeEvents { id, .. } => Some(id),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(subscriptions, vec![3, 1]);
+    assert_eq!(stats.events_for_subscription(1), HAEVLO_000_BASE.1 as usize);
+
+    let msg = wsapi.unsubscribe(1).await.unwrap();
+    assert!(matches!(msg, WsMessage::Result { success: true, .. }));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn reconnect_resync() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.responses.insert("get_states".to_owned(), "get-states.yaml".to_owned());
+    let manager = hast_start_with_config(cfg).await;
+    let options = WsApiOptions::builder().resync_on_reconnect(true).build();
+    let mut wsapi = hast_connect_with(&manager, options).await.unwrap();
+    let mut resyncs = wsapi.resyncs();
+    assert!(resyncs.borrow().is_none());
+
+    wsapi.reconnect(manager.subscribe()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(1), resyncs.changed()).await.unwrap().unwrap();
+    let states = resyncs.borrow_and_update().clone().unwrap();
+    assert_eq!(states.len(), 2);
+    assert_eq!(states[0].entity_id, "light.kitchen");
+
+    // Not without the option
+    let mut wsapi = hast_connect(&manager).await.unwrap();
+    let resyncs = wsapi.resyncs();
+    wsapi.reconnect(manager.subscribe()).await.unwrap();
+    assert!(!resyncs.has_changed().unwrap());
+
+    drop(wsapi);
+    manager.shutdown().await;
+}
+
+/// Hands out [WS_TOKEN] first, then a new token on each following call.
+#[derive(Debug, Default)]
+struct RefreshingToken(std::sync::atomic::AtomicUsize);
+
+impl TokenProvider for RefreshingToken {
+    fn access_token(&self) -> AccessToken<'_> {
+        let calls = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Box::pin(async move {
+            Ok(match calls {
+                0 => WS_TOKEN.to_owned(),
+                n => format!("refreshed-{}", n),
+            })
+        })
+    }
+}
+
+/// Hands out [WS_TOKEN], except on the second call.
+#[derive(Debug, Default)]
+struct FlakyToken(std::sync::atomic::AtomicUsize);
+
+impl TokenProvider for FlakyToken {
+    fn access_token(&self) -> AccessToken<'_> {
+        let calls = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Box::pin(async move {
+            Ok(match calls {
+                1 => "flaky".to_owned(),
+                _ => WS_TOKEN.to_owned(),
+            })
+        })
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn reconnect_failure_keeps_subscriptions() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let url = hass::url::Url::parse(&format!("ws://{}:{}/api/websocket", WS_HOST, WS_PORT)).unwrap();
+    let token = std::sync::Arc::new(FlakyToken::default());
+    let mut wsapi = WsApi::connect_with_token_provider(url, token, manager.subscribe(), WsApiOptions::default()).await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    for _ in 0..HAEVLO_000_BASE.1 {
+        assert_eq!(rx.recv().await.unwrap().id(), Some(1));
+    }
+
+    let res = wsapi.reconnect(manager.subscribe()).await;
+    assert!(matches!(res, Err(herror::Error::Authentication(_))), "{:?}", res);
+
+    // The subscription survived the failure, and moves over to the next connection
+    let ids = wsapi.reconnect(manager.subscribe()).await.unwrap();
+    assert_eq!(ids.into_iter().collect::<Vec<_>>(), vec![(1, 1)]);
+    for _ in 0..HAEVLO_000_BASE.1 {
+        let msg = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert_eq!(msg.unwrap().id(), Some(1));
+    }
+
+    drop(rx);
+    drop(wsapi);
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn reconnect_cancelled_on_shutdown() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Authenticates the first connection, then accepts the following ones
+    // without ever completing their handshake, like an unresponsive HA
+    let listener = tokio::net::TcpListener::bind((WS_HOST, 0)).await.unwrap();
+    let url = hass::url::Url::parse(&format!("ws://{}/api/websocket", listener.local_addr().unwrap())).unwrap();
+    let attempts = std::sync::Arc::new(AtomicUsize::new(0));
+    let stub_attempts = attempts.clone();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        raw_send(&mut ws, &WsMessage::AuthRequired { ha_version: "stub".to_owned() }).await;
+        let _auth = ws.next().await;
+        raw_send(&mut ws, &WsMessage::AuthOk { ha_version: "stub".to_owned() }).await;
+        let mut pending = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            stub_attempts.fetch_add(1, Ordering::SeqCst);
+            pending.push(stream);
+        }
+    });
+
+    let manager = Manager::new();
+    let mut wsapi = WsApi::connect_with(url, WS_TOKEN, manager.subscribe(), WsApiOptions::default()).await.unwrap();
+    let shutdown = manager.subscribe();
+    let reconnect = async {
+        tokio::join!(
+            wsapi.reconnect(shutdown),
+            async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                manager.shutdown().await;
+            },
+        ).0
+    };
+    match tokio::time::timeout(Duration::from_secs(2), reconnect).await {
+        Ok(Err(herror::Error::Cancelled)) => (), // OK
+        Ok(Err(e)) => panic!("unexpected error: {}", e),
+        Ok(Ok(_)) => panic!("unexpected reconnection"),
+        Err(_) => panic!("reconnect not cancelled"),
+    }
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn token_provider_refresh_on_reconnect() {
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let url = hass::url::Url::parse(&format!("ws://{}:{}/api/websocket", WS_HOST, WS_PORT)).unwrap();
+    let token = std::sync::Arc::new(RefreshingToken::default());
+    let mut wsapi = WsApi::connect_with_token_provider(url, token, manager.subscribe(), WsApiOptions::default()).await.unwrap();
+
+    // hast only knows WS_TOKEN, hence it turns the refreshed one down
+    let res = wsapi.reconnect(manager.subscribe()).await;
+    assert!(matches!(res, Err(herror::Error::Authentication(_))), "{:?}", res);
+
+    let tokens: Vec<_> = stats.received().into_iter()
+        .filter_map(|msg| match msg {
+            WsMessage::Auth { access_token } => Some(access_token),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(tokens, vec![WS_TOKEN.to_owned(), "refreshed-1".to_owned()]);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn closed_on_server_shutdown() {
+    let hast_manager = hast_start(HAEVLO_000_BASE.0).await;
+    let manager = Manager::new();
+    let wsapi = WsApi::new_unsecure(WS_HOST, WS_PORT, WS_TOKEN, manager.subscribe()).await.unwrap();
+
+    let closed = wsapi.closed();
+    assert!(tokio::time::timeout(Duration::from_millis(100), wsapi.closed()).await.is_err());
+    hast_manager.shutdown().await;
+    tokio::time::timeout(Duration::from_secs(3), closed).await.unwrap();
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn subscription_event_type() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let (state_changed, _rx1) = wsapi.subscribe_event_with_id(Some(EventType::StateChanged)).await.unwrap();
+    let (any, _rx2) = wsapi.subscribe_event_with_id(None).await.unwrap();
+    assert_eq!(wsapi.subscription_event_type(state_changed), Some(Some(EventType::StateChanged)));
+    assert_eq!(wsapi.subscription_event_type(any), Some(None));
+    assert_eq!(wsapi.subscription_event_type(any + 100), None);
+
+    wsapi.unsubscribe(state_changed).await.unwrap();
+    assert_eq!(wsapi.subscription_event_type(state_changed), None);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn subscribe_excluding_context() {
+    let manager = hast_start(HAEVLO_001_CONTEXTS.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+    let mut rx = wsapi
+        .subscribe_event_excluding_context(Some(EventType::StateChanged), "piresence")
+        .await
+        .unwrap();
+
+    // Events 2 and 3 respectively carry a matching user_id and parent_id
+    for expected in ["01GA0000000000000000000001", "01GA0000000000000000000004"] {
+        let msg = rx.recv().await.unwrap();
+        assert_eq!(msg.context().unwrap().id, expected);
+    }
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn recent_events_buffer() {
+    const CAPACITY: usize = 3;
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let options = WsApiOptions::builder().recent_events(CAPACITY).build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+    assert!(wsapi.recent_events().is_empty());
+
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    let mut received = Vec::new();
+    for _ in 0..HAEVLO_000_BASE.1 {
+        received.push(rx.recv().await.unwrap());
+    }
+    assert_eq!(wsapi.recent_events(), received[received.len() - CAPACITY..]);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[allow(clippy::result_large_err)] // imposed by the accept_hdr_async() callback
+async fn connect_with_headers() {
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = hass::url::Url::parse(&format!("ws://{}/api/websocket", listener.local_addr().unwrap())).unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut headers = None;
+        let _ = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, res: Response| {
+            headers = Some(req.headers().clone());
+            Ok(res)
+        }).await;
+        headers.unwrap()
+    });
+
+    let manager = hass::sync::shutdown::Manager::new();
+    let options = WsApiOptions::builder()
+        .connect_timeout(Some(Duration::from_millis(500)))
+        .header("X-Forwarded-Host", "ha.example.com")
+        .build();
+    // The stub never authenticates us, only its handshake matters here
+    let _ = WsApi::connect_with(url.clone(), WS_TOKEN, manager.subscribe(), options).await;
+    let headers = server.await.unwrap();
+    assert_eq!(headers.get("x-forwarded-host").unwrap(), "ha.example.com");
+
+    let options = WsApiOptions::builder().header("Not A Header", "value").build();
+    match WsApi::connect_with(url, WS_TOKEN, manager.subscribe(), options).await {
+        Err(herror::Error::InvalidHeader(_)) => (), // OK
+        x => panic!("unexpected result: {:?}", x),
+    };
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn connect_with_subprotocol() {
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.subprotocols = vec!["v2.hast".to_owned()];
+    let manager = hast_start_with_config(cfg).await;
+
+    let options = WsApiOptions::builder()
+        .subprotocol("v1.hast")
+        .subprotocol("v2.hast")
+        .build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    assert!(matches!(rx.recv().await, Some(WsMessage::Event { .. })));
+
+    // The accepted subprotocol is echoed back
+    let mut request = format!("ws://{}:{}/api/websocket", WS_HOST, WS_PORT).into_client_request().unwrap();
+    request.headers_mut().insert("Sec-WebSocket-Protocol", "v1.hast, v2.hast".parse().unwrap());
+    let (_, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+    assert_eq!(response.headers().get("Sec-WebSocket-Protocol").unwrap(), "v2.hast");
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn request_cancelled() {
+    let url = silent_stub_start().await;
+    let manager = hass::sync::shutdown::Manager::new();
+    let wsapi = WsApi::connect_with(url, WS_TOKEN, manager.subscribe(), WsApiOptions::default()).await.unwrap();
+
+    let request = wsapi.request_cancellable(WsMessage::GetStates { id: 0 }).await.unwrap();
+    let canceller = request.canceller();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        canceller.cancel().await.unwrap();
+    });
+    let canceller = request.canceller();
+    // Resolving at all means the messenger dropped the receiver of the id
+    match request.response().await {
+        Err(herror::Error::Cancelled) => (), // OK
+        x => panic!("unexpected result: {:?}", x),
+    };
+    assert!(canceller.is_cancelled());
+    canceller.cancel().await.unwrap();
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn hast_lifecycle_events() {
+    let yaml_dir = format!("{}/{}/", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR);
+    let mut cfg = HastConfig::new(WS_PORT, WS_TOKEN.to_owned(), yaml_dir);
+    cfg.skip_hast_messages = true;
+    cfg.emit_lifecycle = true;
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let mut rx = wsapi.subscribe_event(Some(EventType::HomeassistantStarted)).await.unwrap();
+    let id = match rx.recv().await {
+        Some(WsMessage::Event { id, event: EventObj::Event { event_type, .. } }) => {
+            assert_eq!(event_type, EventType::HomeassistantStarted);
+            id
+        },
+        x => panic!("unexpected message: {:?}", x),
+    };
+    // homeassistant_start was filtered out by hast
+    assert_eq!(stats.events_for_subscription(id), 1);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn try_recv_all_buffered() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+
+    // Wait for the whole burst to be buffered
+    let expected = HAEVLO_000_BASE.1 as usize;
+    while rx.len() < expected {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let events = WsApi::try_recv_all(&mut rx);
+    assert_eq!(events.len(), expected);
+    assert!(events.iter().all(|ev| matches!(ev, WsMessage::Event { .. })));
+    assert!(rx.is_empty());
+    assert!(WsApi::try_recv_all(&mut rx).is_empty());
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn validate_config() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.responses.insert("validate_config".to_owned(), "validate-config.yaml".to_owned());
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let trigger = serde_json::json!({"platform": "state", "entity_id": "binary_sensor.studio_motion_motion"});
+    let action = serde_json::json!([{"not_an_action": "light.turn_on"}]);
+    let res = wsapi.validate_config(Some(trigger), None, Some(action)).await.unwrap();
+
+    let trigger = res.trigger.unwrap();
+    assert!(trigger.valid);
+    assert!(trigger.error.is_none());
+    assert!(res.condition.is_none());
+    let action = res.action.unwrap();
+    assert!(!action.valid);
+    assert_eq!(action.error.as_deref(), Some("Unable to determine action @ data[0]"));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn id_reuse() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.responses.insert("call_service".to_owned(), "id-reuse.yaml".to_owned());
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    match wsapi.turn_on("light.kitchen").await {
+        Err(e @ herror::Error::IdReuse(_)) => assert!(e.is_retryable()),
+        o => panic!("unexpected result: {:?}", o),
+    }
+
+    // Retried once with a new id
+    let ids: Vec<_> = stats.received().into_iter()
+        .filter(|msg| matches!(msg, WsMessage::CallService { .. }))
+        .map(|msg| msg.id().unwrap())
+        .collect();
+    assert_eq!(ids.len(), 2);
+    assert!(ids[1] > ids[0]);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn register_oneshot() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let id = wsapi.next_id();
+    let reply = wsapi.register_oneshot(id).await.unwrap();
+    wsapi.send_raw(WsMessage::CallService {
+        id,
+        domain: "light".to_owned(),
+        service: "turn_on".to_owned(),
+        service_data: None,
+        target: Some(serde_json::json!({"entity_id": "light.kitchen"})),
+    }).await.unwrap();
+
+    let reply = tokio::time::timeout(Duration::from_secs(5), reply).await.unwrap().unwrap();
+    assert!(matches!(reply, WsMessage::Result { success: true, .. }), "unexpected reply: {:?}", reply);
+    assert_eq!(reply.id(), Some(id));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn pipeline_fire_events() {
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let fire = |event_type, n| WsMessage::FireEvent {
+        id: 0,
+        event_type,
+        event_data: Some(serde_json::json!({ "n": n })),
+    };
+    let replies = wsapi.pipeline(vec![
+        fire(EventType::HaevloStart, 1),
+        fire(EventType::HaevloStop, 2),
+    ]).await.unwrap();
+
+    let fired: Vec<_> = stats.received().into_iter()
+        .filter_map(|msg| match msg {
+            WsMessage::FireEvent { id, event_type, .. } => Some((id, event_type)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(fired.iter().map(|(_, t)| *t).collect::<Vec<_>>(), vec![EventType::HaevloStart, EventType::HaevloStop]);
+    assert_eq!(replies.len(), fired.len());
+    for (reply, (id, _)) in replies.iter().zip(&fired) {
+        assert!(matches!(reply, WsMessage::Result { success: true, .. }), "unexpected reply: {:?}", reply);
+        assert_eq!(reply.id(), Some(*id));
+    }
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn get_services() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.responses.insert("get_services".to_owned(), "get-services.yaml".to_owned());
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let services = wsapi.get_services().await.unwrap();
+    assert_eq!(services.len(), 2);
+    let turn_on = &services["light"]["turn_on"];
+    assert_eq!(turn_on.name.as_deref(), Some("Turn on"));
+    assert_eq!(turn_on.fields.pointer("/brightness/selector/number/max"), Some(&serde_json::json!(255)));
+    assert!(services["homeassistant"].contains_key("restart"));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn entity_registry() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.responses.insert("config/entity_registry/list".to_owned(), "entity-registry.yaml".to_owned());
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let entries = wsapi.entity_registry().await.unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[1].name.as_deref(), Some("Storeroom Motion"));
+    assert_eq!(entries[1].device_id.as_deref(), Some("9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b"));
+    assert_eq!(entries[2].platform, "sun");
+    assert_eq!(entries[2].device_id, None);
+
+    let areas: BTreeMap<&str, &str> = entries.iter()
+        .filter_map(|entry| Some((entry.entity_id.as_str(), entry.area_id.as_deref()?)))
+        .collect();
+    assert_eq!(areas, BTreeMap::from([("binary_sensor.studio_motion_motion", "studio")]));
+
+    // Served from the cache, without asking hast again
+    assert_eq!(wsapi.cached_entity_registry().await.unwrap(), entries);
+    let requests = stats.received().iter()
+        .filter(|msg| matches!(msg, WsMessage::EntityRegistryList { .. }))
+        .count();
+    assert_eq!(requests, 1);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn subscribe_state_changes() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let changes = wsapi.subscribe_state_changes().await.unwrap();
+    let changes: Vec<_> = changes.take(HAEVLO_000_BASE.1 as usize).collect().await;
+    assert_eq!(changes.len(), HAEVLO_000_BASE.1 as usize);
+    for change in &changes {
+        let (old_state, new_state) = (change.old_state.as_ref().unwrap(), change.new_state.as_ref().unwrap());
+        assert_eq!(new_state.entity_id, change.entity_id);
+        assert_eq!(new_state.attributes["device_class"], "motion");
+        assert_ne!(old_state.state, new_state.state);
+    }
+    assert_eq!(changes[0].entity_id, "binary_sensor.studio_motion_motion");
+    assert_eq!(changes[0].new_state.as_ref().unwrap().state, "on");
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn record_subscription() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let recorder = Manager::new();
+    let mut buffer = Vec::new();
+    let (count, _) = tokio::join!(
+        wsapi.record_subscription(Some(EventType::StateChanged), &mut buffer, RecordFormat::Yaml, recorder.subscribe()),
+        async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            recorder.shutdown().await;
+        },
+    );
+    assert_eq!(count.unwrap(), HAEVLO_000_BASE.1 as usize);
+
+    // Recorded events match the scenario, apart from the subscription id
+    let event = |msg: WsMessage| match msg {
+        WsMessage::Event { event, .. } => event,
+        msg => panic!("unexpected message: {:?}", msg),
+    };
+    let path = format!("{}/{}/{}", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR, HAEVLO_000_BASE.0);
+    let expected: Vec<EventObj> = scenario::read_file(path).unwrap().into_iter().map(|msg| event(msg.unwrap())).collect();
+    let recorded: Vec<EventObj> = scenario::read(std::str::from_utf8(&buffer).unwrap()).into_iter().map(|msg| event(msg.unwrap())).collect();
+    assert_eq!(recorded, expected);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn get_states() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.responses.insert("get_states".to_owned(), "get-states.yaml".to_owned());
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let states = wsapi.get_states().await.unwrap();
+    assert_eq!(states.len(), 2);
+    assert_eq!(states[0].entity_id, "light.kitchen");
+    assert_eq!(states[0].state, "on");
+    assert_eq!(states[0].attributes["vendor_specific"]["firmware"], "1.2.3");
+    assert!(states[0].last_updated > states[0].last_changed);
+    assert_eq!(states[1].attributes["device_class"], "motion");
+    assert_eq!(states[1].context.id, "01G2P6A1J5Z8X3Y0Q9W7E4R2T2");
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn get_states_stream() {
+    const COUNT: usize = 5000;
+    let dir = std::env::temp_dir().join("hass-get-states-stream");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut yaml = String::from("---\ntype: result\nid: 1\nsuccess: true\nresult:\n");
+    for i in 0..COUNT {
+        yaml.push_str(&format!(concat!(
+            "  - entity_id: sensor.synthetic_{}\n",
+            "    state: \"{}\"\n",
+            "    attributes: {{ unit_of_measurement: W }}\n",
+            "    last_changed: \"2022-05-10T23:34:50.163029+00:00\"\n",
+            "    last_updated: \"2022-05-10T23:34:50.163029+00:00\"\n",
+            "    context: {{ id: ctx{}, parent_id: ~, user_id: ~ }}\n"), i, i, i));
+    }
+    std::fs::write(dir.join("get-states.yaml"), yaml).unwrap();
+
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.yaml_dir = dir.to_string_lossy().into_owned();
+    cfg.responses.insert("get_states".to_owned(), "get-states.yaml".to_owned());
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let states = wsapi.get_states_stream().await.unwrap();
+    futures_util::pin_mut!(states);
+    let mut count = 0;
+    while let Some(state) = states.next().await {
+        assert_eq!(state.entity_id, format!("sensor.synthetic_{}", count));
+        assert_eq!(state.state, count.to_string());
+        assert_eq!(state.attributes["unit_of_measurement"], "W");
+        count += 1;
+    }
+    assert_eq!(count, COUNT);
+
+    manager.shutdown().await;
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn idle_timeout_closes_connection() {
+    const IDLE: Duration = Duration::from_millis(300);
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let options = WsApiOptions::builder()
+        .idle_timeout(Some(IDLE))
+        .keepalive_interval(Some(IDLE / 3))
+        .build();
+    let wsapi = hast_connect_with(&manager, options).await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    for _ in 0..HAEVLO_000_BASE.1 {
+        assert!(rx.recv().await.is_some());
+    }
+
+    // Pongs keep coming, yet the receiver gets closed once HA goes quiet
+    let quiet = tokio::time::Instant::now();
+    let closed = tokio::time::timeout(IDLE * 10, rx.recv()).await.unwrap();
+    assert!(closed.is_none());
+    assert!(quiet.elapsed() >= IDLE / 2);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn toggle_services() {
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    wsapi.turn_on("light.kitchen").await.unwrap();
+    wsapi.turn_off("switch.garden_pump").await.unwrap();
+    wsapi.toggle("sensor.hallway_temperature").await.unwrap();
+    wsapi.turn_on("no_domain").await.unwrap();
+
+    let calls: Vec<_> = stats.received().into_iter()
+        .filter_map(|msg| match msg {
+            WsMessage::CallService { domain, service, target, .. } => Some((domain, service, target.unwrap())),
+            _ => None,
+        })
+        .collect();
+    let expected = [
+        ("light", "turn_on", "light.kitchen"),
+        ("switch", "turn_off", "switch.garden_pump"),
+        ("homeassistant", "toggle", "sensor.hallway_temperature"),
+        ("homeassistant", "turn_on", "no_domain"),
+    ];
+    assert_eq!(calls.len(), expected.len());
+    for ((domain, service, target), expected) in calls.iter().zip(expected) {
+        assert_eq!((domain.as_str(), service.as_str()), (expected.0, expected.1));
+        assert_eq!(target, &serde_json::json!({ "entity_id": expected.2 }));
+    }
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn expectations_met() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.expectations = vec!["light.turn_on".parse().unwrap()];
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    wsapi.turn_on("light.kitchen").await.unwrap();
+
+    manager.shutdown().await;
+    assert!(stats.unmet_expectations().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn expectations_unmet() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.expectations = vec!["light.turn_on".parse().unwrap(), "auth".parse().unwrap()];
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    wsapi.turn_off("light.kitchen").await.unwrap();
+
+    manager.shutdown().await;
+    let unmet: Vec<_> = stats.unmet_expectations().into_iter().map(|(_, e)| e).collect();
+    assert_eq!(unmet, vec![Expectation::CallService { domain: "light".to_string(), service: "turn_on".to_string() }]);
+}
+
+/// Collects the events of the scenario played by hast with the given `chaos`.
+async fn chaos_events(chaos: ChaosConfig) -> Vec<WsMessage> {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.chaos = Some(chaos);
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    let mut events = Vec::new();
+    while let Ok(Some(msg)) = tokio::time::timeout(Duration::from_millis(200), rx.recv()).await {
+        events.push(msg);
+    }
+    manager.shutdown().await;
+    events
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn chaos_disabled() {
+    let file = format!("{}/{}/{}", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR, HAEVLO_000_BASE.0);
+    let scenario: Vec<_> = scenario::read_file(file).unwrap().into_iter().map(|ev| ev.unwrap()).collect();
+
+    let events = chaos_events(ChaosConfig { drop_prob: 0.0, reorder_prob: 0.0, seed: 42 }).await;
+    let id = events[0].id().unwrap();
+    assert_eq!(events, scenario.into_iter().map(|ev| ev.set_id(id)).collect::<Vec<_>>());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn chaos_seeded() {
+    let file = format!("{}/{}/{}", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR, HAEVLO_000_BASE.0);
+    let scenario: Vec<_> = scenario::read_file(file).unwrap().into_iter().map(|ev| ev.unwrap()).collect();
+    let chaos = ChaosConfig { drop_prob: 0.2, reorder_prob: 0.3, seed: 7 };
+
+    // Same seed, same alterations
+    let indexes = chaos.apply((0..scenario.len()).collect());
+    assert_eq!(indexes, chaos.apply((0..scenario.len()).collect()));
+    assert_ne!(indexes, (0..scenario.len()).collect::<Vec<_>>());
+
+    let events = chaos_events(chaos).await;
+    let id = events[0].id().unwrap();
+    let expected: Vec<_> = indexes.into_iter().map(|i| scenario[i].clone().set_id(id)).collect();
+    assert_eq!(events, expected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn max_scenario_events() {
+    const MAX: usize = 20;
+    let base = std::fs::read_to_string(format!("{}/{}/{}", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR, HAEVLO_000_BASE.0)).unwrap();
+    let dir = std::env::temp_dir().join("hass-max-scenario-events");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("oversized.yaml"), base.repeat(10)).unwrap();
+
+    let mut cfg = hast_config("oversized.yaml");
+    cfg.yaml_dir = dir.to_string_lossy().into_owned();
+    cfg.max_scenario_events = Some(MAX);
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let (id, mut rx) = wsapi.subscribe_event_with_id(None).await.unwrap();
+    let mut count = 0;
+    while let Ok(Some(_)) = tokio::time::timeout(Duration::from_millis(200), rx.recv()).await {
+        count += 1;
+    }
+    assert_eq!(count, MAX);
+    assert_eq!(stats.events_for_subscription(id), MAX);
+
+    manager.shutdown().await;
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn shutdown_drains_connections() {
+    use tokio_tungstenite::tungstenite::Message;
+
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let mut ws = raw_connect().await;
+    assert!(matches!(raw_recv(&mut ws).await, WsMessage::AuthRequired { .. }));
+    raw_send(&mut ws, &WsMessage::Auth { access_token: WS_TOKEN.to_owned() }).await;
+    assert!(matches!(raw_recv(&mut ws).await, WsMessage::AuthOk { .. }));
+    raw_send(&mut ws, &WsMessage::SubscribeEvents { id: 1, event_type: None }).await;
+    assert!(matches!(raw_recv(&mut ws).await, WsMessage::Result { id: 1, success: true, .. }));
+    while stats.events_for_subscription(1) < HAEVLO_000_BASE.1 as usize {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Shut down while the burst of events is still to be read
+    let shutdown = tokio::spawn(manager.shutdown());
+    let mut events = 0;
+    loop {
+        match ws.next().await {
+            Some(Ok(Message::Text(_))) => events += 1,
+            Some(Ok(Message::Close(frame))) => {
+                assert!(frame.is_some());
+                break;
+            },
+            o => panic!("unexpected frame: {:?}", o),
+        }
+    }
+    assert_eq!(events, HAEVLO_000_BASE.1);
+    shutdown.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn realtime_replay() {
+    const STEP_MS: i64 = 100;
+    let dir = std::env::temp_dir().join("hass-realtime-replay");
+    std::fs::create_dir_all(&dir).unwrap();
+    let base = std::fs::read_to_string(format!("{}/{}/{}", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR, HAEVLO_000_BASE.0)).unwrap();
+    let start = chrono::DateTime::parse_from_rfc3339("2022-05-10T23:34:50Z").unwrap();
+    let mut n = 0;
+    let yaml: Vec<String> = base.lines()
+        .map(|line| if line.starts_with("  time_fired:") {
+            let fired = start + chrono::Duration::milliseconds(n * STEP_MS);
+            n += 1;
+            format!("  time_fired: \"{}\"", fired.to_rfc3339())
+        } else {
+            line.to_owned()
+        })
+        .collect();
+    std::fs::write(dir.join("realtime.yaml"), yaml.join("\n")).unwrap();
+
+    let mut cfg = hast_config("realtime.yaml");
+    cfg.yaml_dir = dir.to_string_lossy().into_owned();
+    cfg.realtime = true;
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    rx.recv().await.unwrap();
+    let first = tokio::time::Instant::now();
+    for _ in 1..HAEVLO_000_BASE.1 {
+        rx.recv().await.unwrap();
+    }
+    let elapsed = first.elapsed();
+    let expected = Duration::from_millis((STEP_MS * (n - 1)) as u64);
+    assert!(elapsed >= expected - Duration::from_millis(50), "replayed too fast: {:?}", elapsed);
+    assert!(elapsed < expected * 3, "replayed too slow: {:?}", elapsed);
+
+    manager.shutdown().await;
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn shutdown_interrupts_burst() {
+    const REPEAT: usize = 1250;
+    let total = HAEVLO_000_BASE.1 as usize * REPEAT;
+    let dir = std::env::temp_dir().join("hass-shutdown-interrupts-burst");
+    std::fs::create_dir_all(&dir).unwrap();
+    let base = std::fs::read_to_string(format!("{}/{}/{}", env!("CARGO_MANIFEST_DIR"), WS_YAML_DIR, HAEVLO_000_BASE.0)).unwrap();
+    std::fs::write(dir.join("large.yaml"), base.repeat(REPEAT)).unwrap();
+
+    let mut cfg = hast_config("large.yaml");
+    cfg.yaml_dir = dir.to_string_lossy().into_owned();
+    cfg.drain_timeout = Duration::ZERO;
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let (id, mut rx) = wsapi.subscribe_event_with_id(None).await.unwrap();
+    rx.recv().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), manager.shutdown()).await.unwrap();
+
+    let sent = stats.events_for_subscription(id);
+    assert!(sent < total, "the whole burst of {} events was sent", total);
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn loop_scenario() {
+    const LOOPS: u32 = 3;
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.loop_scenario = true;
+    cfg.loop_delay = Duration::from_millis(50);
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let (id, mut rx) = wsapi.subscribe_event_with_id(None).await.unwrap();
+    for _ in 0..HAEVLO_000_BASE.1 * LOOPS {
+        assert_eq!(rx.recv().await.unwrap().id(), Some(id));
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), manager.shutdown()).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn heartbeat_after_scenario() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.heartbeat = Some(Duration::from_millis(50));
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let event_type = |msg: WsMessage| match msg {
+        WsMessage::Event { event: EventObj::Event { event_type, .. }, .. } => event_type,
+        msg => panic!("unexpected message: {:?}", msg),
+    };
+    let mut rx = wsapi.subscribe_event(None).await.unwrap();
+    for _ in 0..HAEVLO_000_BASE.1 {
+        assert_eq!(event_type(rx.recv().await.unwrap()), EventType::StateChanged);
+    }
+    for _ in 0..3 {
+        assert_eq!(event_type(rx.recv().await.unwrap()), EventType::TimeChanged);
+    }
+
+    // Subscriptions to other event types get no heartbeat
+    let mut rx = wsapi.subscribe_event(Some(EventType::StateChanged)).await.unwrap();
+    for _ in 0..HAEVLO_000_BASE.1 {
+        assert_eq!(event_type(rx.recv().await.unwrap()), EventType::StateChanged);
+    }
+    assert!(tokio::time::timeout(Duration::from_millis(200), rx.recv()).await.is_err());
+
+    tokio::time::timeout(Duration::from_secs(5), manager.shutdown()).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn concurrent_subscriptions_and_requests() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.emit_lifecycle = true;
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    // Collects the events of a new subscription, checking they all carry its id
+    let collect = |event_type: Option<EventType>, count: u32| {
+        let wsapi = &wsapi;
+        async move {
+            let (id, mut rx) = wsapi.subscribe_event_with_id(event_type).await.unwrap();
+            let mut event_types = Vec::new();
+            for _ in 0..count {
+                match rx.recv().await.unwrap() {
+                    WsMessage::Event { id: event_id, event: EventObj::Event { event_type, .. } } => {
+                        assert_eq!(event_id, id);
+                        event_types.push(event_type);
+                    },
+                    msg => panic!("unexpected message: {:?}", msg),
+                }
+            }
+            event_types
+        }
+    };
+    let requests = async {
+        let fired = wsapi.request(WsMessage::FireEvent {
+            id: 0,
+            event_type: EventType::HaevloStart,
+            event_data: None,
+        });
+        let called = wsapi.turn_on("light.kitchen");
+        let (fired, called) = tokio::join!(fired, called);
+        (fired.unwrap(), called.unwrap())
+    };
+
+    let (all, started, (fired, called)) = tokio::join!(
+        collect(None, HAEVLO_000_BASE.1 + 2),
+        collect(Some(EventType::HomeassistantStarted), 1),
+        requests,
+    );
+
+    // Lifecycle events first, then the scenario
+    assert_eq!(all[..2], [EventType::HomeassistantStart, EventType::HomeassistantStarted]);
+    assert!(all[2..].iter().all(|t| *t == EventType::StateChanged));
+    assert_eq!(started, [EventType::HomeassistantStarted]);
+    assert!(matches!(fired, WsMessage::Result { success: true, .. }), "unexpected reply: {:?}", fired);
+    assert!(matches!(&called, WsMessage::Result { success: true, .. }), "unexpected reply: {:?}", called);
+    assert_ne!(fired.id(), called.id());
+    assert!(called.context().is_some());
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn connect_timeout() {
+    // Accepts TCP connections but never answers the handshake, which behaves
+    // like a firewalled host without depending on the network setup
+    let listener = tokio::net::TcpListener::bind((WS_HOST, 0)).await.unwrap();
+    let url = hass::url::Url::parse(&format!("ws://{}/api/websocket", listener.local_addr().unwrap())).unwrap();
+    let server = tokio::spawn(async move {
+        let (_stream, _) = listener.accept().await.unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let manager = hass::sync::shutdown::Manager::new();
+    let options = WsApiOptions::builder()
+        .connect_timeout(Some(Duration::from_millis(300)))
+        .build();
+    let connect = WsApi::connect_with(url, WS_TOKEN, manager.subscribe(), options);
+    match tokio::time::timeout(Duration::from_secs(3), connect).await {
+        Ok(Err(herror::Error::Timeout)) => (), // OK
+        Ok(x) => panic!("unexpected result: {:?}", x.map(|_| ())),
+        Err(_) => panic!("connect_timeout was not enforced"),
+    }
+
+    server.abort();
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn dropped_subscription_unsubscribes() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.heartbeat = Some(Duration::from_millis(20));
+    let (manager, stats) = hast_start_with_stats(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let kept = wsapi.subscribe_event(None).await.unwrap();
+    let kept_id = kept.id();
+    let _kept = kept.into_inner();
+    let mut dropped = wsapi.subscribe_event(Some(EventType::TimeChanged)).await.unwrap();
+    let dropped_id = dropped.id();
+    dropped.recv().await.unwrap();
+    assert_eq!(stats.subscriptions(), 2);
+    drop(dropped);
+
+    // Unsubscribed in the background
+    let unsubscribed = |subscription| stats.received().iter().any(|msg| matches!(msg,
+        WsMessage::UnsubscribeEvents { subscription: s, .. } if *s == subscription));
+    tokio::time::timeout(Duration::from_secs(3), async {
+        while stats.subscriptions() > 1 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }).await.expect("dropped subscription was not cancelled");
+    assert!(unsubscribed(dropped_id));
+    assert!(wsapi.subscription_event_type(dropped_id).is_none());
+
+    // hast stops streaming heartbeats to it
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let streamed = stats.events_for_subscription(dropped_id);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(stats.events_for_subscription(dropped_id), streamed);
+
+    // The subscription given up with into_inner() is left alone
+    assert!(!unsubscribed(kept_id));
+    assert_eq!(wsapi.subscription_event_type(kept_id), Some(None));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn dropped_subscription_after_reconnect() {
+    let (manager, stats) = hast_start_with_stats(hast_config(HAEVLO_000_BASE.0)).await;
+    let mut wsapi = hast_connect(&manager).await.unwrap();
+
+    wsapi.next_id();
+    let moved = wsapi.subscribe_event(Some(EventType::StateChanged)).await.unwrap();
+    let moved_id = moved.id();
+    wsapi.reconnect(manager.subscribe()).await.unwrap();
+
+    // Ids start over, the first one going to the subscription moved over:
+    // the next one reuses its old id
+    let reusing = wsapi.subscribe_event(None).await.unwrap();
+    assert_eq!(reusing.id(), moved_id);
+
+    drop(moved);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(wsapi.subscription_event_type(moved_id), Some(None));
+    assert!(!stats.received().iter().any(|msg| matches!(msg, WsMessage::UnsubscribeEvents { .. })));
+
+    drop(reusing);
+    drop(wsapi);
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn event_before_subscribe_ack() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.event_before_ack = true;
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let (id, mut rx) = wsapi.subscribe_event_with_id(None).await.unwrap();
+    let mut event_types = Vec::new();
+    for _ in 0..HAEVLO_000_BASE.1 + 1 {
+        match rx.recv().await.unwrap() {
+            WsMessage::Event { id: event_id, event: EventObj::Event { event_type, .. } } => {
+                assert_eq!(event_id, id);
+                event_types.push(event_type);
+            },
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+    // The early event comes first, followed by the scenario
+    assert_eq!(event_types[0], EventType::TimeChanged);
+    assert!(event_types[1..].iter().all(|t| *t == EventType::StateChanged));
+
+    // Subscriptions not expecting it are unaffected
+    let mut rx = wsapi.subscribe_event(Some(EventType::StateChanged)).await.unwrap();
+    assert!(matches!(rx.recv().await.unwrap(), WsMessage::Event { .. }));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn event_before_subscribe_events_ack() {
+    let mut cfg = hast_config(HAEVLO_000_BASE.0);
+    cfg.event_before_ack = true;
+    let manager = hast_start_with_config(cfg).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let mut rx = wsapi.subscribe_events(&[EventType::TimeChanged, EventType::StateChanged]).await.unwrap();
+    let mut event_types = Vec::new();
+    for _ in 0..HAEVLO_000_BASE.1 + 1 {
+        match rx.recv().await.unwrap() {
+            WsMessage::Event { event: EventObj::Event { event_type, .. }, .. } => event_types.push(event_type),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+    assert_eq!(event_types[0], EventType::TimeChanged);
+    assert!(event_types[1..].iter().all(|t| *t == EventType::StateChanged));
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn ha_version() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let expected = format!("{}-{}", env!("CARGO_PKG_VERSION"), env!("CARGO_PKG_NAME"));
+    assert_eq!(wsapi.ha_version(), expected);
+
+    manager.shutdown().await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial_test::serial]
+async fn cloned_handles_subscribe_concurrently() {
+    let manager = hast_start(HAEVLO_000_BASE.0).await;
+    let wsapi = hast_connect(&manager).await.unwrap();
+
+    let tasks: Vec<_> = (0..2).map(|_| {
+        let wsapi = wsapi.clone();
+        tokio::spawn(async move {
+            let (id, mut rx) = wsapi.subscribe_event_with_id(Some(EventType::StateChanged)).await.unwrap();
+            for _ in 0..HAEVLO_000_BASE.1 {
+                assert_eq!(rx.recv().await.unwrap().id(), Some(id));
+            }
+            id
+        })
+    }).collect();
+    let mut ids = Vec::new();
+    for task in tasks {
+        ids.push(task.await.unwrap());
+    }
+
+    // Ids and subscriptions are shared by all handles
+    assert_ne!(ids[0], ids[1]);
+    for id in ids {
+        assert_eq!(wsapi.subscription_event_type(id), Some(Some(EventType::StateChanged)));
+    }
+
+    manager.shutdown().await;
+}
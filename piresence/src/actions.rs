@@ -0,0 +1,173 @@
+//! Presence-driven actions
+//!
+//! Follows the estimates of a [PresenceEngine] to detect when each area
+//! becomes occupied or vacated, and calls the services configured for such
+//! transitions.
+//!
+//! Transitions are debounced against the clock the changes are timed with,
+//! see [hass::sync::clock::EventClock].
+//!
+//! [PresenceEngine]: crate::presence::PresenceEngine
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hass::pirengine::home::{AreaId, Presence};
+use hass::sync::clock::{self, Clock};
+use hass::WsApi;
+use tokio::sync::mpsc::Receiver;
+
+use crate::config::Config;
+use crate::presence::PresenceChange;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Transition {
+    Occupied,
+    Vacated,
+}
+
+impl From<Presence> for Transition {
+    fn from(presence: Presence) -> Transition {
+        match presence {
+            Presence::NoOne => Transition::Vacated,
+            _ => Transition::Occupied,
+        }
+    }
+}
+
+/// Delays transitions of areas until they have lasted for a given period,
+/// dropping those reverted in the meantime.
+#[derive(Debug)]
+pub struct Debouncer {
+    period: chrono::Duration,
+    /// Transitions waiting for their deadline
+    pending: BTreeMap<AreaId, (Transition, DateTime<Utc>)>,
+    /// Last transition that got through, per area
+    current: BTreeMap<AreaId, Transition>,
+}
+
+impl Debouncer {
+    pub fn new(period: Duration) -> Debouncer {
+        Debouncer {
+            period: chrono::Duration::from_std(period).unwrap_or(chrono::Duration::MAX),
+            pending: BTreeMap::new(),
+            current: BTreeMap::new(),
+        }
+    }
+
+    /// Records that `area` went through `transition` at `time`.
+    ///
+    /// Areas are initially considered vacated.
+    pub fn update(&mut self, area: &str, transition: Transition, time: DateTime<Utc>) {
+        match self.pending.get(area) {
+            Some((pending, _)) if *pending != transition => {
+                tracing::debug!("debouncer: {} reverted to {:?}", area, transition);
+                self.pending.remove(area);
+            },
+            Some(_) => (),
+            None => {
+                let current = self.current.get(area).copied().unwrap_or(Transition::Vacated);
+                if current != transition {
+                    self.pending.insert(area.to_owned(), (transition, time + self.period));
+                }
+            },
+        }
+    }
+
+    /// Returns the earliest deadline of the pending transitions, if any.
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.pending.values().map(|(_, deadline)| *deadline).min()
+    }
+
+    /// Returns the transitions that lasted long enough as of `now`.
+    pub fn due(&mut self, now: DateTime<Utc>) -> Vec<(AreaId, Transition)> {
+        let due: Vec<AreaId> = self.pending.iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(area, _)| area.clone())
+            .collect();
+        due.into_iter()
+            .map(|area| {
+                let (transition, _) = self.pending.remove(&area).unwrap();
+                self.current.insert(area.clone(), transition);
+                (area, transition)
+            })
+            .collect()
+    }
+}
+
+/// Processes the estimates received from `changes`, e.g. those of a
+/// [PresenceEngine], calling the services configured for the occupancy
+/// transitions of each area.
+///
+/// `clock` should be the one the changes are timed with, e.g. the
+/// [EventClock] driving the engine. Returns when `changes` gets closed.
+///
+/// [PresenceEngine]: crate::presence::PresenceEngine
+/// [EventClock]: hass::sync::clock::EventClock
+pub async fn run(api: &WsApi, config: &Config, clock: &dyn Clock, mut changes: Receiver<PresenceChange>) {
+    let mut debouncer = Debouncer::new(config.debounce());
+
+    loop {
+        let deadline = debouncer.next_deadline();
+        tokio::select! {
+            change = changes.recv() => match change {
+                Some(change) => {
+                    act(api, config, debouncer.due(change.time)).await;
+                    debouncer.update(&change.area, change.presence.into(), change.time);
+                },
+                None => break,
+            },
+
+            _ = clock::sleep_until(clock, deadline) => {
+                act(api, config, debouncer.due(clock.now())).await;
+            },
+        }
+    }
+}
+
+async fn act(api: &WsApi, config: &Config, transitions: Vec<(AreaId, Transition)>) {
+    for (area_id, transition) in transitions {
+        tracing::info!("area {}: {:?}", area_id, transition);
+        let Some(area_config) = config.areas.get(&area_id) else {
+            continue;
+        };
+        let calls = match transition {
+            Transition::Occupied => &area_config.on_occupied,
+            Transition::Vacated => &area_config.on_vacated,
+        };
+        for call in calls {
+            let res = api.call_service(&call.domain, &call.service, call.service_data.clone(), call.target.clone()).await;
+            if let Err(e) = res {
+                tracing::error!("area {}: could not call service {}.{}: {}", area_id, call.domain, call.service, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::<Utc>::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn debouncer() {
+        let mut debouncer = Debouncer::new(Duration::from_secs(2));
+        debouncer.update("studio", Transition::Occupied, at(0));
+        assert!(debouncer.due(at(1)).is_empty());
+        assert_eq!(debouncer.next_deadline(), Some(at(2)));
+        assert_eq!(debouncer.due(at(2)), vec![("studio".to_owned(), Transition::Occupied)]);
+
+        // Reverted before the deadline
+        debouncer.update("studio", Transition::Vacated, at(10));
+        debouncer.update("studio", Transition::Occupied, at(11));
+        assert_eq!(debouncer.next_deadline(), None);
+        assert!(debouncer.due(at(20)).is_empty());
+
+        // Already vacated
+        debouncer.update("kitchen", Transition::Vacated, at(20));
+        assert!(debouncer.due(at(30)).is_empty());
+    }
+}
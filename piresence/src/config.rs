@@ -0,0 +1,127 @@
+//! Configuration of `piresence`
+//!
+//! The configuration is a JSON file describing the areas of the home, the
+//! motion sensors found in each of them, and the services to call when they
+//! become occupied or vacated, e.g.:
+//!
+//! ```json
+//! {
+//!     "debounce_ms": 2000,
+//!     "presence_timeout_ms": 300000,
+//!     "areas": {
+//!         "studio": {
+//!             "sensors": ["binary_sensor.studio_motion_motion"],
+//!             "on_occupied": [
+//!                 {"domain": "light", "service": "turn_on", "target": {"entity_id": "light.studio"}}
+//!             ],
+//!             "on_vacated": [
+//!                 {"domain": "light", "service": "turn_off", "target": {"entity_id": "light.studio"}}
+//!             ]
+//!         }
+//!     }
+//! }
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use hass::json::ServiceDescription;
+use hass::pirengine::home::{Area, AreaId, Home};
+use hass::serde_json::Value;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// Time an area must stay occupied or vacated before the corresponding
+    /// services are called, to avoid rapid on/off sequences.
+    #[serde(default)]
+    pub debounce_ms: u64,
+
+    /// Time after the last motion in an area, with none of its sensors still
+    /// detecting it, after which the area is estimated empty, see
+    /// [crate::presence::PresenceEngine]. Defaults to 5 minutes.
+    #[serde(default = "default_presence_timeout_ms")]
+    pub presence_timeout_ms: u64,
+
+    pub areas: BTreeMap<AreaId, AreaConfig>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct AreaConfig {
+    /// Entity ids of the motion sensors of the area
+    pub sensors: Vec<String>,
+
+    /// Services called when the area becomes occupied
+    #[serde(default)]
+    pub on_occupied: Vec<ServiceCall>,
+
+    /// Services called when the area becomes vacated
+    #[serde(default)]
+    pub on_vacated: Vec<ServiceCall>,
+}
+
+/// A call to a HA service, see [hass::WsApi::call_service()].
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ServiceCall {
+    pub domain: String,
+    pub service: String,
+    pub service_data: Option<Value>,
+    pub target: Option<Value>,
+}
+
+fn default_presence_timeout_ms() -> u64 {
+    5 * 60 * 1000
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            debounce_ms: 0,
+            presence_timeout_ms: default_presence_timeout_ms(),
+            areas: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the JSON file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Config> {
+        Ok(hass::serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms)
+    }
+
+    pub fn presence_timeout(&self) -> Duration {
+        Duration::from_millis(self.presence_timeout_ms)
+    }
+
+    /// Builds the [Home] made of the configured areas, along with their sensors.
+    pub fn home(&self) -> Home {
+        let mut home = Home::new(self.areas.len());
+        for (id, area) in &self.areas {
+            let area = area.sensors.iter().fold(Area::new(id), |a, sensor| a.with_sensor(sensor));
+            home.add_area(area);
+        }
+        home
+    }
+
+    /// Returns the id of the area the sensor `entity_id` belongs to, if any.
+    pub fn area_of(&self, entity_id: &str) -> Option<&str> {
+        self.areas.iter()
+            .find(|(_, area)| area.sensors.iter().any(|sensor| sensor == entity_id))
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// Returns the configured service calls whose service is not among the
+    /// `services` known to HA, see [hass::WsApi::get_services()].
+    pub fn unknown_services<'a>(&'a self, services: &HashMap<String, HashMap<String, ServiceDescription>>) -> Vec<&'a ServiceCall> {
+        self.areas.values()
+            .flat_map(|area| area.on_occupied.iter().chain(&area.on_vacated))
+            .filter(|call| !services.get(&call.domain).is_some_and(|s| s.contains_key(&call.service)))
+            .collect()
+    }
+}
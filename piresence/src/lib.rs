@@ -1,5 +1,11 @@
 use clap::Parser;
 
+pub mod actions;
+pub mod config;
+pub mod presence;
+
+pub use presence::expected_timeline;
+
 /// Command-line arguments for the binary
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -15,6 +21,15 @@ pub struct CmdArgs {
     /// Authentication token for Home Assistant
     #[clap(long)]
     pub token: String,
+
+    /// JSON file with the areas of the home and their actions, see [config]
+    #[clap(long)]
+    pub config: String,
+
+    /// JSON file where presence estimates are restored from at startup and
+    /// saved to on shutdown, see [hass::pirengine::home::Home::save_snapshot()]
+    #[clap(long)]
+    pub snapshot: Option<String>,
 }
 
 impl CmdArgs {
@@ -1,25 +1,63 @@
-use hass::{self, json};
+use chrono::Utc;
+use hass::json::EventType;
+use hass::logging;
+use hass::pirengine::home::Home;
+use hass::sync::clock::EventClock;
+use hass::sync::shutdown;
+use hass::WsApi;
+use piresence::actions;
+use piresence::config::Config;
+use piresence::presence::PresenceEngine;
 use piresence::CmdArgs;
-use tracing_subscriber;
-use hass::wsapi::WsApi;
+use tokio::sync::mpsc;
 
 #[tokio::main]
 async fn main() {
-    // Initialize logging framework
-    tracing_subscriber::fmt::init();
+    // Initialize logging framework, keeping a handle to change its filter at runtime
+    let log_handle = logging::init();
 
     let args = CmdArgs::parse_args();
     tracing::trace!("commandline args: {:?}", args);
-    //hass::wsconnect(&args.host, args.port, &args.token);
 
-    /*
-    let host = env::var("HA_HOST").expect("environment variable missing: HA_HOST");
-    let port : u16 = env::var("HA_PORT")
-        .unwrap_or("8123".into())
-        //.expect("environment variable missing: HA_PORT")
-        .parse()
-        .expect("environment variable error: HA_PORT only accepts 16 bit unsigned integers");
-    let token = env::var("HA_TOKEN")
-        .unwrap_or("ABCDEFG".into());
-    */
+    let config = Config::load(&args.config).expect("could not load the configuration");
+    let manager = shutdown::Manager::new();
+    let api = WsApi::new_unsecure(&args.host, args.port, &args.token, manager.subscribe()).await
+        .expect("could not connect to HA WebSocket");
+    match api.get_services().await {
+        Ok(services) => for call in config.unknown_services(&services) {
+            tracing::warn!("unknown service in configuration: {}.{}", call.domain, call.service);
+        },
+        Err(e) => tracing::warn!("could not fetch services, skipping their validation: {}", e),
+    }
+    let events = api.subscribe_event(Some(EventType::StateChanged)).await
+        .expect("could not subscribe to events: state_changed");
+    match api.subscribe_event(Some(EventType::PiresenceLoglevel)).await {
+        Ok(loglevel_events) => {
+            tokio::spawn(logging::follow(log_handle, loglevel_events.into_inner()));
+        },
+        Err(e) => tracing::warn!("could not subscribe to events: piresence_loglevel: {}", e),
+    }
+    let mut home = config.home();
+    if let Some(path) = &args.snapshot {
+        if home.load_snapshot(path) {
+            tracing::info!("restored presence estimates from {}", path);
+        }
+    }
+    let mut presence = PresenceEngine::new(home.into_graph(), config.presence_timeout());
+
+    // Actions follow the estimates of the engine, timed with its clock
+    let clock = EventClock::new(Utc::now());
+    let (changes_tx, changes) = mpsc::channel(16);
+    tokio::select! {
+        _ = actions::run(&api, &config, &clock, changes) => (),
+        _ = presence.run(&clock, events.into_inner(), changes_tx) => (),
+        _ = tokio::signal::ctrl_c() => tracing::info!("CTRL-C detected, shutting down"),
+    }
+    manager.shutdown().await;
+
+    if let Some(path) = &args.snapshot {
+        if let Err(e) = Home::from(presence.into_areas()).save_snapshot(path) {
+            tracing::error!("could not save presence estimates to {}: {}", path, e);
+        }
+    }
 }
@@ -0,0 +1,293 @@
+//! Presence estimation
+//!
+//! Estimates whether someone is in each area of the home from its motion
+//! sensors: an area is occupied as soon as any of them detects motion, and
+//! estimated empty once none did for a while.
+//!
+//! Timings follow an [EventClock]. Changes of estimates are forwarded as
+//! [PresenceChange]s, e.g. to [crate::actions].
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hass::json::{EventObj, WsMessage};
+use hass::pirengine::home::{Area, AreaId, NodeId, Presence, VecGraph};
+use hass::sync::clock::{self, Clock, EventClock};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::config::Config;
+
+/// Change of the estimate of an area, as forwarded by [PresenceEngine::run()].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PresenceChange {
+    pub area: AreaId,
+    pub presence: Presence,
+    /// Time the change was noticed at
+    pub time: DateTime<Utc>,
+}
+
+/// Keeps the [Presence] estimate of each area up to date with the
+/// `state_changed` events of their sensors.
+pub struct PresenceEngine {
+    areas: VecGraph<Area>,
+    /// Time without motion after which an area is estimated empty
+    timeout: chrono::Duration,
+    /// Sensors currently detecting motion
+    active: BTreeSet<String>,
+}
+
+impl PresenceEngine {
+    /// Creates an engine for the `areas` of the home, estimating them empty
+    /// `timeout` after their last motion.
+    pub fn new(areas: VecGraph<Area>, timeout: Duration) -> PresenceEngine {
+        PresenceEngine {
+            areas,
+            timeout: chrono::Duration::from_std(timeout).unwrap_or(chrono::Duration::MAX),
+            active: BTreeSet::new(),
+        }
+    }
+
+    /// Returns the current estimate for the area `id`, if there is one such area.
+    pub fn presence(&self, id: &str) -> Option<Presence> {
+        self.areas.find_node_id(|area| area.id() == id)
+            .map(|node_id| self.areas.get_node(node_id).presence_esimate)
+    }
+
+    /// Returns the current estimates of all areas, by area id.
+    pub fn estimates(&self) -> Vec<(&str, Presence)> {
+        (0..self.areas.node_count())
+            .map(|node_id| self.areas.get_node(node_id))
+            .map(|area| (area.id(), area.presence_esimate))
+            .collect()
+    }
+
+    pub fn areas(&self) -> &VecGraph<Area> {
+        &self.areas
+    }
+
+    /// Returns the areas with their current estimates, dropping the engine.
+    pub fn into_areas(self) -> VecGraph<Area> {
+        self.areas
+    }
+
+    /// Updates the area whose sensor changed state with `msg`, returning the
+    /// nodes of the areas whose estimate changed.
+    ///
+    /// Areas left without motion for long enough as of the time of `msg` are
+    /// estimated empty first, see [PresenceEngine::decay()].
+    pub fn update(&mut self, msg: &WsMessage) -> Vec<NodeId> {
+        let WsMessage::Event { event: EventObj::Event { data, time_fired, .. }, .. } = msg else {
+            return Vec::new();
+        };
+        let mut changed = self.decay(*time_fired);
+        let Some(entity_id) = msg.entity_id() else {
+            return changed;
+        };
+        let Some(node_id) = self.areas.area_for_sensor(entity_id) else {
+            return changed;
+        };
+        let Some(state) = data.pointer("/new_state/state").and_then(|s| s.as_str()) else {
+            return changed;
+        };
+
+        if state == "on" {
+            self.active.insert(entity_id.to_owned());
+        } else {
+            self.active.remove(entity_id);
+        }
+        // Motion was detected until now, whether it just started or stopped
+        let area = self.areas.get_node_mut(node_id);
+        area.last_seen = Some(*time_fired);
+        if state == "on" && area.presence_esimate == Presence::NoOne {
+            area.presence_esimate = Presence::AtLeast(1);
+            tracing::debug!("presence: {}: {:?}", area.id(), area.presence_esimate);
+            changed.push(node_id);
+        }
+        changed
+    }
+
+    /// Estimates empty the areas with no sensor detecting motion since `timeout`
+    /// before `now`, returning their nodes.
+    pub fn decay(&mut self, now: DateTime<Utc>) -> Vec<NodeId> {
+        let mut changed = Vec::new();
+        for node_id in 0..self.areas.node_count() {
+            let area = self.areas.get_node(node_id);
+            let expired = area.last_seen.is_none_or(|last_seen| now - last_seen >= self.timeout);
+            if area.presence_esimate != Presence::NoOne && self.is_idle(area) && expired {
+                let area = self.areas.get_node_mut(node_id);
+                area.presence_esimate = Presence::NoOne;
+                tracing::debug!("presence: {}: {:?}", area.id(), area.presence_esimate);
+                changed.push(node_id);
+            }
+        }
+        changed
+    }
+
+    /// Returns when the next area will be estimated empty by [PresenceEngine::decay()],
+    /// unless motion is detected there meanwhile, if any.
+    pub fn next_expiry(&self) -> Option<DateTime<Utc>> {
+        (0..self.areas.node_count())
+            .map(|node_id| self.areas.get_node(node_id))
+            .filter(|area| area.presence_esimate != Presence::NoOne && self.is_idle(area))
+            .filter_map(|area| match area.last_seen {
+                Some(last_seen) => last_seen.checked_add_signed(self.timeout),
+                None => Some(DateTime::<Utc>::MIN_UTC),
+            })
+            .min()
+    }
+
+    /// Whether none of the sensors of `area` is detecting motion.
+    fn is_idle(&self, area: &Area) -> bool {
+        !area.sensors().iter().any(|sensor| self.active.contains(sensor))
+    }
+
+    /// Processes the `state_changed` events received from `events`, moving
+    /// `clock` to their time, and forwards the estimates of areas to `changes`
+    /// as they change.
+    ///
+    /// Areas are also estimated empty when no more events are coming, as
+    /// `clock` goes on. Returns when `events` gets closed.
+    pub async fn run(&mut self, clock: &EventClock, mut events: Receiver<WsMessage>, changes: Sender<PresenceChange>) {
+        loop {
+            let expiry = self.next_expiry();
+            let (time, changed) = tokio::select! {
+                msg = events.recv() => match msg {
+                    Some(msg) => {
+                        let time = match &msg {
+                            WsMessage::Event { event: EventObj::Event { time_fired, .. }, .. } => {
+                                clock.observe(*time_fired);
+                                *time_fired
+                            },
+                            _ => clock.now(),
+                        };
+                        (time, self.update(&msg))
+                    },
+                    None => break,
+                },
+                _ = clock::sleep_until(clock, expiry) => {
+                    let now = clock.now();
+                    (now, self.decay(now))
+                },
+            };
+            for node_id in changed {
+                let area = self.areas.get_node(node_id);
+                tracing::info!("presence: area {}: {:?}", area.id(), area.presence_esimate);
+                let change = PresenceChange {
+                    area: area.id().to_owned(),
+                    presence: area.presence_esimate,
+                    time,
+                };
+                if changes.send(change).await.is_err() {
+                    tracing::debug!("presence: changes no longer listened to");
+                }
+            }
+        }
+    }
+}
+
+/// Computes the presence timeline expected from playing the `scenario`, e.g.
+/// one read with `hass::hast::scenario`, against the home of `config`: each
+/// change of estimate is listed with the time it happens and the area it
+/// concerns, in chronological order.
+///
+/// Areas are estimated empty as soon as the presence timeout elapses, even
+/// when that's after the last event of the scenario, so that the timeline
+/// ends with every area left without motion being empty. The result is meant
+/// as a reference to compare the estimates of a [PresenceEngine] against.
+pub fn expected_timeline(scenario: &[WsMessage], config: &Config) -> Vec<(DateTime<Utc>, AreaId, Presence)> {
+    let mut engine = PresenceEngine::new(config.home().into_graph(), config.presence_timeout());
+    let mut timeline = Vec::new();
+    let entry = |engine: &PresenceEngine, node_id: NodeId, time: DateTime<Utc>| {
+        let area = engine.areas.get_node(node_id);
+        // Areas get empty when their timeout elapses, not when that's noticed
+        let time = match (area.presence_esimate, area.last_seen) {
+            (Presence::NoOne, Some(last_seen)) => last_seen + engine.timeout,
+            _ => time,
+        };
+        (time, area.id().to_owned(), area.presence_esimate)
+    };
+    for msg in scenario {
+        let WsMessage::Event { event: EventObj::Event { time_fired, .. }, .. } = msg else {
+            continue;
+        };
+        for node_id in engine.update(msg) {
+            timeline.push(entry(&engine, node_id, *time_fired));
+        }
+    }
+    for node_id in engine.decay(DateTime::<Utc>::MAX_UTC) {
+        timeline.push(entry(&engine, node_id, DateTime::<Utc>::MAX_UTC));
+    }
+    timeline.sort_by_key(|(time, _, _)| *time);
+    timeline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hass::json::{ContextObject, EventType};
+    use hass::serde_json::json;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::<Utc>::from_timestamp(secs, 0).unwrap()
+    }
+
+    fn motion(entity_id: &str, state: &str, time_fired: DateTime<Utc>) -> WsMessage {
+        WsMessage::Event {
+            id: 1,
+            event: EventObj::Event {
+                data: json!({"entity_id": entity_id, "new_state": {"entity_id": entity_id, "state": state}}),
+                event_type: EventType::StateChanged,
+                time_fired,
+                origin: "LOCAL".to_owned(),
+                context: ContextObject::default(),
+            },
+        }
+    }
+
+    fn engine() -> PresenceEngine {
+        let mut areas = VecGraph::new_undirected(2);
+        let entrance = areas.add_node(Area::new("entrance").with_sensor("binary_sensor.hall_motion")).unwrap();
+        let kitchen = areas.add_node(Area::new("kitchen").with_sensor("binary_sensor.kitchen_motion")).unwrap();
+        areas.add_edge(entrance, kitchen);
+        PresenceEngine::new(areas, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn motion_then_timeout() {
+        let mut engine = engine();
+        assert_eq!(engine.presence("entrance"), Some(Presence::NoOne));
+
+        assert_eq!(engine.update(&motion("binary_sensor.hall_motion", "on", at(0))), vec![0]);
+        assert_eq!(engine.presence("entrance"), Some(Presence::AtLeast(1)));
+        assert_eq!(engine.presence("kitchen"), Some(Presence::NoOne));
+
+        // Still occupied until the timeout since the motion stopped
+        assert!(engine.update(&motion("binary_sensor.hall_motion", "off", at(10))).is_empty());
+        assert_eq!(engine.next_expiry(), Some(at(70)));
+        assert!(engine.decay(at(69)).is_empty());
+        assert_eq!(engine.decay(at(70)), vec![0]);
+        assert_eq!(engine.estimates(), vec![("entrance", Presence::NoOne), ("kitchen", Presence::NoOne)]);
+    }
+
+    #[test]
+    fn ongoing_motion_does_not_decay() {
+        let mut engine = engine();
+        engine.update(&motion("binary_sensor.kitchen_motion", "on", at(0)));
+        assert_eq!(engine.next_expiry(), None);
+        assert!(engine.decay(at(3600)).is_empty());
+        assert_eq!(engine.presence("kitchen"), Some(Presence::AtLeast(1)));
+
+        // Decayed by the time of the next event
+        engine.update(&motion("binary_sensor.kitchen_motion", "off", at(3600)));
+        assert_eq!(engine.update(&motion("binary_sensor.hall_motion", "off", at(3700))), vec![1]);
+        assert_eq!(engine.presence("kitchen"), Some(Presence::NoOne));
+    }
+
+    #[test]
+    fn unknown_sensors_ignored() {
+        let mut engine = engine();
+        assert!(engine.update(&motion("binary_sensor.garage_motion", "on", at(0))).is_empty());
+        assert!(engine.update(&WsMessage::Pong { id: 3 }).is_empty());
+        assert_eq!(engine.presence("garage"), None);
+    }
+}
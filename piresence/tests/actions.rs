@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use hass::hast::server::{Hast, HastConfig, HastStats};
+use hass::json::EventType;
+use hass::serde_json::{self, json};
+use hass::sync::clock::EventClock;
+use hass::sync::shutdown::Manager;
+use hass::{WsApi, WsMessage};
+use piresence::actions;
+use piresence::config::Config;
+use piresence::presence::PresenceEngine;
+use tokio::sync::mpsc;
+
+const WS_HOST: &str = "127.0.0.1";
+const WS_TOKEN: &str = "letmein";
+
+/// Areas watched by the motion sensors found in `000-base.yaml`
+const CONFIG: &str = r#"{
+    "debounce_ms": 2000,
+    "presence_timeout_ms": 1000,
+    "areas": {
+        "studio": {
+            "sensors": ["binary_sensor.studio_motion_motion"],
+            "on_occupied": [{"domain": "light", "service": "turn_on", "target": {"entity_id": "light.studio"}}],
+            "on_vacated": [{"domain": "light", "service": "turn_off", "target": {"entity_id": "light.studio"}}]
+        },
+        "disbrigo": {
+            "sensors": ["binary_sensor.disbrigo_motion_motion"],
+            "on_occupied": [{"domain": "light", "service": "turn_on", "target": {"entity_id": "light.disbrigo"}}]
+        }
+    }
+}"#;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn occupancy_calls_services() {
+    let config: Config = serde_json::from_str(CONFIG).unwrap();
+
+    let yaml_dir = format!("{}/../hass/tests/resources/", env!("CARGO_MANIFEST_DIR"));
+    // Any free port will do, hast tells which one it got
+    let cfg = HastConfig::new_with_scenario(0, WS_TOKEN.to_owned(), yaml_dir, Some("000-base.yaml".to_owned()));
+    let manager = Manager::new();
+    let mut hast = Hast::new(cfg, manager.subscribe());
+    let stats = hast.stats();
+    hast.start().unwrap();
+    let port = hast.local_addr().unwrap().port();
+
+    let api = WsApi::new_unsecure(WS_HOST, port, WS_TOKEN, manager.subscribe()).await.unwrap();
+    let events = api.subscribe_event(Some(EventType::StateChanged)).await.unwrap();
+
+    // The studio gets estimated empty at 23:35:01, but is occupied again
+    // within the debounce period: no calls are expected for that.
+    let expected = [
+        ("light", "turn_on", json!({"entity_id": "light.studio"})),
+        ("light", "turn_on", json!({"entity_id": "light.disbrigo"})),
+        ("light", "turn_off", json!({"entity_id": "light.studio"})),
+    ];
+
+    // The scenario is played at once, leaving the last transitions to the
+    // periodic decay and flush, due after the last event
+    let mut presence = PresenceEngine::new(config.home().into_graph(), config.presence_timeout());
+    let clock = EventClock::new(Utc::now());
+    let (changes_tx, changes) = mpsc::channel(16);
+    let calls = tokio::select! {
+        _ = actions::run(&api, &config, &clock, changes) => panic!("changes closed unexpectedly"),
+        _ = presence.run(&clock, events.into_inner(), changes_tx) => panic!("events closed unexpectedly"),
+        calls = tokio::time::timeout(Duration::from_secs(10), service_calls(&stats, expected.len())) => {
+            calls.expect("service calls not received in time")
+        },
+    };
+    drop(hast);
+    manager.shutdown().await;
+
+    assert_eq!(calls.len(), expected.len(), "{:?}", calls);
+    for (call, expected) in calls.iter().zip(expected) {
+        assert_eq!((call.0.as_str(), call.1.as_str(), &call.2), (expected.0, expected.1, &expected.2));
+    }
+}
+
+/// Waits for hast to receive at least `count` service calls, returning them.
+async fn service_calls(stats: &HastStats, count: usize) -> Vec<(String, String, serde_json::Value)> {
+    loop {
+        let calls: Vec<_> = stats.received().into_iter()
+            .filter_map(|msg| match msg {
+                WsMessage::CallService { domain, service, target, .. } => Some((domain, service, target.unwrap())),
+                _ => None,
+            })
+            .collect();
+        if calls.len() >= count {
+            return calls;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
@@ -0,0 +1,38 @@
+use chrono::{DateTime, Utc};
+use hass::hast::scenario;
+use hass::pirengine::home::Presence;
+use hass::serde_json;
+use piresence::config::Config;
+use piresence::expected_timeline;
+
+/// Areas watched by some of the motion sensors found in `000-base.yaml`
+const CONFIG: &str = r#"{
+    "presence_timeout_ms": 5000,
+    "areas": {
+        "studio": {"sensors": ["binary_sensor.studio_motion_motion"]},
+        "disbrigo": {"sensors": ["binary_sensor.disbrigo_motion_motion"]}
+    }
+}"#;
+
+fn at(time: &str) -> DateTime<Utc> {
+    time.parse().unwrap()
+}
+
+#[test]
+fn base_scenario_timeline() {
+    let config: Config = serde_json::from_str(CONFIG).unwrap();
+    let path = format!("{}/../hass/tests/resources/000-base.yaml", env!("CARGO_MANIFEST_DIR"));
+    let scenario: Vec<_> = scenario::read_file(path).unwrap()
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    // The studio is vacated at 23:35:00 but its motion is back before the
+    // timeout, and camera events belong to no configured area
+    assert_eq!(expected_timeline(&scenario, &config), vec![
+        (at("2022-05-10T23:34:50.163029Z"), "studio".to_owned(), Presence::AtLeast(1)),
+        (at("2022-05-10T23:34:53.615363Z"), "disbrigo".to_owned(), Presence::AtLeast(1)),
+        (at("2022-05-10T23:35:13.228993Z"), "disbrigo".to_owned(), Presence::NoOne),
+        (at("2022-05-10T23:35:16.252910Z"), "studio".to_owned(), Presence::NoOne),
+    ]);
+}